@@ -0,0 +1,463 @@
+use axum::{
+    extract::{ConnectInfo, Extension, Query, Request, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+use socketioxide::SocketIo;
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+use crate::auth::{is_admin, ADMIN_ROOM, ADMIN_TOKEN_HEADER};
+use crate::e2e;
+use crate::export::escape_html;
+use crate::links::LinkQuery;
+use crate::lockout::Lockout;
+use crate::roles::Role;
+use crate::scheduler::now_millis;
+use crate::state::{AppState, SharedState};
+use crate::utils::constant_time_eq;
+
+pub const ACCESS_COOKIE: &str = "zher_access";
+
+// Routes reachable without room access, used to enter the code
+const PUBLIC_PATHS: [&str; 3] = ["/api/access", "/api/tls", "/api/tls/ca"];
+
+const DOWNLOAD_PATH: &str = "/api/download/";
+
+pub fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+pub fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key == name {
+            urlencoding::decode(value).ok().map(|v| v.into_owned())
+        } else {
+            None
+        }
+    })
+}
+
+pub fn access_cookie(token: &str) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax",
+        ACCESS_COOKIE, token
+    )
+}
+
+pub fn code_matches(state: &AppState, code: &str) -> bool {
+    code_role(state, code).is_some()
+}
+
+/// The role a code lets its holder in as: the room code makes members and
+/// the guest code guests. The guest code only works alongside a room code.
+pub fn code_role(state: &AppState, code: &str) -> Option<Role> {
+    let room_code = state.room_code.as_deref()?;
+    let matches = |expected: &str| constant_time_eq(code.as_bytes(), expected.as_bytes());
+    if matches(room_code) {
+        Some(Role::Member)
+    } else if state.config.guest_code.as_deref().is_some_and(matches) {
+        Some(Role::Guest)
+    } else {
+        None
+    }
+}
+
+/// Role of a device entering the room: the role of the code it presents or
+/// holds a cookie for, or `default_role` while the room code is off.
+pub fn entry_role(state: &AppState, headers: &HeaderMap, presented: Option<&str>) -> Role {
+    if !state.room_code_enabled || state.room_code.is_none() {
+        return state.config.default_role;
+    }
+    presented
+        .and_then(|code| code_role(state, code))
+        .or_else(|| {
+            cookie_value(headers, ACCESS_COOKIE)
+                .and_then(|token| state.access_tokens.get(token))
+                .and_then(|code| code_role(state, code))
+        })
+        .unwrap_or(Role::Member)
+}
+
+pub enum CodeAttempt {
+    Accepted,
+    Rejected(Vec<Lockout>),
+    // Milliseconds until the client may try again
+    LockedOut(u64),
+}
+
+/// Checks a room code presented by `ip`. Wrong codes count towards the
+/// per-IP and global lockouts, and locked out clients aren't checked at all.
+pub fn try_room_code(state: &mut AppState, ip: Option<IpAddr>, code: &str) -> CodeAttempt {
+    let now = now_millis();
+    if let Some(wait) = state.code_attempts.locked_for(ip, now) {
+        return CodeAttempt::LockedOut(wait);
+    }
+    if code_matches(state, code) {
+        state.code_attempts.record_success(ip);
+        return CodeAttempt::Accepted;
+    }
+
+    let lockouts = state.code_attempts.record_failure(ip, now);
+    // Attempts during a lockout aren't logged, they would only flood the log
+    state
+        .audit
+        .record("room_code_failed", ip, None, serde_json::json!({}));
+    for lockout in &lockouts {
+        warn!(
+            "Room code locked for {} after {} failed attempts",
+            lockout.ip.as_deref().unwrap_or("all clients"),
+            lockout.failures
+        );
+        state.audit.record(
+            "room_code_lockout",
+            ip,
+            None,
+            serde_json::to_value(lockout).unwrap_or_default(),
+        );
+    }
+    CodeAttempt::Rejected(lockouts)
+}
+
+pub fn notify_lockouts(io: &SocketIo, attempt: &CodeAttempt) {
+    if let CodeAttempt::Rejected(lockouts) = attempt {
+        for lockout in lockouts {
+            let _ = io.to(ADMIN_ROOM).emit("lockout", lockout);
+        }
+    }
+}
+
+pub fn attempt_error(attempt: &CodeAttempt) -> Option<String> {
+    match attempt {
+        CodeAttempt::Accepted => None,
+        CodeAttempt::Rejected(_) => Some("房间码错误".to_string()),
+        CodeAttempt::LockedOut(wait) => {
+            Some(format!("尝试次数过多，请 {} 秒后再试", wait.div_ceil(1000)))
+        }
+    }
+}
+
+/// Response for a request without room access. `attempt` is None when no
+/// code was presented at all.
+pub fn denied_response(html: bool, attempt: Option<&CodeAttempt>) -> Response {
+    let error = attempt.and_then(attempt_error);
+    let status = match attempt {
+        Some(CodeAttempt::LockedOut(_)) => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::UNAUTHORIZED,
+    };
+
+    let mut response = if html {
+        (status, Html(code_entry_page(error.as_deref()))).into_response()
+    } else {
+        let body = error.unwrap_or_else(|| "Room code required".to_string());
+        (status, body).into_response()
+    };
+    if let Some(CodeAttempt::LockedOut(wait)) = attempt {
+        if let Ok(value) = wait.div_ceil(1000).to_string().parse() {
+            response.headers_mut().insert(header::RETRY_AFTER, value);
+        }
+    }
+    response
+}
+
+/// Whether a request may enter the room without presenting the code: the
+/// room code is off, the client is an admin, or it holds an access cookie
+/// issued for the current code.
+pub fn has_room_access(state: &AppState, headers: &HeaderMap, addr: Option<&SocketAddr>) -> bool {
+    if !state.room_code_enabled {
+        return true;
+    }
+    if state.room_code.is_none() {
+        return true;
+    }
+
+    let admin_token = headers
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok());
+    if is_admin(&state.admin_token, addr, admin_token) {
+        return true;
+    }
+
+    cookie_value(headers, ACCESS_COOKIE)
+        .and_then(|token| state.access_tokens.get(token))
+        .is_some_and(|code| code_matches(state, code))
+}
+
+/// Whether `uri` is a download with a valid signed link, which is meant to
+/// work outside the room. Download counts are checked by the handler.
+pub fn has_download_link(state: &SharedState, uri: &Uri) -> bool {
+    let Some(file_id) = uri.path().strip_prefix(DOWNLOAD_PATH) else {
+        return false;
+    };
+    let Ok(file_id) = urlencoding::decode(file_id) else {
+        return false;
+    };
+    let Ok(Query(query)) = Query::<LinkQuery>::try_from_uri(uri) else {
+        return false;
+    };
+    let state_read = state.read().unwrap();
+    state_read
+        .links
+        .check(&state_read.signer, &file_id, &query, now_millis())
+        .is_some()
+}
+
+/// Room code check in front of every route, including the Socket.IO
+/// handshake. A correct `?code=` (or admin token) is exchanged for an
+/// HttpOnly cookie so the code doesn't have to stay in the URL.
+pub async fn room_code_guard(
+    State(state): State<SharedState>,
+    Extension(io): Extension<SocketIo>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if PUBLIC_PATHS.contains(&req.uri().path()) || has_download_link(&state, req.uri()) {
+        return next.run(req).await;
+    }
+
+    let addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0);
+    let code = query_param(req.uri().query(), "code");
+    let admin_param = query_param(req.uri().query(), "admin");
+
+    // None when the request already has access
+    let attempt = {
+        let mut state_write = state.write().unwrap();
+        if has_room_access(&state_write, req.headers(), addr.as_ref()) {
+            None
+        } else if admin_param.is_some_and(|t| is_admin(&state_write.admin_token, None, Some(&t))) {
+            Some(Some(CodeAttempt::Accepted))
+        } else {
+            let ip = addr.map(|a| a.ip().to_canonical());
+            Some(
+                code.as_deref()
+                    .map(|c| try_room_code(&mut state_write, ip, c)),
+            )
+        }
+    };
+
+    let issued = match attempt {
+        None => None,
+        Some(Some(CodeAttempt::Accepted)) => {
+            let mut state_write = state.write().unwrap();
+            // None when it was the admin token that got the request in
+            let accepted = code.as_deref().filter(|c| code_matches(&state_write, c));
+            Some(state_write.issue_access_token(accepted))
+        }
+        Some(attempt) => {
+            if let Some(ref attempt) = attempt {
+                notify_lockouts(&io, attempt);
+            }
+            return denied_response(wants_html(&req), attempt.as_ref());
+        }
+    };
+
+    let Some(token) = issued else {
+        return next.run(req).await;
+    };
+
+    // Page loads drop the code from the URL once the cookie is set. In E2E
+    // mode the page still needs it for the key, so it moves to the fragment,
+    // which browsers never send.
+    if wants_html(&req) && req.uri().query().is_some_and(|q| q.contains("code=")) {
+        let mut location = strip_query_param(req.uri(), "code");
+        if let Some(code) = code.filter(|_| e2e::info(&state.read().unwrap()).is_some()) {
+            location = format!("{}#code={}", location, urlencoding::encode(&code));
+        }
+        return (
+            StatusCode::SEE_OTHER,
+            [
+                (header::LOCATION, location),
+                (header::SET_COOKIE, access_cookie(&token)),
+            ],
+        )
+            .into_response();
+    }
+
+    let mut response = next.run(req).await;
+    if let Ok(cookie) = access_cookie(&token).parse() {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    response
+}
+
+pub fn wants_html(req: &Request) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/html"))
+}
+
+fn strip_query_param(uri: &Uri, name: &str) -> String {
+    let rest: Vec<&str> = uri
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|p| !p.is_empty() && p.split('=').next() != Some(name))
+        .collect();
+    if rest.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), rest.join("&"))
+    }
+}
+
+pub fn code_entry_page(error: Option<&str>) -> String {
+    let error = error
+        .map(|e| format!("<p class=\"error\">{}</p>", escape_html(e)))
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>zhe'r</title>
+<style>
+body {{ font-family: sans-serif; display: flex; min-height: 100vh; margin: 0; align-items: center; justify-content: center; background: #f3f4f6; }}
+form {{ background: #fff; padding: 2rem; border-radius: 0.75rem; box-shadow: 0 1px 3px rgba(0,0,0,.1); display: flex; flex-direction: column; gap: 0.75rem; }}
+input, button {{ font-size: 1rem; padding: 0.5rem 0.75rem; border-radius: 0.5rem; border: 1px solid #d1d5db; }}
+button {{ background: #2563eb; color: #fff; border: none; }}
+.error {{ color: #dc2626; margin: 0; }}
+</style>
+</head>
+<body>
+<form method="post" action="/api/access" onsubmit="try {{ localStorage.setItem('zher_room_code', this.code.value.trim()) }} catch (e) {{}}">
+<label for="code">请输入房间码</label>
+<input id="code" name="code" autocomplete="off" autofocus required>
+{}
+<button type="submit">进入</button>
+</form>
+</body>
+</html>
+"#,
+        error
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{read_page, AuditLog};
+
+    #[test]
+    fn test_cookie_value() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "a=1; zher_access=tok; b=2".parse().unwrap());
+        assert_eq!(cookie_value(&headers, ACCESS_COOKIE), Some("tok"));
+        assert_eq!(cookie_value(&headers, "b"), Some("2"));
+        assert_eq!(cookie_value(&headers, "missing"), None);
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(
+            query_param(Some("a=1&code=123456"), "code").as_deref(),
+            Some("123456")
+        );
+        assert_eq!(
+            query_param(Some("code=a%20b"), "code").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(query_param(Some("codex=1"), "code"), None);
+        assert_eq!(query_param(None, "code"), None);
+    }
+
+    #[test]
+    fn test_strip_query_param() {
+        let uri: Uri = "/?code=123456&lang=en".parse().unwrap();
+        assert_eq!(strip_query_param(&uri, "code"), "/?lang=en");
+        let uri: Uri = "/index.html?code=123456".parse().unwrap();
+        assert_eq!(strip_query_param(&uri, "code"), "/index.html");
+    }
+
+    #[test]
+    fn test_has_room_access() {
+        let lan: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+        let mut state = AppState::default();
+        let headers = HeaderMap::new();
+        assert!(has_room_access(&state, &headers, Some(&lan)));
+
+        state.room_code_enabled = true;
+        state.room_code = Some("123456".to_string());
+        assert!(!has_room_access(&state, &headers, Some(&lan)));
+
+        let token = state.issue_access_token(None);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, access_cookie(&token).parse().unwrap());
+        assert!(has_room_access(&state, &headers, Some(&lan)));
+
+        // Rotating the code invalidates cookies issued for the old one
+        state.room_code = Some("654321".to_string());
+        assert!(!has_room_access(&state, &headers, Some(&lan)));
+    }
+
+    #[test]
+    fn test_entry_role() {
+        let mut state = AppState::default();
+        state.config.default_role = Role::Guest;
+        state.config.guest_code = Some("guest-code".to_string());
+        let headers = HeaderMap::new();
+        assert_eq!(entry_role(&state, &headers, None), Role::Guest);
+
+        state.room_code_enabled = true;
+        state.room_code = Some("123456".to_string());
+        assert_eq!(entry_role(&state, &headers, Some("123456")), Role::Member);
+        assert_eq!(
+            entry_role(&state, &headers, Some("guest-code")),
+            Role::Guest
+        );
+
+        // A cookie issued for the guest code keeps its holder a guest
+        let token = state.issue_access_token(Some("guest-code"));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, access_cookie(&token).parse().unwrap());
+        assert!(has_room_access(&state, &headers, None));
+        assert_eq!(entry_role(&state, &headers, None), Role::Guest);
+    }
+
+    #[test]
+    fn test_failed_codes_are_audited() {
+        let dir = std::env::temp_dir().join(format!("zher-access-{}", uuid::Uuid::new_v4()));
+        let mut state = AppState {
+            audit: AuditLog::new(Some(dir.join("audit.log")), 1 << 20, 1),
+            room_code_enabled: true,
+            room_code: Some("123456".to_string()),
+            ..Default::default()
+        };
+        let ip: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+
+        // The fifth wrong code starts a lockout, later ones aren't checked
+        for _ in 0..7 {
+            try_room_code(&mut state, ip, "000000");
+        }
+        assert!(matches!(
+            try_room_code(&mut state, ip, "123456"),
+            CodeAttempt::LockedOut(_)
+        ));
+        state.audit.flush();
+
+        let paths = state.audit.paths();
+        let (failed, _) = read_page(&paths, 0, 100, Some("room_code_failed"));
+        assert_eq!(failed.len(), 5);
+        assert_eq!(failed[0].ip.as_deref(), Some("10.0.0.1"));
+        let (lockouts, _) = read_page(&paths, 0, 100, Some("room_code_lockout"));
+        assert_eq!(lockouts.len(), 1);
+        assert_eq!(lockouts[0].details["ip"], "10.0.0.1");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
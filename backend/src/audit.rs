@@ -0,0 +1,322 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+};
+use tracing::warn;
+
+use crate::scheduler::now_millis;
+use crate::state::User;
+
+// Largest page the audit endpoint returns
+pub const MAX_PAGE: usize = 500;
+// Entries kept without a data directory, oldest dropped first
+const MEMORY_ENTRIES: usize = 10_000;
+
+/// One line of the audit log.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AuditEntry {
+    // Unix time in milliseconds
+    pub time: u64,
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(rename = "userId", default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(rename = "userName", default, skip_serializing_if = "Option::is_none")]
+    pub user_name: Option<String>,
+    #[serde(default)]
+    pub details: Value,
+}
+
+enum Command {
+    Append(String),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Append-only JSON-lines log of security-relevant events. Once the file
+/// reaches `max_bytes` it is renamed to `audit.log.1`, older files move up
+/// by one and the oldest beyond `files` is dropped. Entries are written by a
+/// background thread, so recording one never waits on the disk while the
+/// state lock is held. Without a path the latest entries are kept in memory.
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    files: usize,
+    writer: Option<mpsc::Sender<Command>>,
+    memory: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new(None, 0, 0)
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: Option<PathBuf>, max_bytes: u64, files: usize) -> Self {
+        let writer = path.clone().and_then(|path| {
+            let (tx, rx) = mpsc::channel();
+            let mut file = LogFile::new(path, max_bytes, files);
+            let spawned = thread::Builder::new()
+                .name("audit-log".to_string())
+                .spawn(move || {
+                    for command in rx {
+                        match command {
+                            Command::Append(line) => file.append(&line),
+                            Command::Flush(done) => {
+                                let _ = done.send(());
+                            }
+                        }
+                    }
+                });
+            match spawned {
+                Ok(_) => Some(tx),
+                Err(e) => {
+                    warn!("Failed to start the audit log writer: {}", e);
+                    None
+                }
+            }
+        });
+        Self {
+            path,
+            files,
+            writer,
+            memory: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, event: &str, ip: Option<IpAddr>, user: Option<&User>, details: Value) {
+        let entry = AuditEntry {
+            time: now_millis(),
+            event: event.to_string(),
+            ip: ip.map(|ip| ip.to_canonical().to_string()),
+            user_id: user.map(|u| u.id.clone()),
+            user_name: user.map(|u| u.name.clone()),
+            details,
+        };
+        let Some(writer) = &self.writer else {
+            let mut memory = self.memory.lock().unwrap();
+            if memory.len() >= MEMORY_ENTRIES {
+                memory.pop_front();
+            }
+            memory.push_back(entry);
+            return;
+        };
+        match serde_json::to_string(&entry) {
+            Ok(mut line) => {
+                line.push('\n');
+                let _ = writer.send(Command::Append(line));
+            }
+            Err(e) => warn!("Failed to serialize audit entry: {}", e),
+        }
+    }
+
+    /// Waits until everything recorded so far has been written.
+    pub fn flush(&self) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let (done, written) = mpsc::channel();
+        if writer.send(Command::Flush(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+
+    /// A page of the entries kept in memory, like `read_page`. None when the
+    /// log is kept in files.
+    pub fn memory_page(
+        &self,
+        offset: usize,
+        limit: usize,
+        event: Option<&str>,
+    ) -> Option<(Vec<AuditEntry>, bool)> {
+        if self.writer.is_some() {
+            return None;
+        }
+        let memory = self.memory.lock().unwrap();
+        Some(page(memory.iter().rev().cloned(), offset, limit, event))
+    }
+
+    /// The log files, newest first, for `read_page`.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+        std::iter::once(path.clone())
+            .chain((1..=self.files).map(|n| rotated(path, n)))
+            .collect()
+    }
+}
+
+// The current log file, owned by the writer thread
+struct LogFile {
+    path: PathBuf,
+    max_bytes: u64,
+    files: usize,
+    // Size of the current file, to know when to rotate
+    size: u64,
+}
+
+impl LogFile {
+    fn new(path: PathBuf, max_bytes: u64, files: usize) -> Self {
+        let size = fs::metadata(&path).map_or(0, |m| m.len());
+        Self {
+            path,
+            max_bytes,
+            files,
+            size,
+        }
+    }
+
+    fn append(&mut self, line: &str) {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate();
+        }
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+            })
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        match result {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => warn!("Failed to write audit log {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn rotate(&mut self) {
+        let path = &self.path;
+        for n in (1..self.files).rev() {
+            let _ = fs::rename(rotated(path, n), rotated(path, n + 1));
+        }
+        let result = if self.files == 0 {
+            fs::remove_file(path)
+        } else {
+            fs::rename(path, rotated(path, 1))
+        };
+        if let Err(e) = result {
+            warn!("Failed to rotate audit log {}: {}", path.display(), e);
+        }
+        self.size = 0;
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Entries newest first, skipping `offset` and keeping those of `event` if
+/// given. Returns the page and whether older entries follow. Older files are
+/// only read when the page reaches into them.
+pub fn read_page(
+    paths: &[PathBuf],
+    offset: usize,
+    limit: usize,
+    event: Option<&str>,
+) -> (Vec<AuditEntry>, bool) {
+    let entries = paths.iter().flat_map(|path| {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        content
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .collect::<Vec<_>>()
+    });
+    page(entries, offset, limit, event)
+}
+
+fn page(
+    entries: impl Iterator<Item = AuditEntry>,
+    offset: usize,
+    limit: usize,
+    event: Option<&str>,
+) -> (Vec<AuditEntry>, bool) {
+    let mut entries: Vec<AuditEntry> = entries
+        .filter(|entry| event.is_none_or(|event| entry.event == event))
+        .skip(offset)
+        .take(limit + 1)
+        .collect();
+    let more = entries.len() > limit;
+    entries.truncate(limit);
+    (entries, more)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_rotation_and_paging() {
+        let dir = std::env::temp_dir().join(format!("zher-audit-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("audit.log");
+        let log = AuditLog::new(Some(path.clone()), 400, 2);
+        for n in 0..20 {
+            let event = if n % 2 == 0 { "download" } else { "connect" };
+            log.record(event, None, None, json!({ "n": n }));
+        }
+        log.flush();
+        assert!(rotated(&path, 1).exists());
+        assert!(!rotated(&path, 3).exists());
+        assert!(fs::metadata(&path).unwrap().len() <= 400);
+
+        let (page, more) = read_page(&log.paths(), 0, 3, None);
+        let numbers: Vec<u64> = page
+            .iter()
+            .map(|e| e.details["n"].as_u64().unwrap())
+            .collect();
+        assert_eq!(numbers, vec![19, 18, 17]);
+        assert!(more);
+
+        let (page, _) = read_page(&log.paths(), 1, 2, Some("download"));
+        let numbers: Vec<u64> = page
+            .iter()
+            .map(|e| e.details["n"].as_u64().unwrap())
+            .collect();
+        assert_eq!(numbers, vec![16, 14]);
+
+        // The oldest entries were dropped with the oldest file
+        let (page, more) = read_page(&log.paths(), 0, 100, None);
+        assert!(page.len() < 20);
+        assert!(!more);
+        assert!(log.memory_page(0, 100, None).is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_memory_log() {
+        let log = AuditLog::default();
+        for n in 0..MEMORY_ENTRIES + 5 {
+            let event = if n % 2 == 0 { "download" } else { "connect" };
+            log.record(event, None, None, json!({ "n": n }));
+        }
+        assert!(log.paths().is_empty());
+
+        let (page, more) = log.memory_page(1, 2, Some("download")).unwrap();
+        let numbers: Vec<u64> = page
+            .iter()
+            .map(|e| e.details["n"].as_u64().unwrap())
+            .collect();
+        assert_eq!(numbers, vec![10_002, 10_000]);
+        assert!(more);
+
+        // Only the latest entries are kept
+        let (page, _) = log.memory_page(MEMORY_ENTRIES - 1, 10, None).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].details["n"], 5);
+    }
+}
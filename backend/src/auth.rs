@@ -0,0 +1,93 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::{convert::Infallible, net::SocketAddr};
+
+use crate::state::SharedState;
+use crate::utils::constant_time_eq;
+
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+// Socket.IO room that admin sockets join, for admin-only events
+pub const ADMIN_ROOM: &str = "admins";
+
+/// Loopback clients are always admins; everyone else needs the admin token.
+/// Behind a reverse proxy every client looks like loopback unless the proxy
+/// is listed in `trusted_proxies`, which makes `addr` the real client.
+pub fn is_admin(admin_token: &str, addr: Option<&SocketAddr>, provided: Option<&str>) -> bool {
+    addr.is_some_and(|a| a.ip().to_canonical().is_loopback())
+        || provided.is_some_and(|t| constant_time_eq(t.as_bytes(), admin_token.as_bytes()))
+}
+
+// Whether the request comes from an admin, for handlers that only vary output
+pub struct IsAdmin(pub bool);
+
+#[async_trait]
+impl FromRequestParts<SharedState> for IsAdmin {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &SharedState,
+    ) -> Result<Self, Self::Rejection> {
+        let addr = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|c| &c.0);
+        let token = parts
+            .headers
+            .get(ADMIN_TOKEN_HEADER)
+            .and_then(|v| v.to_str().ok());
+        let admin_token = state.read().unwrap().admin_token.clone();
+
+        Ok(IsAdmin(is_admin(&admin_token, addr, token)))
+    }
+}
+
+// Rejects non-admin requests with 403
+pub struct AdminOnly;
+
+#[async_trait]
+impl FromRequestParts<SharedState> for AdminOnly {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &SharedState,
+    ) -> Result<Self, Self::Rejection> {
+        let Ok(IsAdmin(admin)) = IsAdmin::from_request_parts(parts, state).await;
+        if admin {
+            Ok(AdminOnly)
+        } else {
+            Err((
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({
+                    "error": "Admin access required"
+                })),
+            )
+                .into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_admin() {
+        let loopback: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:5000".parse().unwrap();
+        let lan: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+
+        assert!(is_admin("secret", Some(&loopback), None));
+        assert!(is_admin("secret", Some(&mapped), None));
+        assert!(is_admin("secret", Some(&lan), Some("secret")));
+        assert!(!is_admin("secret", Some(&lan), Some("wrong")));
+        assert!(!is_admin("secret", Some(&lan), None));
+        assert!(!is_admin("secret", None, None));
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+use crate::login::Account;
+use crate::ratelimit::RateLimitConfig;
+use crate::roles::{PermissionMatrix, Role};
+use crate::scan::ScannerConfig;
+
+const CONFIG_FILE: &str = "zher.json";
+
+/// Server settings read from `zher.json` in the working directory, or from
+/// the file named by `ZHER_CONFIG`. Every field is optional.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Directory for persistent data; None keeps everything in memory
+    pub data_dir: Option<PathBuf>,
+    // Fixed admin token; a random one is generated at startup otherwise
+    pub admin_token: Option<String>,
+    // Shape of room codes: 6 digits, longer alphanumeric codes or passphrases
+    pub room_code_format: RoomCodeFormat,
+    // New devices wait for an admin to approve them
+    pub pairing: bool,
+    // What happens to connected sockets when the room code changes
+    pub room_code_change: RoomCodeChangePolicy,
+    // Seconds a re-challenged socket has to enter the new code
+    pub reauth_timeout: u64,
+    // Serve HTTPS with a self-signed certificate; needs the `tls` feature
+    pub tls: bool,
+    // Plain HTTP port that redirects to HTTPS when `tls` is on
+    pub http_redirect_port: Option<u16>,
+    // Clients encrypt messages and files with a key from the room code
+    pub e2e: bool,
+    // Origins allowed besides the server's own addresses, e.g. a proxy URL
+    pub allowed_origins: Vec<String>,
+    // CIDRs that may connect; empty allows every address
+    pub allow_ips: Vec<String>,
+    // CIDRs that are always refused
+    pub deny_ips: Vec<String>,
+    // Reverse proxies whose X-Forwarded-For header is believed
+    pub trusted_proxies: Vec<String>,
+    // Role of new devices while the room code is off
+    pub default_role: Role,
+    // Second room code that lets devices in as guests
+    pub guest_code: Option<String>,
+    // What guests and members may do
+    pub permissions: PermissionMatrix,
+    // Size in bytes at which the audit log is rotated
+    pub audit_max_bytes: u64,
+    // Rotated audit logs kept besides the current one
+    pub audit_files: usize,
+    // Per-session and per-IP limits on events, messages, downloads and API calls
+    pub rate_limits: RateLimitConfig,
+    // Largest Socket.IO packet in bytes; files go over HTTP, not the socket
+    pub max_payload: u64,
+    // Largest text message in bytes, 0 for no limit
+    pub max_message_bytes: u64,
+    // Largest file that may be announced, 0 for no limit
+    pub max_file_size: u64,
+    // Bytes of pinned files the server keeps per sender, 0 for no limit
+    pub max_stored_bytes_per_user: u64,
+    // Malware scanner for files kept on the server
+    pub scanner: Option<ScannerConfig>,
+    // Logins with argon2 password hashes; empty leaves the instance open
+    pub accounts: Vec<Account>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: None,
+            admin_token: None,
+            room_code_format: RoomCodeFormat::default(),
+            pairing: false,
+            room_code_change: RoomCodeChangePolicy::default(),
+            reauth_timeout: 60,
+            tls: false,
+            http_redirect_port: None,
+            e2e: false,
+            allowed_origins: Vec::new(),
+            allow_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            trusted_proxies: Vec::new(),
+            default_role: Role::Member,
+            guest_code: None,
+            permissions: PermissionMatrix::default(),
+            audit_max_bytes: 10 * 1024 * 1024,
+            audit_files: 5,
+            rate_limits: RateLimitConfig::default(),
+            max_payload: 1024 * 1024,
+            max_message_bytes: 64 * 1024,
+            max_file_size: 0,
+            max_stored_bytes_per_user: 1024 * 1024 * 1024,
+            scanner: None,
+            accounts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomCodeFormat {
+    #[default]
+    Digits,
+    Alphanumeric,
+    Passphrase,
+}
+
+impl RoomCodeFormat {
+    pub fn requirement(&self) -> &'static str {
+        match self {
+            Self::Digits => "Room code must be exactly 6 digits",
+            Self::Alphanumeric => "Room code must be 8 to 32 letters or digits",
+            Self::Passphrase => "Passphrase must have at least 3 words and 12 characters",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomCodeChangePolicy {
+    // Ask for the new code, disconnect after `reauth_timeout`
+    #[default]
+    Reauth,
+    // Disconnect right away; the session resumes once the code is entered
+    Disconnect,
+    // Leave existing connections alone
+    Keep,
+}
+
+impl Config {
+    fn path() -> PathBuf {
+        env::var("ZHER_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(CONFIG_FILE))
+    }
+
+    /// Reads the config file, or the defaults if there is none. A file that
+    /// can't be read or parsed is an error, so a typo doesn't quietly turn
+    /// off the settings it was meant to enforce.
+    pub fn load() -> Result<Self, String> {
+        Self::load_from(&Self::path())
+    }
+
+    fn load_from(path: &Path) -> Result<Self, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("Failed to read config {}: {}", path.display(), e)),
+        };
+        let mut config = serde_json::from_str::<Self>(&content)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        info!("Loaded config from {}", path.display());
+        if config.default_role == Role::Admin {
+            warn!("default_role cannot be admin, using member");
+            config.default_role = Role::Member;
+        }
+        Ok(config)
+    }
+
+    /// Writes `accounts` back to the config file, leaving the other settings
+    /// as they are. Creates the file if there is none.
+    pub fn save_accounts(accounts: &[Account]) -> io::Result<()> {
+        let path = Self::path();
+        let mut config = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str::<Value>(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Value::Object(Default::default()),
+            Err(e) => return Err(e),
+        };
+        let Some(obj) = config.as_object_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "config is not a JSON object",
+            ));
+        };
+        obj.insert("accounts".to_string(), serde_json::to_value(accounts)?);
+        fs::write(&path, serde_json::to_string_pretty(&config)? + "\n")
+    }
+
+    pub fn data_path(&self, name: &str) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|dir| dir.join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_from() {
+        let dir = env::temp_dir().join(format!("zher-config-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("zher.json");
+        // A missing file gives the defaults
+        assert!(!Config::load_from(&path).unwrap().tls);
+
+        fs::write(&path, r#"{ "default_role": "admin", "tls": true }"#).unwrap();
+        let config = Config::load_from(&path).unwrap();
+        assert!(config.tls);
+        assert_eq!(config.default_role, Role::Member);
+
+        fs::write(&path, r#"{ "tls": "yes" }"#).unwrap();
+        assert!(Config::load_from(&path).is_err());
+        fs::write(&path, "{ \"tls\": true,").unwrap();
+        assert!(Config::load_from(&path).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
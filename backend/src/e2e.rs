@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socketioxide::SocketIo;
+
+use crate::state::{AppState, SharedState};
+use crate::ws::{TextMessageOptions, MEMBERS_ROOM};
+
+// End-to-end mode: clients derive an AES-256-GCM key from the room code with
+// PBKDF2-SHA256 over the salt below and encrypt everything themselves. The
+// server only checks the framing and relays ciphertext.
+//
+// Files are sealed in chunks of `chunkSize` plaintext bytes, each followed
+// by its tag, with the chunk index in the IV. Any ciphertext byte range
+// therefore maps to whole chunks the sender can reproduce, and Range
+// requests work on the encrypted bytes unchanged. The additional data holds
+// the file id, the index and a last-chunk flag, so clients notice a
+// reordered or truncated download.
+
+pub const KDF_ITERATIONS: u32 = 600_000;
+// Authentication tag appended to every sealed chunk
+pub const TAG_SIZE: u64 = 16;
+const MIN_CHUNK_SIZE: u64 = 16 * 1024;
+const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+// Base64 of the 12 byte AES-GCM IV
+const IV_LENGTH: usize = 16;
+
+/// Base64 AES-GCM ciphertext and the IV it was sealed with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub iv: String,
+    pub data: String,
+}
+
+impl Envelope {
+    fn is_valid(&self) -> bool {
+        let base64 = |s: &str| {
+            s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+        };
+        self.iv.len() == IV_LENGTH
+            && base64(&self.iv)
+            && !self.data.is_empty()
+            && base64(&self.data)
+    }
+}
+
+/// What clients need to derive the key, sent in `welcome` and `e2e-changed`.
+#[derive(Clone, Debug, Serialize)]
+pub struct E2eInfo {
+    pub salt: String,
+    pub iterations: u32,
+}
+
+/// None unless E2E mode is configured and there is a room code to key from.
+pub fn info(state: &AppState) -> Option<E2eInfo> {
+    (state.config.e2e && state.room_code_enabled && state.room_code.is_some()).then(|| E2eInfo {
+        // Stable across restarts, so stored ciphertext stays readable
+        salt: state.signer.derive("e2e-salt")[..32].to_string(),
+        iterations: KDF_ITERATIONS,
+    })
+}
+
+/// Tells clients to switch keys after the room code was toggled or changed.
+pub fn announce(state: &SharedState, io: &SocketIo) {
+    let info = info(&state.read().unwrap());
+    let _ = io.to(MEMBERS_ROOM).emit("e2e-changed", &info);
+}
+
+/// Rejects plaintext messages while E2E mode is on.
+pub fn check_text(state: &AppState, options: &TextMessageOptions) -> Result<(), &'static str> {
+    if info(state).is_none() {
+        return Ok(());
+    }
+    match options.encrypted {
+        Some(ref envelope) if envelope.is_valid() && options.text.is_empty() => Ok(()),
+        _ => Err("端到端加密已开启，消息必须加密"),
+    }
+}
+
+/// Rejects plaintext file announcements while E2E mode is on, and drops any
+/// clear name or type a client sent along with the encrypted ones.
+pub fn check_file_meta(state: &AppState, meta: &mut Value) -> Result<(), &'static str> {
+    if info(state).is_none() {
+        return Ok(());
+    }
+    let Some(obj) = meta.as_object_mut() else {
+        return Err("文件信息无效");
+    };
+
+    let sealed = obj
+        .get("encrypted")
+        .and_then(|v| serde_json::from_value::<Envelope>(v.clone()).ok())
+        .is_some_and(|e| e.is_valid());
+    if !sealed {
+        return Err("端到端加密已开启，文件必须加密");
+    }
+
+    let chunk_size = obj.get("chunkSize").and_then(|v| v.as_u64()).unwrap_or(0);
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+        return Err("加密分块大小无效");
+    }
+    // Even an empty file is one sealed chunk
+    let file_size = obj.get("fileSize").and_then(|v| v.as_u64()).unwrap_or(0);
+    if file_size < TAG_SIZE {
+        return Err("加密文件大小无效");
+    }
+
+    obj.remove("fileName");
+    obj.remove("fileType");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn e2e_state() -> AppState {
+        let mut state = AppState::default();
+        state.config.e2e = true;
+        state.room_code_enabled = true;
+        state.room_code = Some("123456".to_string());
+        state
+    }
+
+    fn envelope() -> Envelope {
+        Envelope {
+            iv: "AAECAwQFBgcICQoL".to_string(),
+            data: "c2VjcmV0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_info_needs_room_code() {
+        let mut state = e2e_state();
+        let salt = info(&state).unwrap().salt;
+        assert_eq!(salt.len(), 32);
+        assert_eq!(info(&state).unwrap().salt, salt);
+
+        state.room_code_enabled = false;
+        assert!(info(&state).is_none());
+    }
+
+    #[test]
+    fn test_check_text() {
+        let state = e2e_state();
+        let plain = TextMessageOptions {
+            text: "hi".to_string(),
+            ..Default::default()
+        };
+        assert!(check_text(&state, &plain).is_err());
+        assert!(check_text(&AppState::default(), &plain).is_ok());
+
+        let sealed = TextMessageOptions {
+            encrypted: Some(envelope()),
+            ..Default::default()
+        };
+        assert!(check_text(&state, &sealed).is_ok());
+
+        let bad_iv = TextMessageOptions {
+            encrypted: Some(Envelope {
+                iv: "short".to_string(),
+                ..envelope()
+            }),
+            ..Default::default()
+        };
+        assert!(check_text(&state, &bad_iv).is_err());
+    }
+
+    #[test]
+    fn test_check_file_meta() {
+        let state = e2e_state();
+
+        let mut plain = json!({ "fileId": "f1", "fileName": "a.txt", "fileSize": 10 });
+        assert!(check_file_meta(&state, &mut plain).is_err());
+
+        let mut sealed = json!({
+            "fileId": "f1",
+            "fileName": "a.txt",
+            "fileSize": 26,
+            "chunkSize": 65536,
+            "encrypted": envelope()
+        });
+        assert!(check_file_meta(&state, &mut sealed).is_ok());
+        assert!(sealed.get("fileName").is_none());
+
+        let mut tiny_chunks = json!({
+            "fileId": "f1",
+            "fileSize": 26,
+            "chunkSize": 16,
+            "encrypted": envelope()
+        });
+        assert!(check_file_meta(&state, &mut tiny_chunks).is_err());
+    }
+}
@@ -0,0 +1,501 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+};
+use tracing::warn;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::filename;
+use crate::state::User;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "json" => Some(Self::Json),
+            "md" | "markdown" => Some(Self::Markdown),
+            "html" => Some(Self::Html),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json; charset=utf-8",
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Markdown => "md",
+            Self::Html => "html",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ExportEntry {
+    pub id: u64,
+    pub timestamp: String,
+    #[serde(rename = "senderId")]
+    pub sender_id: String,
+    #[serde(rename = "senderName")]
+    pub sender_name: String,
+    #[serde(rename = "senderColor")]
+    pub sender_color: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(rename = "fileId", skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    #[serde(rename = "fileName", skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(rename = "fileSize", skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Turns recorded messages into export entries. Sender name and color come
+/// from the current `User` when the sender still has a session, so renames
+/// show up in the transcript.
+pub fn collect_entries<'a>(
+    messages: impl IntoIterator<Item = &'a Value>,
+    users: &HashMap<String, User>,
+    server_url: &str,
+    since: Option<u64>,
+) -> Vec<ExportEntry> {
+    messages
+        .into_iter()
+        .filter_map(|msg| {
+            let id = msg.get("id").and_then(|v| v.as_u64())?;
+            if since.is_some_and(|since| id < since) {
+                return None;
+            }
+            // Self-destructing messages must not outlive their timer in an export
+            let view_once = msg.get("viewOnce").and_then(|v| v.as_bool()) == Some(true);
+            if view_once || msg.get("expiresAt").is_some() {
+                return None;
+            }
+
+            let field = |key: &str| {
+                msg.get(key)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let sender_id = field("senderId");
+            let (sender_name, sender_color) = match users.get(&sender_id) {
+                Some(user) => (user.name.clone(), user.color.clone()),
+                None => (field("senderName"), field("senderColor")),
+            };
+
+            let mut entry = ExportEntry {
+                id,
+                timestamp: format_timestamp(id),
+                sender_id,
+                sender_name,
+                sender_color,
+                msg_type: "text".to_string(),
+                text: None,
+                file_id: None,
+                file_name: None,
+                file_size: None,
+                url: None,
+            };
+
+            if field("type") == "file-meta" {
+                let file_id = field("fileId");
+                entry.msg_type = "file".to_string();
+                entry.file_name = Some(field("fileName"));
+                entry.file_size = msg.get("fileSize").and_then(|v| v.as_u64());
+                entry.url = Some(format!(
+                    "{}/api/download/{}",
+                    server_url,
+                    urlencoding::encode(&file_id)
+                ));
+                entry.file_id = Some(file_id);
+            } else {
+                entry.text = Some(field("text"));
+            }
+
+            Some(entry)
+        })
+        .collect()
+}
+
+pub fn render(format: ExportFormat, entries: &[ExportEntry], exported_at: u64) -> String {
+    match format {
+        ExportFormat::Json => render_json(entries, exported_at),
+        ExportFormat::Markdown => render_markdown(entries, exported_at),
+        ExportFormat::Html => render_html(entries, exported_at),
+    }
+}
+
+fn render_json(entries: &[ExportEntry], exported_at: u64) -> String {
+    serde_json::to_string_pretty(&serde_json::json!({
+        "exportedAt": format_timestamp(exported_at),
+        "messages": entries,
+    }))
+    .unwrap_or_default()
+}
+
+fn render_markdown(entries: &[ExportEntry], exported_at: u64) -> String {
+    let mut out = format!(
+        "# zher chat export\n\nExported at {}\n",
+        format_timestamp(exported_at)
+    );
+
+    for entry in entries {
+        out.push_str(&format!(
+            "\n**{}** · {}\n\n",
+            escape_markdown(&entry.sender_name),
+            entry.timestamp
+        ));
+        match (&entry.text, &entry.url) {
+            (_, Some(url)) => {
+                let name = entry.file_name.as_deref().unwrap_or_default();
+                let size = entry.file_size.map(format_size).unwrap_or_default();
+                out.push_str(&format!(
+                    "📎 [{}](<{}>) ({})\n",
+                    escape_markdown(name),
+                    url.replace(['<', '>'], ""),
+                    size
+                ));
+            }
+            (Some(text), None) => {
+                for line in text.lines() {
+                    out.push_str(&format!("> {}\n", escape_markdown(line)));
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    out
+}
+
+fn render_html(entries: &[ExportEntry], exported_at: u64) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>zher chat export</title>\n<style>\nbody { font-family: sans-serif; max-width: 48rem; margin: 2rem auto; color: #1f2937; }\n.msg { margin: 1rem 0; }\n.meta { font-size: 0.8rem; color: #6b7280; }\n.sender { font-weight: 600; }\n.text { white-space: pre-wrap; margin-top: 0.25rem; }\n</style>\n</head>\n<body>\n",
+    );
+    out.push_str(&format!(
+        "<h1>zher chat export</h1>\n<p class=\"meta\">Exported at {}</p>\n",
+        format_timestamp(exported_at)
+    ));
+
+    for entry in entries {
+        out.push_str("<div class=\"msg\">\n");
+        out.push_str(&format!(
+            "<div class=\"meta\"><span class=\"sender\" style=\"color: {}\">{}</span> · {}</div>\n",
+            escape_html(&entry.sender_color),
+            escape_html(&entry.sender_name),
+            entry.timestamp
+        ));
+        match (&entry.text, &entry.url) {
+            (_, Some(url)) => {
+                let name = entry.file_name.as_deref().unwrap_or_default();
+                let size = entry.file_size.map(format_size).unwrap_or_default();
+                out.push_str(&format!(
+                    "<div class=\"text\">📎 <a href=\"{}\">{}</a> ({})</div>\n",
+                    escape_html(url),
+                    escape_html(name),
+                    size
+                ));
+            }
+            (Some(text), None) => {
+                out.push_str(&format!(
+                    "<div class=\"text\">{}</div>\n",
+                    escape_html(text)
+                ));
+            }
+            (None, None) => {}
+        }
+        out.push_str("</div>\n");
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Backslash-escapes every character Markdown could read as formatting, a
+/// link or inline HTML, so messages render as the text that was sent.
+pub fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Points file entries that have a stored copy at that copy inside the
+/// export archive. Returns the archive name and path of every copy; other
+/// files keep their download link.
+pub fn archive_files(
+    entries: &mut [ExportEntry],
+    stored: &HashMap<String, PathBuf>,
+) -> Vec<(String, PathBuf)> {
+    let mut files = Vec::new();
+    for entry in entries.iter_mut() {
+        let Some(path) = entry.file_id.as_ref().and_then(|id| stored.get(id)) else {
+            continue;
+        };
+        // Numbered, as several messages may share a file name
+        let name = format!(
+            "{}-{}",
+            files.len() + 1,
+            filename::sanitize(entry.file_name.as_deref().unwrap_or_default())
+        );
+        entry.url = Some(format!("files/{}", urlencoding::encode(&name)));
+        files.push((format!("files/{}", name), path.clone()));
+    }
+    files
+}
+
+/// Writes a zip with the transcript and the stored files next to it. Files
+/// are stored uncompressed; most shared files are compressed already.
+pub fn write_archive(
+    out: File,
+    transcript_name: &str,
+    transcript: &str,
+    files: &[(String, PathBuf)],
+) -> zip::result::ZipResult<()> {
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    let mut zip = ZipWriter::new(out);
+    zip.start_file(transcript_name, options)?;
+    zip.write_all(transcript.as_bytes())?;
+    for (name, path) in files {
+        // The file may have been removed since the export started
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Leaving {} out of the export: {}", name, e);
+                continue;
+            }
+        };
+        zip.start_file(name.as_str(), options)?;
+        io::copy(&mut file, &mut zip)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Formats a millisecond Unix timestamp as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_messages() -> Vec<Value> {
+        vec![
+            serde_json::json!({
+                "id": 1700000000000u64,
+                "senderId": "u1",
+                "senderName": "old-name",
+                "senderColor": "#FF6B6B",
+                "type": "text",
+                "text": "<b>hi</b>",
+            }),
+            serde_json::json!({
+                "id": 1700000060000u64,
+                "senderId": "u2",
+                "senderName": "bob",
+                "senderColor": "#4ECDC4",
+                "type": "file-meta",
+                "fileId": "abc",
+                "fileName": "notes.txt",
+                "fileSize": 2048,
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1700000000000), "2023-11-14 22:13:20 UTC");
+    }
+
+    #[test]
+    fn test_collect_entries_uses_current_user_and_since() {
+        let mut users = HashMap::new();
+        users.insert(
+            "u1".to_string(),
+            User {
+                id: "u1".to_string(),
+                name: "alice".to_string(),
+                color: "#FF6B6B".to_string(),
+                device: "desktop".to_string(),
+                role: Default::default(),
+            },
+        );
+
+        let entries = collect_entries(&sample_messages(), &users, "http://host:4836", None);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].sender_name, "alice");
+        assert_eq!(entries[1].sender_name, "bob");
+        assert_eq!(
+            entries[1].url.as_deref(),
+            Some("http://host:4836/api/download/abc")
+        );
+
+        let entries = collect_entries(
+            &sample_messages(),
+            &users,
+            "http://host:4836",
+            Some(1700000000001),
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].msg_type, "file");
+    }
+
+    #[test]
+    fn test_collect_entries_skips_ephemeral() {
+        let mut messages = sample_messages();
+        messages.push(serde_json::json!({
+            "id": 1700000120000u64,
+            "senderId": "u1",
+            "type": "text",
+            "text": "gone soon",
+            "ttl": 60,
+            "expiresAt": 1700000180000u64,
+        }));
+        messages.push(serde_json::json!({
+            "id": 1700000130000u64,
+            "senderId": "u2",
+            "type": "file-meta",
+            "fileId": "once",
+            "fileName": "secret.txt",
+            "viewOnce": true,
+        }));
+        let entries = collect_entries(&messages, &HashMap::new(), "http://h", None);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.id < 1700000120000));
+    }
+
+    #[test]
+    fn test_render_html_escapes_text() {
+        let entries = collect_entries(&sample_messages(), &HashMap::new(), "", None);
+        let html = render(ExportFormat::Html, &entries, 0);
+        assert!(html.contains("&lt;b&gt;hi&lt;/b&gt;"));
+        assert!(!html.contains("<b>hi</b>"));
+    }
+
+    #[test]
+    fn test_render_markdown_links_files() {
+        let entries = collect_entries(&sample_messages(), &HashMap::new(), "http://h", None);
+        let md = render(ExportFormat::Markdown, &entries, 0);
+        assert!(md.contains("[notes\\.txt](<http://h/api/download/abc>) (2.0 KB)"));
+        assert!(md.contains("> \\<b\\>hi\\<\\/b\\>"));
+    }
+
+    #[test]
+    fn test_escape_markdown() {
+        assert_eq!(
+            escape_markdown("**bold** [x](y)"),
+            "\\*\\*bold\\*\\* \\[x\\]\\(y\\)"
+        );
+        assert_eq!(escape_markdown("# 标题"), "\\# 标题");
+    }
+
+    #[test]
+    fn test_archive_files() {
+        let mut entries = collect_entries(&sample_messages(), &HashMap::new(), "http://h", None);
+        let mut stored = HashMap::new();
+        stored.insert("abc".to_string(), PathBuf::from("/store/1"));
+        let files = archive_files(&mut entries, &stored);
+        assert_eq!(
+            files,
+            vec![("files/1-notes.txt".to_string(), PathBuf::from("/store/1"))]
+        );
+        assert_eq!(entries[1].url.as_deref(), Some("files/1-notes.txt"));
+
+        let path = std::env::temp_dir().join(format!("zher-export-{}.zip", uuid::Uuid::new_v4()));
+        let store = std::env::temp_dir().join(format!("zher-export-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&store, b"notes").unwrap();
+        let files = vec![("files/1-notes.txt".to_string(), store.clone())];
+        write_archive(File::create(&path).unwrap(), "chat.md", "# chat", &files).unwrap();
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut notes = String::new();
+        io::Read::read_to_string(&mut zip.by_name("files/1-notes.txt").unwrap(), &mut notes)
+            .unwrap();
+        assert_eq!(notes, "notes");
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(store);
+    }
+}
@@ -0,0 +1,133 @@
+// Longest name most filesystems accept, in bytes
+const MAX_NAME_BYTES: usize = 255;
+// Longer "extensions" are cut like the rest of the name
+const MAX_EXTENSION_BYTES: usize = 16;
+
+const FALLBACK_NAME: &str = "unknown_file";
+
+// Device names Windows won't open as files, whatever the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Characters that reorder how the rest of the name is displayed
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// A client-supplied file name made safe for headers and for disk: control
+/// characters dropped, path separators and characters Windows forbids
+/// replaced, no leading dots, no reserved device names and at most 255
+/// bytes with the extension kept. The original stays in the message for
+/// display.
+pub fn sanitize(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .filter(|&c| !c.is_control() && !is_bidi_control(c))
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = cleaned
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '.')
+        .trim_end_matches(|c: char| c.is_whitespace() || c == '.');
+
+    let stem = trimmed.split('.').next().unwrap_or_default().trim_end();
+    let name = if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        format!("_{}", trimmed)
+    } else {
+        trimmed.to_string()
+    };
+
+    let name = truncate(&name);
+    let name = name.trim_end_matches(|c: char| c.is_whitespace() || c == '.');
+    if name.is_empty() {
+        FALLBACK_NAME.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// Cuts the stem so the name fits, on a character boundary
+fn truncate(name: &str) -> String {
+    if name.len() <= MAX_NAME_BYTES {
+        return name.to_string();
+    }
+    let extension = name
+        .rfind('.')
+        .map(|i| &name[i..])
+        .filter(|ext| ext.len() <= MAX_EXTENSION_BYTES)
+        .unwrap_or_default();
+    let mut end = MAX_NAME_BYTES - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+/// The name with everything outside printable ASCII replaced, for the
+/// quoted `filename=` that older clients read.
+pub fn ascii_fallback(name: &str) -> String {
+    sanitize(name)
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '%' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// `Content-Disposition` for a download of `name`, with an ASCII `filename`
+/// and the full name as UTF-8 in `filename*` (RFC 6266).
+pub fn content_disposition(name: &str) -> String {
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii_fallback(name),
+        urlencoding::encode(&sanitize(name))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("report.pdf"), "report.pdf");
+        assert_eq!(sanitize("报告 v2.pdf"), "报告 v2.pdf");
+        assert_eq!(sanitize("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(sanitize("a\\b:c*?.txt"), "a_b_c__.txt");
+        assert_eq!(sanitize("evil\u{202e}fdp.exe"), "evilfdp.exe");
+        assert_eq!(sanitize("line\r\nbreak\0.txt"), "linebreak.txt");
+        assert_eq!(sanitize(".hidden"), "hidden");
+        assert_eq!(sanitize("trailing. . "), "trailing");
+        assert_eq!(sanitize("con"), "_con");
+        assert_eq!(sanitize("LPT1.tar.gz"), "_LPT1.tar.gz");
+        assert_eq!(sanitize("console.log"), "console.log");
+        assert_eq!(sanitize(""), "unknown_file");
+        assert_eq!(sanitize(" ... "), "unknown_file");
+
+        let long = format!("{}.txt", "文".repeat(200));
+        let cut = sanitize(&long);
+        assert!(cut.len() <= 255);
+        assert!(cut.ends_with("文.txt"));
+
+        for name in ["../../etc/passwd", "con", &long, "a\u{7f}b. "] {
+            assert_eq!(sanitize(&sanitize(name)), sanitize(name));
+        }
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("报告 100%.pdf"),
+            "attachment; filename=\"__ 100_.pdf\"; filename*=UTF-8''%E6%8A%A5%E5%91%8A%20100%25.pdf"
+        );
+        assert_eq!(
+            content_disposition("a\"b\r\nContent-Type: x"),
+            "attachment; filename=\"a_bContent-Type_ x\"; filename*=UTF-8''a_bContent-Type_%20x"
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use axum::{
     body::Body,
-    extract::{Path, State},
+    extract::{ConnectInfo, Form, Path, Query, State},
     http::{header, HeaderMap, StatusCode, Uri},
     response::{IntoResponse, Response},
 };
@@ -9,11 +9,44 @@ use futures::StreamExt;
 use rust_embed::RustEmbed;
 use serde::Serialize;
 use socketioxide::SocketIo;
-use tokio::sync::mpsc;
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::ReaderStream;
 use tracing::warn;
 
-use crate::state::SharedState;
+use crate::access::{
+    access_cookie, cookie_value, denied_response, notify_lockouts, try_room_code, CodeAttempt,
+};
+use crate::audit::{read_page, MAX_PAGE};
+use crate::auth::{AdminOnly, IsAdmin};
+use crate::config::Config;
+use crate::e2e;
+use crate::export::{archive_files, collect_entries, render, write_archive, ExportFormat};
+use crate::filename::content_disposition;
+use crate::integrity::{repr_digest, Integrity, IntegrityReport, StreamHasher};
+use crate::links::LinkQuery;
+use crate::login::{
+    account_hash, account_names, check_credentials, hash_password, login_cookie, login_denied,
+    logout_cookie, validate_account, Account, LOGIN_COOKIE,
+};
+use crate::ratelimit::{request_keys, too_many_requests, DownloadSlot};
+use crate::roles::{cookie_session, Permission, RequestRole, Role, DENIED};
+use crate::scan::Verdict;
+use crate::scheduler::now_millis;
+use crate::state::{SharedState, Transfer, User};
+use crate::store::StoredFile;
+use crate::utils::{generate_room_code, validate_room_code};
+use crate::ws::{challenge_stale_sockets, MEMBERS_ROOM};
 
 #[derive(RustEmbed)]
 #[folder = "$CARGO_MANIFEST_DIR/../frontend/dist"]
@@ -23,22 +56,69 @@ struct Assets;
 pub async fn upload_file(
     Path(transfer_id): Path<String>,
     State(state): State<SharedState>,
+    axum::Extension(io): axum::Extension<SocketIo>,
     body: Body,
 ) -> impl IntoResponse {
-    let tx = {
+    let transfer = {
         let mut state_write = state.write().unwrap();
         state_write.transfers.remove(&transfer_id)
     };
 
-    if let Some(tx) = tx {
+    if let Some(transfer) = transfer {
+        let tx = transfer.tx;
+        let mut hasher = transfer.hasher;
+        let mut report = None;
+        let mut sent = 0u64;
         let mut stream = body.into_data_stream();
         while let Some(chunk) = stream.next().await {
             match chunk {
                 Ok(bytes) => {
+                    let len = bytes.len() as u64;
+                    // The sender may not send more than the announced size
+                    if sent + len > transfer.length {
+                        warn!(
+                            "Upload {} for file {} is larger than {} bytes",
+                            transfer_id, transfer.file_id, transfer.length
+                        );
+                        let _ = tx
+                            .send(Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                "upload larger than announced",
+                            )))
+                            .await;
+                        return StatusCode::PAYLOAD_TOO_LARGE;
+                    }
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&bytes);
+                    }
+                    // The last chunk is held back until the hash is checked,
+                    // so the receiver never gets a whole corrupted file
+                    if sent + len == transfer.length {
+                        if let Some(hasher) = hasher.take() {
+                            let (status, expected, actual) = hasher.finish();
+                            report = Some(IntegrityReport {
+                                file_id: transfer.file_id.clone(),
+                                transfer_id: transfer_id.clone(),
+                                status,
+                                expected,
+                                actual,
+                            });
+                            if status == Integrity::Mismatch {
+                                let _ = tx
+                                    .send(Err(std::io::Error::new(
+                                        std::io::ErrorKind::InvalidData,
+                                        "SHA-256 mismatch",
+                                    )))
+                                    .await;
+                                break;
+                            }
+                        }
+                    }
                     if tx.send(Ok(bytes)).await.is_err() {
                         // Receiver dropped
                         break;
                     }
+                    sent += len;
                 }
                 Err(e) => {
                     let _ = tx
@@ -51,6 +131,60 @@ pub async fn upload_file(
                 }
             }
         }
+
+        // A stream that ends short never reaches the check above
+        if report.is_none() && sent < transfer.length {
+            if let Some(hasher) = hasher.take() {
+                let (_, expected, actual) = hasher.finish();
+                report = Some(IntegrityReport {
+                    file_id: transfer.file_id.clone(),
+                    transfer_id: transfer_id.clone(),
+                    status: Integrity::Incomplete,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        if let Some(report) = &report {
+            let sender = {
+                let state_read = state.read().unwrap();
+                state_read
+                    .file_owners
+                    .get(&transfer.file_id)
+                    .map(|(socket_id, _, _)| socket_id.clone())
+            };
+            match report.status {
+                Integrity::Mismatch => warn!(
+                    "File {} failed its integrity check in transfer {}: expected {}, got {}",
+                    report.file_id, transfer_id, report.expected, report.actual
+                ),
+                Integrity::Incomplete => warn!(
+                    "Transfer {} of file {} ended after {} of {} bytes",
+                    transfer_id, report.file_id, sent, transfer.length
+                ),
+                Integrity::Match => {}
+            }
+            let sockets: Vec<String> = sender.into_iter().chain(transfer.receivers).collect();
+            let _ = io.to(sockets).emit("transfer-integrity", report);
+        }
+        if report.is_some_and(|r| r.status == Integrity::Mismatch) {
+            return StatusCode::UNPROCESSABLE_ENTITY;
+        }
+
+        // A view-once file is gone after its first complete download
+        if transfer.whole_file && sent == transfer.length {
+            let expired = {
+                let mut state_write = state.write().unwrap();
+                state_write
+                    .view_once_message_for_file(&transfer.file_id)
+                    .filter(|id| state_write.expire_message(*id).is_some())
+            };
+            if let Some(message_id) = expired {
+                let _ = io.to(MEMBERS_ROOM).emit("message-deleted", message_id);
+            }
+        }
+
         StatusCode::OK
     } else {
         StatusCode::NOT_FOUND
@@ -59,11 +193,148 @@ pub async fn upload_file(
 
 // GET /download/:file_id
 pub async fn download_file(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path(file_id): Path<String>,
+    Query(link): Query<LinkQuery>,
     headers: HeaderMap,
+    RequestRole(role): RequestRole,
     State(state): State<SharedState>,
     axum::Extension(io): axum::Extension<SocketIo>,
 ) -> Response {
+    let keys = request_keys(&state.read().unwrap(), Some(addr.ip()), &headers);
+    let response = match DownloadSlot::acquire(&state, keys) {
+        Some(slot) => {
+            let response =
+                serve_download(file_id.clone(), &link, &headers, role, &state, &io).await;
+            // The slot is held until the body has been sent or dropped
+            let (parts, body) = response.into_parts();
+            let body = body.into_data_stream().map(move |chunk| {
+                let _ = &slot;
+                chunk
+            });
+            Response::from_parts(parts, Body::from_stream(body))
+        }
+        None => too_many_requests(1000),
+    };
+
+    // Who pulled which file, refusals included
+    let status = response.status();
+    let range = response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .or_else(|| headers.get(header::RANGE))
+        .and_then(|v| v.to_str().ok());
+    let state_read = state.read().unwrap();
+    let user = cookie_session(&state_read, &headers).map(|s| s.user.clone());
+    let file_name = state_read
+        .file_owners
+        .get(&file_id)
+        .map(|(_, name, _)| name.clone())
+        .or_else(|| state_read.file_store.get(&file_id).map(|f| f.name.clone()));
+    state_read.audit.record(
+        "download",
+        Some(addr.ip()),
+        user.as_ref(),
+        serde_json::json!({
+            "fileId": file_id,
+            "fileName": file_name,
+            "link": link.link,
+            "range": range,
+            "status": status.as_u16(),
+            "outcome": download_outcome(status)
+        }),
+    );
+    drop(state_read);
+    response
+}
+
+fn download_outcome(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::OK => "served",
+        StatusCode::PARTIAL_CONTENT => "partial",
+        StatusCode::FORBIDDEN => "denied",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::GONE => "expired",
+        StatusCode::RANGE_NOT_SATISFIABLE => "bad_range",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+        _ => "failed",
+    }
+}
+
+pub async fn serve_download(
+    file_id: String,
+    link: &LinkQuery,
+    headers: &HeaderMap,
+    role: Role,
+    state: &SharedState,
+    io: &SocketIo,
+) -> Response {
+    if link.link.is_none() {
+        return serve_file(file_id, false, headers, role, state, io).await;
+    }
+
+    let redeemed = {
+        let mut guard = state.write().unwrap();
+        let state_write = &mut *guard;
+        state_write
+            .links
+            .check(&state_write.signer, &file_id, link, now_millis())
+            .map(|l| l.id.clone())
+            .filter(|id| state_write.links.redeem(id))
+    };
+    let Some(link_id) = redeemed else {
+        let body = Json(serde_json::json!({ "error": "Download link is invalid or used up" }));
+        return (StatusCode::FORBIDDEN, body).into_response();
+    };
+    let response = serve_file(file_id, true, headers, role, state, io).await;
+    // Only responses that serve the file use up a download
+    if !response.status().is_success() {
+        state.write().unwrap().links.refund(&link_id);
+    }
+    response
+}
+
+async fn serve_file(
+    file_id: String,
+    by_link: bool,
+    headers: &HeaderMap,
+    role: Role,
+    state: &SharedState,
+    io: &SocketIo,
+) -> Response {
+    let (stored, scanning) = {
+        let state_read = state.read().unwrap();
+        // A signed link stands in for the permission
+        if !by_link
+            && !state_read
+                .config
+                .permissions
+                .allows(role, Permission::Download)
+        {
+            let body = Json(serde_json::json!({ "error": DENIED }));
+            return (StatusCode::FORBIDDEN, body).into_response();
+        }
+        if state_read.expired_files.contains(&file_id) {
+            return StatusCode::GONE.into_response();
+        }
+        let verdict = state_read.scans.get(&file_id);
+        if let Some(Verdict::Infected { threat }) = verdict {
+            let body = Json(serde_json::json!({
+                "error": "File was flagged by the malware scanner",
+                "threat": threat
+            }));
+            return (StatusCode::FORBIDDEN, body).into_response();
+        }
+        // With a scanner, stored copies are only served once they have a verdict
+        let stored = state_read.scanned_copy(&file_id).cloned();
+        let scanning = stored.is_none() && state_read.file_store.get(&file_id).is_some();
+        (stored, scanning)
+    };
+
+    if let Some(stored) = stored {
+        return serve_stored_file(stored, headers).await;
+    }
+
     let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(2);
     let transfer_id = uuid::Uuid::new_v4().to_string();
 
@@ -73,55 +344,34 @@ pub async fn download_file(
     };
 
     if let Some((sender_id, filename, filesize)) = file_info {
-        {
-            let mut state_write = state.write().unwrap();
-            state_write.transfers.insert(transfer_id.clone(), tx);
-        }
-
-        // Parse Range Header
-        let mut start_byte = 0;
-        let mut end_byte = filesize - 1;
-        let mut is_partial = false;
-
-        if let Some(range_header) = headers.get(header::RANGE) {
-            if let Ok(range_str) = range_header.to_str() {
-                if range_str.starts_with("bytes=") {
-                    let ranges: Vec<&str> =
-                        range_str.trim_start_matches("bytes=").split('-').collect();
-                    if ranges.len() >= 1 {
-                        if let Ok(s) = ranges[0].parse::<u64>() {
-                            start_byte = s;
-                            is_partial = true;
-                        }
-                    }
-                    if ranges.len() >= 2 && !ranges[1].is_empty() {
-                        if let Ok(e) = ranges[1].parse::<u64>() {
-                            end_byte = e;
-                            is_partial = true;
-                        }
-                    }
-                }
-            }
-        }
-
-        if start_byte > end_byte || start_byte >= filesize {
+        let Some((start_byte, end_byte, is_partial)) = parse_range(headers, filesize) else {
             return (StatusCode::RANGE_NOT_SATISFIABLE, "Invalid Range").into_response();
-        }
-        if end_byte >= filesize {
-            end_byte = filesize - 1;
-        }
-
-        let content_length = end_byte - start_byte + 1;
+        };
 
-        #[derive(Serialize)]
-        struct StartUploadData {
-            #[serde(rename = "fileId")]
-            file_id: String,
-            #[serde(rename = "transferId")]
-            transfer_id: String,
-            offset: u64,
-            end: u64,
-        }
+        let whole_file = start_byte == 0 && end_byte == filesize - 1;
+        let sha256 = {
+            let mut state_write = state.write().unwrap();
+            // Only a whole file can be checked against the announced hash
+            let sha256 = state_write
+                .file_sha256(&file_id)
+                .filter(|_| whole_file)
+                .map(str::to_string);
+            let receivers = cookie_session(&state_write, headers)
+                .map(|s| s.active_sockets.iter().cloned().collect())
+                .unwrap_or_default();
+            state_write.transfers.insert(
+                transfer_id.clone(),
+                Transfer {
+                    file_id: file_id.clone(),
+                    tx,
+                    length: end_byte - start_byte + 1,
+                    whole_file,
+                    hasher: sha256.clone().map(StreamHasher::new),
+                    receivers,
+                },
+            );
+            sha256
+        };
 
         if let Err(e) = io.to(sender_id).emit(
             "start-upload",
@@ -137,76 +387,146 @@ pub async fn download_file(
         }
 
         let stream = ReceiverStream::new(rx);
-        let encoded_filename = urlencoding::encode(&filename);
-        let content_disposition = format!("attachment; filename*=UTF-8''{}", encoded_filename);
-
-        let status = if is_partial {
-            StatusCode::PARTIAL_CONTENT
-        } else {
-            StatusCode::OK
-        };
-        let content_range = format!("bytes {}-{}/{}", start_byte, end_byte, filesize);
-
-        let mut response = Body::from_stream(stream).into_response();
-        *response.status_mut() = status;
-
-        let headers = response.headers_mut();
-        headers.insert(
-            header::CONTENT_TYPE,
-            "application/octet-stream".parse().unwrap(),
-        );
-        headers.insert(
-            header::CONTENT_DISPOSITION,
-            content_disposition.parse().unwrap(),
-        );
-        headers.insert(
-            header::CONTENT_LENGTH,
-            content_length.to_string().parse().unwrap(),
-        );
-        headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
-
-        if is_partial {
-            headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
-        }
-
-        response
+        attachment_response(
+            Body::from_stream(stream),
+            &filename,
+            start_byte,
+            end_byte,
+            filesize,
+            is_partial,
+            sha256.as_deref(),
+        )
+    } else if scanning {
+        StatusCode::SERVICE_UNAVAILABLE.into_response()
     } else {
         StatusCode::NOT_FOUND.into_response()
     }
 }
 
-pub async fn static_handler(uri: Uri, State(state): State<SharedState>) -> impl IntoResponse {
-    let path = uri.path().trim_start_matches('/');
-    let query = uri.query().unwrap_or("");
+#[derive(Serialize)]
+pub struct StartUploadData {
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+    #[serde(rename = "transferId")]
+    pub transfer_id: String,
+    pub offset: u64,
+    pub end: u64,
+}
 
-    // Check if room code is required for accessing the main page
-    if path.is_empty() || path == "index.html" {
-        let state_read = state.read().unwrap();
-        if state_read.room_code_enabled {
-            if let Some(ref expected_code) = state_read.room_code {
-                // Parse query string to get room code
-                let code_param = query.split('&').find_map(|param| {
-                    let parts: Vec<&str> = param.split('=').collect();
-                    if parts.len() == 2 && parts[0] == "code" {
-                        Some(parts[1].to_string())
-                    } else {
-                        None
-                    }
-                });
+// Parse Range Header, returns (start, end, is_partial) or None if unsatisfiable
+fn parse_range(headers: &HeaderMap, filesize: u64) -> Option<(u64, u64, bool)> {
+    let mut start_byte = 0;
+    let mut end_byte = filesize.saturating_sub(1);
+    let mut is_partial = false;
 
-                match code_param {
-                    Some(provided_code) if &provided_code == expected_code => {
-                        // Valid room code, allow access
-                    }
-                    _ => {
-                        // Invalid or missing room code - return 404
-                        return StatusCode::NOT_FOUND.into_response();
+    if let Some(range_header) = headers.get(header::RANGE) {
+        if let Ok(range_str) = range_header.to_str() {
+            if range_str.starts_with("bytes=") {
+                let ranges: Vec<&str> = range_str.trim_start_matches("bytes=").split('-').collect();
+                if let Some(Ok(s)) = ranges.first().map(|r| r.parse::<u64>()) {
+                    start_byte = s;
+                    is_partial = true;
+                }
+                if ranges.len() >= 2 && !ranges[1].is_empty() {
+                    if let Ok(e) = ranges[1].parse::<u64>() {
+                        end_byte = e;
+                        is_partial = true;
                     }
                 }
             }
         }
     }
 
+    if start_byte > end_byte || start_byte >= filesize {
+        return None;
+    }
+    if end_byte >= filesize {
+        end_byte = filesize - 1;
+    }
+
+    Some((start_byte, end_byte, is_partial))
+}
+
+fn attachment_response(
+    body: Body,
+    filename: &str,
+    start_byte: u64,
+    end_byte: u64,
+    filesize: u64,
+    is_partial: bool,
+    sha256: Option<&str>,
+) -> Response {
+    let content_length = end_byte - start_byte + 1;
+    let content_disposition = content_disposition(filename);
+
+    let status = if is_partial {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    let content_range = format!("bytes {}-{}/{}", start_byte, end_byte, filesize);
+
+    let mut response = body.into_response();
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        "application/octet-stream".parse().unwrap(),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        content_disposition.parse().unwrap(),
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        content_length.to_string().parse().unwrap(),
+    );
+    headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+
+    if is_partial {
+        headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+    }
+    // The digest covers the whole file, so only full downloads carry it
+    if let Some(value) = sha256.filter(|_| !is_partial).and_then(repr_digest) {
+        headers.insert("repr-digest", value.parse().unwrap());
+    }
+
+    response
+}
+
+async fn serve_stored_file(stored: StoredFile, headers: &HeaderMap) -> Response {
+    let Some((start_byte, end_byte, is_partial)) = parse_range(headers, stored.size) else {
+        return (StatusCode::RANGE_NOT_SATISFIABLE, "Invalid Range").into_response();
+    };
+
+    let mut file = match tokio::fs::File::open(&stored.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open stored file {:?}: {}", stored.path, e);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    if let Err(e) = file.seek(SeekFrom::Start(start_byte)).await {
+        warn!("Failed to seek stored file {:?}: {}", stored.path, e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let stream = ReaderStream::new(file.take(end_byte - start_byte + 1));
+    attachment_response(
+        Body::from_stream(stream),
+        &stored.name,
+        start_byte,
+        end_byte,
+        stored.size,
+        is_partial,
+        stored.sha256.as_deref(),
+    )
+}
+
+// Room code access is checked by access::room_code_guard before this runs
+pub async fn static_handler(uri: Uri) -> impl IntoResponse {
+    let path = uri.path().trim_start_matches('/');
     let path = if path.is_empty() { "index.html" } else { path };
 
     match Assets::get(path) {
@@ -235,12 +555,23 @@ pub struct DiscoveryToggle {
 }
 
 pub async fn toggle_discovery(
+    _admin: AdminOnly,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<SharedState>,
     Json(payload): Json<DiscoveryToggle>,
 ) -> impl IntoResponse {
     let state_read = state.read().unwrap();
-    let discovery = state_read.discovery.lock().unwrap();
-    discovery.set_enabled(payload.enabled);
+    state_read
+        .discovery
+        .lock()
+        .unwrap()
+        .set_enabled(payload.enabled);
+    state_read.audit.record(
+        "discovery_toggled",
+        Some(addr.ip()),
+        None,
+        serde_json::json!({ "enabled": payload.enabled }),
+    );
 
     (
         StatusCode::OK,
@@ -251,14 +582,32 @@ pub async fn toggle_discovery(
 }
 
 // GET /api/roomcode
-pub async fn get_roomcode(State(state): State<SharedState>) -> impl IntoResponse {
+pub async fn get_roomcode(
+    IsAdmin(admin): IsAdmin,
+    State(state): State<SharedState>,
+) -> impl IntoResponse {
     let state_read = state.read().unwrap();
 
+    // Only admins may read the codes back or see lockouts
+    let (code, guest_code, lockouts) = if admin {
+        (
+            state_read.room_code.clone(),
+            state_read.config.guest_code.clone(),
+            state_read.code_attempts.active_lockouts(now_millis()),
+        )
+    } else {
+        (None, None, Vec::new())
+    };
+
     (
         StatusCode::OK,
         Json(serde_json::json!({
             "enabled": state_read.room_code_enabled,
-            "code": state_read.room_code
+            "code": code,
+            "guestCode": guest_code,
+            "format": state_read.config.room_code_format,
+            "lockouts": lockouts,
+            "admin": admin
         })),
     )
 }
@@ -270,14 +619,31 @@ pub struct RoomCodeToggle {
 }
 
 pub async fn toggle_roomcode(
+    _admin: AdminOnly,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     axum::Extension(io): axum::Extension<SocketIo>,
     State(state): State<SharedState>,
     Json(payload): Json<RoomCodeToggle>,
 ) -> impl IntoResponse {
-    {
+    let code = {
         let mut state_write = state.write().unwrap();
         state_write.room_code_enabled = payload.enabled;
-    }
+        let generated = payload.enabled && state_write.room_code.is_none();
+        if generated {
+            let format = state_write.config.room_code_format;
+            state_write.room_code = Some(generate_room_code(format));
+        }
+        // The code itself never goes into the log
+        state_write.audit.record(
+            "room_code_toggled",
+            Some(addr.ip()),
+            None,
+            serde_json::json!({ "enabled": payload.enabled, "generated": generated }),
+        );
+        state_write.room_code.clone()
+    };
+    challenge_stale_sockets(&state, &io);
+    e2e::announce(&state, &io);
 
     // Emit event to all clients to notify room code status changed
     let _ = io.emit(
@@ -290,7 +656,8 @@ pub async fn toggle_roomcode(
     (
         StatusCode::OK,
         Json(serde_json::json!({
-            "enabled": payload.enabled
+            "enabled": payload.enabled,
+            "code": code
         })),
     )
 }
@@ -302,26 +669,44 @@ pub struct RoomCodeUpdate {
 }
 
 pub async fn update_roomcode(
+    _admin: AdminOnly,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     axum::Extension(io): axum::Extension<SocketIo>,
     State(state): State<SharedState>,
     Json(payload): Json<RoomCodeUpdate>,
 ) -> impl IntoResponse {
-    if payload.code.len() != 6 || !payload.code.chars().all(|c| c.is_ascii_digit()) {
+    let format = state.read().unwrap().config.room_code_format;
+    if !validate_room_code(&payload.code, format) {
         return (
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
-                "error": "Room code must be exactly 6 digits"
+                "error": format.requirement()
             })),
         )
             .into_response();
     }
 
-    {
+    let admin_sockets: Vec<String> = {
         let mut state_write = state.write().unwrap();
         state_write.room_code = Some(payload.code.clone());
-    }
+        // Cookies issued for the old code stop working, guest ones are kept
+        let guest_code = state_write.config.guest_code.clone();
+        state_write
+            .access_tokens
+            .retain(|_, code| *code == payload.code || guest_code.as_ref() == Some(code));
+        state_write.audit.record(
+            "room_code_changed",
+            Some(addr.ip()),
+            None,
+            serde_json::json!({}),
+        );
+        state_write.admin_sockets.iter().cloned().collect()
+    };
+    challenge_stale_sockets(&state, &io);
+    e2e::announce(&state, &io);
 
-    let _ = io.emit(
+    // Only admins may see the new code
+    let _ = io.to(admin_sockets).emit(
         "roomcode-changed",
         serde_json::json!({
             "code": payload.code
@@ -336,3 +721,456 @@ pub async fn update_roomcode(
     )
         .into_response()
 }
+
+// POST /api/access
+#[derive(Deserialize)]
+pub struct AccessForm {
+    pub code: String,
+}
+
+pub async fn enter_room(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    axum::Extension(io): axum::Extension<SocketIo>,
+    State(state): State<SharedState>,
+    Form(form): Form<AccessForm>,
+) -> Response {
+    let (attempt, token) = {
+        let mut state_write = state.write().unwrap();
+        let attempt = try_room_code(
+            &mut state_write,
+            Some(addr.ip().to_canonical()),
+            form.code.trim(),
+        );
+        let token = matches!(attempt, CodeAttempt::Accepted)
+            .then(|| state_write.issue_access_token(Some(form.code.trim())));
+        (attempt, token)
+    };
+    notify_lockouts(&io, &attempt);
+
+    match token {
+        Some(token) => (
+            StatusCode::SEE_OTHER,
+            [
+                (header::LOCATION, "/".to_string()),
+                (header::SET_COOKIE, access_cookie(&token)),
+            ],
+        )
+            .into_response(),
+        None => denied_response(true, Some(&attempt)),
+    }
+}
+
+// POST /api/login
+#[derive(Deserialize)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+}
+
+pub async fn login(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<SharedState>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    let ip = addr.ip().to_canonical();
+    let hash = {
+        let state_read = state.read().unwrap();
+        if let Some(wait) = state_read.login_attempts.locked_for(Some(ip), now_millis()) {
+            let error = format!("尝试次数过多，请 {} 秒后再试", wait.div_ceil(1000));
+            let mut response = login_denied(true, StatusCode::TOO_MANY_REQUESTS, Some(&error));
+            if let Ok(value) = wait.div_ceil(1000).to_string().parse() {
+                response.headers_mut().insert(header::RETRY_AFTER, value);
+            }
+            return response;
+        }
+        account_hash(&state_read, &form.username).map(str::to_string)
+    };
+
+    // Argon2 is slow on purpose, keep it off the async workers
+    let password = form.password;
+    let valid = tokio::task::spawn_blocking(move || check_credentials(hash.as_deref(), &password))
+        .await
+        .unwrap_or(false);
+
+    let (token, secure) = {
+        let mut state_write = state.write().unwrap();
+        let secure = state_write.ca_certificate.is_some();
+        let details = serde_json::json!({ "username": form.username });
+        if valid {
+            state_write.login_attempts.record_success(Some(ip));
+            state_write.audit.record("login", Some(ip), None, details);
+            (Some(state_write.issue_login_token(&form.username)), secure)
+        } else {
+            let lockouts = state_write
+                .login_attempts
+                .record_failure(Some(ip), now_millis());
+            for lockout in &lockouts {
+                warn!(
+                    "Login locked for {} after {} failed attempts",
+                    lockout.ip.as_deref().unwrap_or("all clients"),
+                    lockout.failures
+                );
+            }
+            state_write
+                .audit
+                .record("login_failed", Some(ip), None, details);
+            (None, secure)
+        }
+    };
+
+    match token {
+        Some(token) => (
+            StatusCode::SEE_OTHER,
+            [
+                (header::LOCATION, "/".to_string()),
+                (header::SET_COOKIE, login_cookie(&token, secure)),
+            ],
+        )
+            .into_response(),
+        None => login_denied(true, StatusCode::UNAUTHORIZED, Some("用户名或密码错误")),
+    }
+}
+
+// POST /api/logout
+pub async fn logout(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    {
+        let mut state_write = state.write().unwrap();
+        let username = cookie_value(&headers, LOGIN_COOKIE)
+            .and_then(|token| state_write.login_tokens.remove(token));
+        if let Some((username, _)) = username {
+            state_write.audit.record(
+                "logout",
+                Some(addr.ip()),
+                None,
+                serde_json::json!({ "username": username }),
+            );
+        }
+    }
+    (
+        StatusCode::SEE_OTHER,
+        [
+            (header::LOCATION, "/".to_string()),
+            (header::SET_COOKIE, logout_cookie()),
+        ],
+    )
+        .into_response()
+}
+
+// GET /api/tls
+pub async fn get_tls_info(State(state): State<SharedState>) -> impl IntoResponse {
+    let state_read = state.read().unwrap();
+    Json(serde_json::json!({
+        "enabled": state_read.ca_fingerprint.is_some(),
+        "fingerprint": state_read.ca_fingerprint
+    }))
+}
+
+// GET /api/tls/ca
+pub async fn download_ca(State(state): State<SharedState>) -> Response {
+    let state_read = state.read().unwrap();
+    match state_read.ca_certificate {
+        Some(ref pem) => (
+            [
+                (header::CONTENT_TYPE, "application/x-x509-ca-cert"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"zher-ca.crt\"",
+                ),
+            ],
+            pem.clone(),
+        )
+            .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "HTTPS is not enabled" })),
+        )
+            .into_response(),
+    }
+}
+
+// GET /api/admin/audit?offset=&limit=&event=
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub event: Option<String>,
+}
+
+pub async fn get_audit_log(
+    _admin: AdminOnly,
+    State(state): State<SharedState>,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_PAGE);
+    let offset = query.offset;
+    let (paths, in_memory) = {
+        let state_read = state.read().unwrap();
+        let in_memory = state_read
+            .audit
+            .memory_page(offset, limit, query.event.as_deref());
+        (state_read.audit.paths(), in_memory)
+    };
+    let page = match in_memory {
+        Some(page) => Ok(page),
+        None => {
+            tokio::task::spawn_blocking(move || {
+                read_page(&paths, offset, limit, query.event.as_deref())
+            })
+            .await
+        }
+    };
+
+    match page {
+        Ok((entries, more)) => Json(serde_json::json!({
+            "entries": entries,
+            "offset": offset,
+            "limit": limit,
+            "hasMore": more
+        }))
+        .into_response(),
+        Err(e) => {
+            warn!("Failed to read audit log: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// GET /api/admin/accounts
+pub async fn list_accounts(
+    _admin: AdminOnly,
+    State(state): State<SharedState>,
+) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "accounts": account_names(&state.read().unwrap())
+    }))
+}
+
+// POST /api/admin/accounts
+#[derive(Deserialize)]
+pub struct PasswordUpdate {
+    pub username: String,
+    pub password: String,
+}
+
+/// Sets an account's password, adding the account if it is new, and saves
+/// the accounts to the config file.
+pub async fn set_password(
+    _admin: AdminOnly,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<SharedState>,
+    Json(payload): Json<PasswordUpdate>,
+) -> Response {
+    let username = payload.username.trim().to_string();
+    if let Err(error) = validate_account(&username, &payload.password) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": error })),
+        )
+            .into_response();
+    }
+
+    let password = payload.password;
+    let hashed = tokio::task::spawn_blocking(move || hash_password(&password)).await;
+    let hash = match hashed.map_err(|e| e.to_string()).and_then(|r| r) {
+        Ok(hash) => hash,
+        Err(e) => {
+            warn!("Failed to hash password: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let accounts = {
+        let mut state_write = state.write().unwrap();
+        let accounts = &mut state_write.config.accounts;
+        let created = match accounts.iter_mut().find(|a| a.username == username) {
+            Some(account) => {
+                account.password_hash = hash;
+                false
+            }
+            None => {
+                accounts.push(Account {
+                    username: username.clone(),
+                    password_hash: hash,
+                });
+                true
+            }
+        };
+        // Devices logged in with the old password have to log in again
+        state_write
+            .login_tokens
+            .retain(|_, (user, _)| *user != username);
+        state_write.audit.record(
+            if created {
+                "account_created"
+            } else {
+                "password_changed"
+            },
+            Some(addr.ip()),
+            None,
+            serde_json::json!({ "username": username }),
+        );
+        state_write.config.accounts.clone()
+    };
+
+    let saved = tokio::task::spawn_blocking(move || Config::save_accounts(&accounts)).await;
+    let saved = saved.map_err(|e| e.to_string());
+    if let Err(e) = saved.and_then(|r| r.map_err(|e| e.to_string())) {
+        warn!("Failed to save accounts: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": format!("Password changed until restart, saving failed: {}", e)
+            })),
+        )
+            .into_response();
+    }
+
+    Json(serde_json::json!({
+        "accounts": account_names(&state.read().unwrap())
+    }))
+    .into_response()
+}
+
+// GET /api/export?format=json|md|html&since=&archive=true
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<String>,
+    pub since: Option<u64>,
+    // Zip the transcript with the files the server keeps
+    #[serde(default)]
+    pub archive: bool,
+}
+
+pub async fn export_history(
+    RequestRole(role): RequestRole,
+    State(state): State<SharedState>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    if !state
+        .read()
+        .unwrap()
+        .config
+        .permissions
+        .allows(role, Permission::Export)
+    {
+        let body = Json(serde_json::json!({ "error": DENIED }));
+        return (StatusCode::FORBIDDEN, body).into_response();
+    }
+
+    let format = match ExportFormat::parse(query.format.as_deref().unwrap_or("json")) {
+        Some(format) => format,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "error": "Format must be one of json, md or html"
+                })),
+            )
+                .into_response();
+        }
+    };
+
+    let (mut entries, stored) = {
+        let state_read = state.read().unwrap();
+        let users: HashMap<String, User> = state_read
+            .sessions
+            .values()
+            .map(|s| (s.user.id.clone(), s.user.clone()))
+            .collect();
+        let entries = collect_entries(
+            &state_read.history,
+            &users,
+            &state_read.server_url,
+            query.since,
+        );
+        let stored: HashMap<String, PathBuf> = entries
+            .iter()
+            .filter_map(|e| e.file_id.as_ref())
+            .filter_map(|id| Some((id.clone(), state_read.scanned_copy(id)?.path.clone())))
+            .collect();
+        (entries, stored)
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    if query.archive {
+        let files = archive_files(&mut entries, &stored);
+        let transcript_name = format!("chat.{}", format.extension());
+        let transcript = render(format, &entries, now);
+        return export_archive(&transcript_name, transcript, files, now).await;
+    }
+
+    let body = render(format, &entries, now);
+    let content_disposition = format!(
+        "attachment; filename=\"zher-chat-{}.{}\"",
+        now / 1000,
+        format.extension()
+    );
+
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+// Builds the archive in a temporary file and streams it from there
+async fn export_archive(
+    transcript_name: &str,
+    transcript: String,
+    files: Vec<(String, PathBuf)>,
+    now: u64,
+) -> Response {
+    let path = std::env::temp_dir()
+        .join("zher")
+        .join(format!("export-{}.zip", uuid::Uuid::new_v4()));
+    let (archive_path, transcript_name) = (path.clone(), transcript_name.to_string());
+    let written = tokio::task::spawn_blocking(move || {
+        if let Some(parent) = archive_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let out = std::fs::File::create(&archive_path)?;
+        write_archive(out, &transcript_name, &transcript, &files).map_err(std::io::Error::other)
+    })
+    .await
+    .map_err(std::io::Error::other)
+    .and_then(|r| r);
+
+    let opened = match written {
+        Ok(()) => tokio::fs::File::open(&path).await,
+        Err(e) => Err(e),
+    };
+    // The open handle keeps the data readable where files can be unlinked
+    let _ = tokio::fs::remove_file(&path).await;
+    let file = match opened {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to build export archive: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let length = file.metadata().await.map(|m| m.len()).unwrap_or_default();
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"zher-chat-{}.zip\"", now / 1000),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response()
+}
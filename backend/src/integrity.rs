@@ -0,0 +1,172 @@
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::utils::to_hex;
+
+pub const INVALID_SHA256: &str = "文件校验值无效";
+
+/// Checks the optional `sha256` of a file announcement and lowercases it.
+pub fn check_file_meta(meta: &mut Value) -> Result<(), &'static str> {
+    let Some(obj) = meta.as_object_mut() else {
+        return Ok(());
+    };
+    let sha256 = match obj.get("sha256") {
+        None | Some(Value::Null) => return Ok(()),
+        Some(Value::String(s)) if s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit()) => {
+            s.to_ascii_lowercase()
+        }
+        Some(_) => return Err(INVALID_SHA256),
+    };
+    obj.insert("sha256".to_string(), Value::String(sha256));
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrity {
+    Match,
+    Mismatch,
+    // The upload ended before the announced size, `actual` covers what arrived
+    Incomplete,
+}
+
+/// Payload of the `transfer-integrity` event, sent to the sender and the
+/// receiver once a whole relayed file has been checked.
+#[derive(Clone, Debug, Serialize)]
+pub struct IntegrityReport {
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+    #[serde(rename = "transferId")]
+    pub transfer_id: String,
+    pub status: Integrity,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Hashes a relayed file as it passes and compares it with the hash the
+/// sender announced.
+pub struct StreamHasher {
+    hasher: Sha256,
+    expected: String,
+}
+
+impl StreamHasher {
+    pub fn new(expected: String) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            expected,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+    }
+
+    /// Returns the outcome with the expected and actual hash.
+    pub fn finish(self) -> (Integrity, String, String) {
+        let actual = to_hex(&self.hasher.finalize());
+        let status = if actual == self.expected {
+            Integrity::Match
+        } else {
+            Integrity::Mismatch
+        };
+        (status, self.expected, actual)
+    }
+}
+
+/// `Repr-Digest` header value (RFC 9530) for a hex SHA-256.
+pub fn repr_digest(sha256: &str) -> Option<String> {
+    let bytes = from_hex(sha256)?;
+    Some(format!("sha-256=:{}:", to_base64(&bytes)))
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn to_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // SHA-256 of "abc"
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_stream_hasher() {
+        let mut hasher = StreamHasher::new(ABC.to_string());
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(hasher.finish().0, Integrity::Match);
+
+        let mut hasher = StreamHasher::new(ABC.to_string());
+        hasher.update(b"abd");
+        let (status, expected, actual) = hasher.finish();
+        assert_eq!(status, Integrity::Mismatch);
+        assert_eq!(expected, ABC);
+        assert_ne!(actual, ABC);
+    }
+
+    #[test]
+    fn test_check_file_meta() {
+        let mut meta = json!({ "fileId": "f1", "sha256": ABC.to_uppercase() });
+        assert!(check_file_meta(&mut meta).is_ok());
+        assert_eq!(meta["sha256"], ABC);
+        assert!(check_file_meta(&mut json!({ "fileId": "f1" })).is_ok());
+        assert!(check_file_meta(&mut json!({ "sha256": "abc" })).is_err());
+        assert!(check_file_meta(&mut json!({ "sha256": 1 })).is_err());
+    }
+
+    #[test]
+    fn test_repr_digest() {
+        assert_eq!(
+            repr_digest(ABC).unwrap(),
+            "sha-256=:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=:"
+        );
+        assert!(repr_digest("zz").is_none());
+    }
+
+    #[test]
+    fn test_to_base64() {
+        // Test vectors from RFC 4648, section 10
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, output) in vectors {
+            assert_eq!(to_base64(input.as_bytes()), output);
+        }
+        assert_eq!(to_base64(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+}
@@ -0,0 +1,308 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+use tracing::info;
+
+use crate::config::Config;
+use crate::scheduler::now_millis;
+use crate::state::SharedState;
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// An address range such as `192.168.1.0/24` or `fd00::/8`. A bare address
+/// is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address in {}", s))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct IpBan {
+    pub ip: String,
+    // Unix time in milliseconds, None until the server restarts
+    pub until: Option<u64>,
+}
+
+/// Which addresses may reach the server at all. The configured lists are
+/// fixed at startup, bans are added by admins while it runs.
+#[derive(Default)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    // Peers whose X-Forwarded-For is believed
+    trusted_proxies: Vec<Cidr>,
+    bans: HashMap<IpAddr, Option<u64>>,
+}
+
+// Any bad entry fails the whole list. Skipping it would quietly widen an
+// allow list's effect or let a denied range in.
+fn parse_list(name: &str, entries: &[String]) -> Result<Vec<Cidr>, String> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse()
+                .map_err(|e| format!("Invalid {} entry: {}", name, e))
+        })
+        .collect()
+}
+
+impl IpFilter {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        Ok(Self {
+            allow: parse_list("allow_ips", &config.allow_ips)?,
+            deny: parse_list("deny_ips", &config.deny_ips)?,
+            trusted_proxies: parse_list("trusted_proxies", &config.trusted_proxies)?,
+            bans: HashMap::new(),
+        })
+    }
+
+    /// The client behind `peer`. Forwarded addresses are only followed
+    /// through trusted proxies, from the nearest hop outwards, so a client
+    /// can't claim another address by sending the header itself.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut ip = peer.to_canonical();
+        let mut hops = headers
+            .get_all(FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev();
+        while self.trusted_proxies.iter().any(|p| p.contains(ip)) {
+            match hops.next().and_then(|hop| hop.parse::<IpAddr>().ok()) {
+                Some(hop) => ip = hop.to_canonical(),
+                None => break,
+            }
+        }
+        ip
+    }
+
+    /// Loopback is never blocked, so the server machine can always get in
+    /// to undo a ban.
+    pub fn is_allowed(&self, ip: IpAddr, now: u64) -> bool {
+        let ip = ip.to_canonical();
+        if ip.is_loopback() {
+            return true;
+        }
+        if self
+            .bans
+            .get(&ip)
+            .is_some_and(|until| until.is_none_or(|until| until > now))
+        {
+            return false;
+        }
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, until: Option<u64>) -> Result<(), &'static str> {
+        let ip = ip.to_canonical();
+        if ip.is_loopback() {
+            return Err("不能封禁本机地址");
+        }
+        self.bans.insert(ip, until);
+        Ok(())
+    }
+
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        self.bans.remove(&ip.to_canonical()).is_some()
+    }
+
+    /// Active bans, expired ones are dropped.
+    pub fn bans(&mut self, now: u64) -> Vec<IpBan> {
+        self.bans
+            .retain(|_, until| until.is_none_or(|until| until > now));
+        let mut bans: Vec<IpBan> = self
+            .bans
+            .iter()
+            .map(|(ip, until)| IpBan {
+                ip: ip.to_string(),
+                until: *until,
+            })
+            .collect();
+        bans.sort_by(|a, b| a.ip.cmp(&b.ip));
+        bans
+    }
+}
+
+/// Outermost check on every request, including the Socket.IO handshake.
+/// Behind a trusted proxy the client address replaces the proxy's in
+/// `ConnectInfo`, so admin checks, lockouts and logs all see the client.
+pub async fn ip_guard(State(state): State<SharedState>, mut req: Request, next: Next) -> Response {
+    let Some(peer) = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0)
+    else {
+        return next.run(req).await;
+    };
+
+    let (ip, allowed) = {
+        let state_read = state.read().unwrap();
+        let ip = state_read.ip_filter.client_ip(peer.ip(), req.headers());
+        (ip, state_read.ip_filter.is_allowed(ip, now_millis()))
+    };
+    if !allowed {
+        info!("Blocked {} {} from {}", req.method(), req.uri().path(), ip);
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Address not allowed" })),
+        )
+            .into_response();
+    }
+
+    if ip != peer.ip() {
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(ip, peer.port())));
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn filter(allow: &[&str], deny: &[&str], proxies: &[&str]) -> IpFilter {
+        let list = |l: &[&str]| l.iter().map(|s| s.to_string()).collect();
+        let config = Config {
+            allow_ips: list(allow),
+            deny_ips: list(deny),
+            trusted_proxies: list(proxies),
+            ..Default::default()
+        };
+        IpFilter::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(net.contains(ip("192.168.1.77")));
+        assert!(!net.contains(ip("192.168.2.1")));
+        assert!(net.contains(ip("::ffff:192.168.1.5")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("192.168.1.1")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("8.8.8.8")));
+        let single: Cidr = "10.0.0.5".parse().unwrap();
+        assert!(single.contains(ip("10.0.0.5")));
+        assert!(!single.contains(ip("10.0.0.6")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("office".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_entries_fail() {
+        let config = Config {
+            deny_ips: vec!["10.0.0.5".to_string(), "office".to_string()],
+            ..Default::default()
+        };
+        let error = IpFilter::from_config(&config).err().unwrap();
+        assert!(error.contains("deny_ips"));
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let f = filter(&["192.168.1.0/24"], &["192.168.1.66"], &[]);
+        assert!(f.is_allowed(ip("192.168.1.20"), 0));
+        assert!(!f.is_allowed(ip("192.168.1.66"), 0));
+        assert!(!f.is_allowed(ip("192.168.50.3"), 0));
+        assert!(f.is_allowed(ip("127.0.0.1"), 0));
+
+        let mut f = filter(&[], &[], &[]);
+        assert!(f.is_allowed(ip("10.1.2.3"), 0));
+        f.ban(ip("10.1.2.3"), Some(1000)).unwrap();
+        assert!(!f.is_allowed(ip("10.1.2.3"), 500));
+        assert!(f.is_allowed(ip("10.1.2.3"), 1000));
+        assert!(f.bans(1000).is_empty());
+
+        assert!(f.ban(ip("::1"), None).is_err());
+        f.ban(ip("10.1.2.4"), None).unwrap();
+        assert!(f.unban(ip("10.1.2.4")));
+        assert!(f.is_allowed(ip("10.1.2.4"), 0));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR, "6.6.6.6, 192.168.1.20".parse().unwrap());
+
+        // Not trusted: the header is ignored
+        let f = filter(&[], &[], &[]);
+        assert_eq!(f.client_ip(ip("127.0.0.1"), &headers), ip("127.0.0.1"));
+
+        // Only the hop added by the trusted proxy counts
+        let f = filter(&[], &[], &["127.0.0.1"]);
+        assert_eq!(f.client_ip(ip("127.0.0.1"), &headers), ip("192.168.1.20"));
+        assert_eq!(f.client_ip(ip("192.168.1.9"), &headers), ip("192.168.1.9"));
+        assert_eq!(
+            f.client_ip(ip("127.0.0.1"), &HeaderMap::new()),
+            ip("127.0.0.1")
+        );
+    }
+}
@@ -1,6 +1,29 @@
+pub mod access;
+pub mod audit;
+pub mod auth;
+pub mod config;
 pub mod discovery;
+pub mod e2e;
+pub mod export;
+pub mod filename;
 pub mod handlers;
+pub mod integrity;
+pub mod ipfilter;
+pub mod limits;
+pub mod links;
+pub mod lockout;
+pub mod login;
+pub mod origin;
+pub mod pairing;
+pub mod ratelimit;
+pub mod roles;
+pub mod scan;
+pub mod scheduler;
+pub mod signing;
 pub mod state;
+pub mod store;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod utils;
 pub mod ws;
 
@@ -22,12 +45,21 @@ use std::{
 };
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
-use tower_http::cors::CorsLayer;
 
+use crate::access::room_code_guard;
+use crate::audit::AuditLog;
+use crate::config::Config;
 use crate::handlers::{
-    download_file, get_roomcode, static_handler, toggle_discovery, toggle_roomcode,
-    update_roomcode, upload_file,
+    download_ca, download_file, enter_room, export_history, get_audit_log, get_roomcode,
+    get_tls_info, list_accounts, login, logout, set_password, static_handler, toggle_discovery,
+    toggle_roomcode, update_roomcode, upload_file,
 };
+use crate::ipfilter::IpFilter;
+use crate::login::login_guard;
+use crate::pairing::DeviceRegistry;
+use crate::ratelimit::RateLimiter;
+use crate::scheduler::Scheduler;
+use crate::signing::SessionSigner;
 use crate::state::AppState;
 use crate::ws::on_connect;
 
@@ -50,6 +82,36 @@ async fn run_server_internal(
 ) -> Result<(), Box<dyn std::error::Error>> {
     let addr_str = format!("{}:{}", host, port);
 
+    let config = Config::load()?;
+    let http_redirect_port = config.http_redirect_port;
+    let max_payload = config.max_payload;
+
+    #[cfg(not(feature = "tls"))]
+    if config.tls {
+        tracing::warn!("tls is set but this build has no TLS support, serving plain HTTP");
+    }
+    #[cfg(feature = "tls")]
+    let certificates = if config.tls {
+        let dir = config.data_path("tls");
+        if dir.is_none() {
+            tracing::warn!("No data_dir set, phones must trust a new CA after every restart");
+        }
+        let certificates =
+            tls::TlsCertificates::load_or_generate(dir.as_deref(), &tls::detect_hosts())?;
+        tracing::info!("CA fingerprint (SHA-256): {}", certificates.fingerprint);
+        Some(certificates)
+    } else {
+        None
+    };
+    #[cfg(feature = "tls")]
+    let scheme = if certificates.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    #[cfg(not(feature = "tls"))]
+    let scheme = "http";
+
     // Determine server URL for QR code
     let my_local_ip = local_ip().unwrap_or("127.0.0.1".parse().unwrap());
 
@@ -58,31 +120,105 @@ async fn run_server_internal(
     } else {
         host.clone()
     };
-    let server_url = format!("http://{}:{}", display_host, port);
+    let server_url = format!("{}://{}:{}", scheme, display_host, port);
+
+    // Every address the server answers on, for the Origin check
+    let mut hosts: Vec<String> = local_ip_address::list_afinet_netifas()
+        .map(|ifas| ifas.into_iter().map(|(_, ip)| ip.to_string()).collect())
+        .unwrap_or_default();
+    hosts.push(display_host.clone());
+    #[cfg(feature = "tls")]
+    if certificates.is_some() {
+        hosts.extend(tls::server_names(&tls::detect_hosts()));
+    }
+    let allowed_origins =
+        origin::allowed_origins(scheme, port.parse()?, &hosts, &config.allowed_origins);
 
     let mut state_val = AppState::default();
     state_val.server_url = server_url.clone();
+    state_val.allowed_origins = allowed_origins.clone();
+    state_val.scheduler = Scheduler::new(config.data_path("scheduled.json"));
+    state_val.devices = DeviceRegistry::new(config.data_path("devices.json"));
+    state_val.signer = SessionSigner::new(config.data_dir.clone());
+    state_val.ip_filter = IpFilter::from_config(&config)?;
+    state_val.rate_limiter = RateLimiter::new(config.rate_limits.clone());
+    state_val.scanner = config.scanner.as_ref().map(scan::from_config);
+    // Without a data directory the log is only kept in memory
+    let audit_path = config.data_path("audit.log");
+    match audit_path {
+        Some(ref path) => tracing::info!("Audit log: {}", path.display()),
+        None => tracing::info!("Audit log kept in memory, no data_dir set"),
+    }
+    state_val.audit = AuditLog::new(audit_path, config.audit_max_bytes, config.audit_files);
+    if let Some(ref token) = config.admin_token {
+        state_val.admin_token = token.clone();
+    }
+    state_val.config = config;
+    #[cfg(feature = "tls")]
+    if let Some(ref certificates) = certificates {
+        state_val.ca_certificate = Some(certificates.ca_pem.clone());
+        state_val.ca_fingerprint = Some(certificates.fingerprint.clone());
+    }
+
+    tracing::info!(
+        "Admin access: {}/?admin={}",
+        server_url,
+        state_val.admin_token
+    );
     let state = Arc::new(RwLock::new(state_val));
 
     let (layer, io) = SocketIo::builder()
         .with_state(state.clone())
-        .max_payload(100 * 1024 * 1024) // 100MB
+        .max_payload(max_payload)
         .build_layer();
 
     io.ns("/", on_connect);
 
+    let scheduler_handle = tokio::spawn(scheduler::run(state.clone(), io.clone()));
+
     let app = Router::new()
         .route("/api/upload/:transfer_id", post(upload_file))
         .route("/api/download/:file_id", get(download_file))
         .route("/api/discovery", post(toggle_discovery))
         .route("/api/roomcode", get(get_roomcode).post(update_roomcode))
         .route("/api/roomcode/toggle", post(toggle_roomcode))
+        .route("/api/export", get(export_history))
+        .route("/api/access", post(enter_room))
+        .route("/api/login", post(login))
+        .route("/api/logout", post(logout))
+        .route("/api/tls", get(get_tls_info))
+        .route("/api/tls/ca", get(download_ca))
+        .route("/api/admin/audit", get(get_audit_log))
+        .route("/api/admin/accounts", get(list_accounts).post(set_password))
         .fallback(static_handler)
         .layer(
             ServiceBuilder::new()
-                .layer(CorsLayer::permissive())
-                .layer(layer)
-                .layer(axum::Extension(io)),
+                // First, so it also resolves the client address for the rest
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    ipfilter::ip_guard,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    ratelimit::rate_guard,
+                ))
+                .layer(origin::cors_layer(&allowed_origins))
+                // Before the room code check so forged requests can't cause lockouts
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    origin::origin_guard,
+                ))
+                .layer(axum::Extension(io))
+                // Outside the Socket.IO layer so the handshake is covered too
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    login_guard,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    room_code_guard,
+                ))
+                .layer(layer),
         )
         .with_state(state.clone());
 
@@ -95,10 +231,61 @@ async fn run_server_internal(
         discovery.start();
     }
 
+    // Plain HTTP listener that only redirects to HTTPS
+    #[cfg(feature = "tls")]
+    let redirect_handle = match (certificates.is_some(), http_redirect_port) {
+        (true, Some(redirect_port)) => {
+            let redirect_listener =
+                TcpListener::bind(format!("{}:{}", host, redirect_port)).await?;
+            Some(tokio::spawn(tls::redirect_http(
+                redirect_listener,
+                port.parse()?,
+                display_host.clone(),
+                state.clone(),
+            )))
+        }
+        _ => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    if http_redirect_port.is_some() {
+        tracing::warn!("http_redirect_port needs a build with TLS support, ignoring it");
+    }
+
     // Clone state for cleanup
     let cleanup_state = state.clone();
 
     // Run server with optional graceful shutdown
+    #[cfg(feature = "tls")]
+    if let Some(ref certificates) = certificates {
+        let tls_config = tls::rustls_config(certificates).await?;
+        tls::serve(listener, app, tls_config, shutdown_rx).await?;
+    } else {
+        serve_http(listener, app, shutdown_rx).await?;
+    }
+    #[cfg(not(feature = "tls"))]
+    serve_http(listener, app, shutdown_rx).await?;
+
+    scheduler_handle.abort();
+    #[cfg(feature = "tls")]
+    if let Some(redirect_handle) = redirect_handle {
+        redirect_handle.abort();
+    }
+
+    // Stop discovery service when server stops
+    {
+        let state_read = cleanup_state.read().unwrap();
+        let mut discovery = state_read.discovery.lock().unwrap();
+        discovery.stop();
+    }
+
+    Ok(())
+}
+
+async fn serve_http(
+    listener: TcpListener,
+    app: Router,
+    shutdown_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+) -> std::io::Result<()> {
     if let Some(shutdown_rx) = shutdown_rx {
         axum::serve(
             listener,
@@ -107,21 +294,12 @@ async fn run_server_internal(
         .with_graceful_shutdown(async move {
             let _ = shutdown_rx.await;
         })
-        .await?;
+        .await
     } else {
         axum::serve(
             listener,
             app.into_make_service_with_connect_info::<SocketAddr>(),
         )
-        .await?;
-    }
-
-    // Stop discovery service when server stops
-    {
-        let state_read = cleanup_state.read().unwrap();
-        let mut discovery = state_read.discovery.lock().unwrap();
-        discovery.stop();
+        .await
     }
-
-    Ok(())
 }
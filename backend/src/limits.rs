@@ -0,0 +1,145 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::export::format_size;
+
+pub const INVALID_FILE_SIZE: &str = "文件大小无效";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    MessageSize,
+    FileSize,
+    Storage,
+}
+
+/// Payload of the `limit-exceeded` event.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LimitExceeded {
+    // Event that was rejected
+    pub event: &'static str,
+    pub limit: Limit,
+    // Both in bytes
+    pub max: u64,
+    pub size: u64,
+    pub message: String,
+}
+
+impl LimitExceeded {
+    fn new(event: &'static str, limit: Limit, max: u64, size: u64) -> Self {
+        let message = match limit {
+            Limit::MessageSize => format!("消息太长，最多 {}", format_size(max)),
+            Limit::FileSize => format!("文件太大，最多 {}", format_size(max)),
+            Limit::Storage => format!("服务器为该用户保存的文件已达上限 {}", format_size(max)),
+        };
+        Self {
+            event,
+            limit,
+            max,
+            size,
+            message,
+        }
+    }
+}
+
+// A limit of zero is no limit
+fn exceeds(max: u64, size: u64) -> bool {
+    max > 0 && size > max
+}
+
+pub fn check_message(
+    config: &Config,
+    event: &'static str,
+    bytes: u64,
+) -> Result<(), LimitExceeded> {
+    let max = config.max_message_bytes;
+    if exceeds(max, bytes) {
+        return Err(LimitExceeded::new(event, Limit::MessageSize, max, bytes));
+    }
+    Ok(())
+}
+
+/// The announced size of a file, if it is a number within the limit.
+/// Returns Ok(None) when the size is missing or not a byte count.
+pub fn check_file_meta(
+    config: &Config,
+    event: &'static str,
+    meta: &Value,
+) -> Result<Option<u64>, LimitExceeded> {
+    let Some(size) = meta.get("fileSize").and_then(|v| v.as_u64()) else {
+        return Ok(None);
+    };
+    let max = config.max_file_size;
+    if exceeds(max, size) {
+        return Err(LimitExceeded::new(event, Limit::FileSize, max, size));
+    }
+    Ok(Some(size))
+}
+
+/// Whether the server may keep another `size` bytes for a user who already
+/// has `stored` bytes kept.
+pub fn check_storage(
+    config: &Config,
+    event: &'static str,
+    stored: u64,
+    size: u64,
+) -> Result<(), LimitExceeded> {
+    let max = config.max_stored_bytes_per_user;
+    let total = stored.saturating_add(size);
+    if exceeds(max, total) {
+        return Err(LimitExceeded::new(event, Limit::Storage, max, total));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_limits() {
+        let config = Config {
+            max_message_bytes: 10,
+            max_file_size: 100,
+            max_stored_bytes_per_user: 150,
+            ..Default::default()
+        };
+        assert!(check_message(&config, "text-message", 10).is_ok());
+        let error = check_message(&config, "text-message", 11).unwrap_err();
+        assert_eq!(error.limit, Limit::MessageSize);
+        assert_eq!((error.max, error.size), (10, 11));
+
+        assert_eq!(
+            check_file_meta(&config, "file-meta", &json!({ "fileSize": 100 })),
+            Ok(Some(100))
+        );
+        assert_eq!(
+            check_file_meta(&config, "file-meta", &json!({ "fileSize": -1 })),
+            Ok(None)
+        );
+        assert!(check_file_meta(&config, "file-meta", &json!({ "fileSize": 101 })).is_err());
+
+        assert!(check_storage(&config, "pin-message", 50, 100).is_ok());
+        assert!(check_storage(&config, "pin-message", 51, 100).is_err());
+
+        let unlimited = Config {
+            max_file_size: 0,
+            ..Default::default()
+        };
+        assert!(check_file_meta(&unlimited, "file-meta", &json!({ "fileSize": u64::MAX })).is_ok());
+    }
+
+    #[test]
+    fn test_storage_overflow() {
+        let config = Config {
+            max_stored_bytes_per_user: 150,
+            ..Default::default()
+        };
+        // A huge size must not wrap around to a small total
+        let error = check_storage(&config, "pin-message", 100, u64::MAX).unwrap_err();
+        assert_eq!(error.size, u64::MAX);
+        assert!(check_storage(&config, "pin-message", u64::MAX, u64::MAX).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::signing::SessionSigner;
+use crate::utils::constant_time_eq;
+
+// Longest a link may stay valid, in seconds
+pub const MAX_LINK_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// Query of a signed download link,
+/// `/api/download/<file id>?link=<id>&expires=<ms>&sig=<mac>`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LinkQuery {
+    pub link: Option<String>,
+    pub expires: Option<u64>,
+    pub sig: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DownloadLink {
+    pub id: String,
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
+    #[serde(rename = "maxDownloads")]
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    // User who created the link
+    #[serde(skip)]
+    pub created_by: String,
+}
+
+/// Download links handed out for use outside the chat. The signature keeps
+/// the file and expiry from being altered, the registry counts downloads
+/// and lets a file's links be revoked together.
+#[derive(Default)]
+pub struct LinkRegistry {
+    links: HashMap<String, DownloadLink>,
+}
+
+fn link_mac(signer: &SessionSigner, link_id: &str, file_id: &str, expires_at: u64) -> String {
+    signer.derive(&format!("link.{}.{}.{}", link_id, file_id, expires_at))
+}
+
+impl LinkRegistry {
+    /// Creates a link and returns it with the query string that goes with it.
+    pub fn create(
+        &mut self,
+        signer: &SessionSigner,
+        file_id: &str,
+        expires_at: u64,
+        max_downloads: Option<u32>,
+        created_by: &str,
+    ) -> (DownloadLink, String) {
+        let link = DownloadLink {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            file_id: file_id.to_string(),
+            expires_at,
+            max_downloads,
+            downloads: 0,
+            created_by: created_by.to_string(),
+        };
+        let query = format!(
+            "link={}&expires={}&sig={}",
+            link.id,
+            expires_at,
+            link_mac(signer, &link.id, file_id, expires_at)
+        );
+        self.links.insert(link.id.clone(), link.clone());
+        (link, query)
+    }
+
+    /// The link `query` names, if its signature matches `file_id` and it has
+    /// neither expired nor been revoked.
+    pub fn check(
+        &self,
+        signer: &SessionSigner,
+        file_id: &str,
+        query: &LinkQuery,
+        now: u64,
+    ) -> Option<&DownloadLink> {
+        let (Some(id), Some(expires_at), Some(sig)) = (&query.link, query.expires, &query.sig)
+        else {
+            return None;
+        };
+        let expected = link_mac(signer, id, file_id, expires_at);
+        if !constant_time_eq(sig.as_bytes(), expected.as_bytes()) || expires_at <= now {
+            return None;
+        }
+        self.links
+            .get(id)
+            .filter(|link| link.file_id == file_id && link.expires_at == expires_at)
+    }
+
+    /// Lets a download through if the link has downloads left. Every request
+    /// uses one up, ranged ones included, so a limited link can't be drained
+    /// piece by piece.
+    pub fn redeem(&mut self, link_id: &str) -> bool {
+        let Some(link) = self.links.get_mut(link_id) else {
+            return false;
+        };
+        if link.max_downloads.is_some_and(|max| link.downloads >= max) {
+            return false;
+        }
+        link.downloads += 1;
+        true
+    }
+
+    /// Gives back the download of a request that didn't serve the file.
+    pub fn refund(&mut self, link_id: &str) {
+        if let Some(link) = self.links.get_mut(link_id) {
+            link.downloads = link.downloads.saturating_sub(1);
+        }
+    }
+
+    pub fn get(&self, link_id: &str) -> Option<&DownloadLink> {
+        self.links.get(link_id)
+    }
+
+    /// Invalidates every link to `file_id` and returns how many there were.
+    pub fn revoke_file(&mut self, file_id: &str) -> usize {
+        let before = self.links.len();
+        self.links.retain(|_, link| link.file_id != file_id);
+        before - self.links.len()
+    }
+
+    pub fn prune(&mut self, now: u64) {
+        self.links.retain(|_, link| link.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> LinkQuery {
+        let param = |name: &str| {
+            query
+                .split('&')
+                .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_string)
+        };
+        LinkQuery {
+            link: param("link"),
+            expires: param("expires").and_then(|e| e.parse().ok()),
+            sig: param("sig"),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let signer = SessionSigner::new(None);
+        let mut links = LinkRegistry::default();
+        let (link, query) = links.create(&signer, "file-1", 1000, None, "user-1");
+        let query = parse(&query);
+
+        assert_eq!(
+            links.check(&signer, "file-1", &query, 500).unwrap().id,
+            link.id
+        );
+        assert!(links.check(&signer, "file-2", &query, 500).is_none());
+        assert!(links.check(&signer, "file-1", &query, 1000).is_none());
+
+        // Pushing the expiry back breaks the signature
+        let extended = LinkQuery {
+            expires: Some(5000),
+            ..query.clone()
+        };
+        assert!(links.check(&signer, "file-1", &extended, 500).is_none());
+
+        let other = SessionSigner::new(None);
+        assert!(links.check(&other, "file-1", &query, 500).is_none());
+
+        assert_eq!(links.revoke_file("file-1"), 1);
+        assert!(links.check(&signer, "file-1", &query, 500).is_none());
+    }
+
+    #[test]
+    fn test_redeem() {
+        let signer = SessionSigner::new(None);
+        let mut links = LinkRegistry::default();
+        let (link, _) = links.create(&signer, "file-1", 1000, Some(2), "user-1");
+
+        assert!(links.redeem(&link.id));
+        assert!(links.redeem(&link.id));
+        assert!(!links.redeem(&link.id));
+        assert_eq!(links.get(&link.id).unwrap().downloads, 2);
+
+        // A failed request doesn't count
+        links.refund(&link.id);
+        assert!(links.redeem(&link.id));
+        assert!(!links.redeem(&link.id));
+
+        let (unlimited, _) = links.create(&signer, "file-1", 1000, None, "user-1");
+        for _ in 0..10 {
+            assert!(links.redeem(&unlimited.id));
+        }
+        links.prune(1000);
+        assert!(links.get(&unlimited.id).is_none());
+    }
+}
@@ -0,0 +1,180 @@
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+};
+
+// Failed room code attempts an IP gets before backoff starts
+const FREE_ATTEMPTS: u32 = 5;
+// First lockout, doubled on every further failure
+const BASE_LOCKOUT_MS: u64 = 30 * 1000;
+const MAX_LOCKOUT_MS: u64 = 60 * 60 * 1000;
+// An IP's failures are forgotten after this long without a new one
+const FAILURE_RESET_MS: u64 = 60 * 60 * 1000;
+// Failures from all IPs within the window that lock everyone out,
+// for guessing spread over many addresses
+const GLOBAL_WINDOW_MS: u64 = 60 * 1000;
+const GLOBAL_MAX_FAILURES: usize = 50;
+const GLOBAL_LOCKOUT_MS: u64 = 5 * 60 * 1000;
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Lockout {
+    // None for the global lockout
+    pub ip: Option<String>,
+    // Unix time in milliseconds
+    pub until: u64,
+    pub failures: u32,
+}
+
+#[derive(Default)]
+struct IpFailures {
+    count: u32,
+    last: u64,
+    locked_until: u64,
+}
+
+/// Failed room code attempts, per IP and across all clients. Times are Unix
+/// milliseconds so callers can pass `scheduler::now_millis()`.
+#[derive(Default)]
+pub struct AttemptTracker {
+    per_ip: HashMap<IpAddr, IpFailures>,
+    recent: VecDeque<u64>,
+    global_locked_until: u64,
+}
+
+impl AttemptTracker {
+    /// Milliseconds until `ip` may try again, if it is locked out.
+    pub fn locked_for(&self, ip: Option<IpAddr>, now: u64) -> Option<u64> {
+        let ip_until = ip
+            .and_then(|ip| self.per_ip.get(&ip))
+            .map_or(0, |f| f.locked_until);
+        let until = ip_until.max(self.global_locked_until);
+        (until > now).then(|| until - now)
+    }
+
+    /// Records a wrong code and returns any lockouts it started.
+    pub fn record_failure(&mut self, ip: Option<IpAddr>, now: u64) -> Vec<Lockout> {
+        let mut lockouts = Vec::new();
+
+        self.per_ip
+            .retain(|_, f| now.saturating_sub(f.last) <= FAILURE_RESET_MS || f.locked_until > now);
+
+        if let Some(ip) = ip {
+            let failures = self.per_ip.entry(ip).or_default();
+            failures.count += 1;
+            failures.last = now;
+            if failures.count >= FREE_ATTEMPTS {
+                let doublings = (failures.count - FREE_ATTEMPTS).min(16);
+                failures.locked_until = now + (BASE_LOCKOUT_MS << doublings).min(MAX_LOCKOUT_MS);
+                lockouts.push(Lockout {
+                    ip: Some(ip.to_string()),
+                    until: failures.locked_until,
+                    failures: failures.count,
+                });
+            }
+        }
+
+        self.recent.push_back(now);
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.saturating_sub(*t) > GLOBAL_WINDOW_MS)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= GLOBAL_MAX_FAILURES && self.global_locked_until <= now {
+            self.global_locked_until = now + GLOBAL_LOCKOUT_MS;
+            lockouts.push(Lockout {
+                ip: None,
+                until: self.global_locked_until,
+                failures: self.recent.len() as u32,
+            });
+            self.recent.clear();
+        }
+
+        lockouts
+    }
+
+    pub fn record_success(&mut self, ip: Option<IpAddr>) {
+        if let Some(ip) = ip {
+            self.per_ip.remove(&ip);
+        }
+    }
+
+    pub fn active_lockouts(&self, now: u64) -> Vec<Lockout> {
+        let mut lockouts: Vec<Lockout> = self
+            .per_ip
+            .iter()
+            .filter(|(_, f)| f.locked_until > now)
+            .map(|(ip, f)| Lockout {
+                ip: Some(ip.to_string()),
+                until: f.locked_until,
+                failures: f.count,
+            })
+            .collect();
+        if self.global_locked_until > now {
+            lockouts.push(Lockout {
+                ip: None,
+                until: self.global_locked_until,
+                failures: GLOBAL_MAX_FAILURES as u32,
+            });
+        }
+        lockouts.sort_by_key(|l| l.until);
+        lockouts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([192, 168, 1, last]))
+    }
+
+    #[test]
+    fn test_backoff_doubles() {
+        let mut tracker = AttemptTracker::default();
+        for _ in 1..FREE_ATTEMPTS {
+            assert!(tracker.record_failure(ip(2), 0).is_empty());
+        }
+        assert_eq!(tracker.locked_for(ip(2), 0), None);
+
+        let lockouts = tracker.record_failure(ip(2), 0);
+        assert_eq!(lockouts[0].until, BASE_LOCKOUT_MS);
+        assert_eq!(
+            tracker.locked_for(ip(2), 1000),
+            Some(BASE_LOCKOUT_MS - 1000)
+        );
+        assert_eq!(tracker.locked_for(ip(3), 1000), None);
+
+        let lockouts = tracker.record_failure(ip(2), BASE_LOCKOUT_MS);
+        assert_eq!(lockouts[0].until, BASE_LOCKOUT_MS + 2 * BASE_LOCKOUT_MS);
+    }
+
+    #[test]
+    fn test_success_resets_ip() {
+        let mut tracker = AttemptTracker::default();
+        for _ in 0..FREE_ATTEMPTS {
+            tracker.record_failure(ip(2), 0);
+        }
+        tracker.record_success(ip(2));
+        assert_eq!(tracker.locked_for(ip(2), 0), None);
+        assert!(tracker.active_lockouts(0).is_empty());
+    }
+
+    #[test]
+    fn test_global_lockout() {
+        let mut tracker = AttemptTracker::default();
+        let mut lockouts = Vec::new();
+        for i in 0..GLOBAL_MAX_FAILURES {
+            // One failure per address stays under the per-IP limit
+            let addr = Some(IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]));
+            lockouts.extend(tracker.record_failure(addr, 0));
+        }
+        assert_eq!(lockouts.len(), 1);
+        assert_eq!(lockouts[0].ip, None);
+        assert!(tracker.locked_for(ip(99), 0).is_some());
+        assert_eq!(tracker.locked_for(ip(99), GLOBAL_LOCKOUT_MS), None);
+    }
+}
@@ -0,0 +1,272 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::OnceLock};
+
+use crate::access::{cookie_value, has_download_link, wants_html};
+use crate::auth::{is_admin, ADMIN_TOKEN_HEADER};
+use crate::export::escape_html;
+use crate::scheduler::now_millis;
+use crate::state::{AppState, SharedState};
+
+pub const LOGIN_COOKIE: &str = "zher_login";
+// How long a login lasts
+pub const LOGIN_TTL_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+// Routes reachable without logging in
+const PUBLIC_PATHS: [&str; 4] = ["/api/login", "/api/logout", "/api/tls", "/api/tls/ca"];
+
+const MAX_USERNAME_CHARS: usize = 64;
+const MIN_PASSWORD_CHARS: usize = 8;
+
+/// A login from the config file. Only the argon2 hash of the password is
+/// stored, in PHC string format.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Account {
+    pub username: String,
+    pub password_hash: String,
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt_bytes: [u8; 16] = rand::thread_rng().gen();
+    let salt = SaltString::encode_b64(&salt_bytes).map_err(|e| e.to_string())?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Whether `password` matches `hash`. A malformed hash matches nothing.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+/// Checks a password against an account's hash, None for unknown users.
+/// Those are checked against a throwaway hash so they take as long to turn
+/// down.
+pub fn check_credentials(hash: Option<&str>, password: &str) -> bool {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    match hash {
+        Some(hash) => verify_password(hash, password),
+        None => {
+            let dummy = DUMMY_HASH.get_or_init(|| hash_password("").unwrap_or_default());
+            verify_password(dummy, password);
+            false
+        }
+    }
+}
+
+pub fn validate_account(username: &str, password: &str) -> Result<(), &'static str> {
+    if username.is_empty()
+        || username.chars().count() > MAX_USERNAME_CHARS
+        || username.chars().any(char::is_control)
+    {
+        return Err("Username must be 1 to 64 characters");
+    }
+    if password.chars().count() < MIN_PASSWORD_CHARS {
+        return Err("Password must be at least 8 characters");
+    }
+    Ok(())
+}
+
+/// Login is on as soon as the config lists an account.
+pub fn login_required(state: &AppState) -> bool {
+    !state.config.accounts.is_empty()
+}
+
+pub fn account_hash<'a>(state: &'a AppState, username: &str) -> Option<&'a str> {
+    state
+        .config
+        .accounts
+        .iter()
+        .find(|a| a.username == username)
+        .map(|a| a.password_hash.as_str())
+}
+
+pub fn account_names(state: &AppState) -> Vec<String> {
+    state
+        .config
+        .accounts
+        .iter()
+        .map(|a| a.username.clone())
+        .collect()
+}
+
+/// The account a request's login cookie belongs to, while the login hasn't
+/// expired and the account still exists.
+pub fn logged_in_user<'a>(state: &'a AppState, headers: &HeaderMap) -> Option<&'a str> {
+    let now = now_millis();
+    cookie_value(headers, LOGIN_COOKIE)
+        .and_then(|token| state.login_tokens.get(token))
+        .filter(|(username, expires_at)| {
+            *expires_at > now && account_hash(state, username).is_some()
+        })
+        .map(|(username, _)| username.as_str())
+}
+
+/// Whether a request may go past the login: login is off, the client is an
+/// admin, or it holds a login cookie for an existing account.
+pub fn has_login(state: &AppState, headers: &HeaderMap, addr: Option<&SocketAddr>) -> bool {
+    if !login_required(state) {
+        return true;
+    }
+    let admin_token = headers
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok());
+    is_admin(&state.admin_token, addr, admin_token) || logged_in_user(state, headers).is_some()
+}
+
+/// Cookie for a new login, only sent over HTTPS when the server uses TLS.
+pub fn login_cookie(token: &str, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+        LOGIN_COOKIE,
+        token,
+        LOGIN_TTL_MS / 1000,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+pub fn logout_cookie() -> String {
+    format!(
+        "{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0",
+        LOGIN_COOKIE
+    )
+}
+
+/// Response for a request that isn't logged in, or a failed login.
+pub fn login_denied(html: bool, status: StatusCode, error: Option<&str>) -> Response {
+    if html {
+        (status, Html(login_page(error))).into_response()
+    } else {
+        (status, error.unwrap_or("Login required").to_string()).into_response()
+    }
+}
+
+/// Login check in front of every route, including the Socket.IO handshake.
+/// Runs before the room code check, which still applies once logged in.
+pub async fn login_guard(State(state): State<SharedState>, req: Request, next: Next) -> Response {
+    if PUBLIC_PATHS.contains(&req.uri().path()) || has_download_link(&state, req.uri()) {
+        return next.run(req).await;
+    }
+    let addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0);
+    let allowed = has_login(&state.read().unwrap(), req.headers(), addr.as_ref());
+    if allowed {
+        return next.run(req).await;
+    }
+    login_denied(wants_html(&req), StatusCode::UNAUTHORIZED, None)
+}
+
+pub fn login_page(error: Option<&str>) -> String {
+    let error = error
+        .map(|e| format!("<p class=\"error\">{}</p>", escape_html(e)))
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>zhe'r</title>
+<style>
+body {{ font-family: sans-serif; display: flex; min-height: 100vh; margin: 0; align-items: center; justify-content: center; background: #f3f4f6; }}
+form {{ background: #fff; padding: 2rem; border-radius: 0.75rem; box-shadow: 0 1px 3px rgba(0,0,0,.1); display: flex; flex-direction: column; gap: 0.75rem; }}
+input, button {{ font-size: 1rem; padding: 0.5rem 0.75rem; border-radius: 0.5rem; border: 1px solid #d1d5db; }}
+button {{ background: #2563eb; color: #fff; border: none; }}
+.error {{ color: #dc2626; margin: 0; }}
+</style>
+</head>
+<body>
+<form method="post" action="/api/login">
+<label for="username">用户名</label>
+<input id="username" name="username" autocomplete="username" autofocus required>
+<label for="password">密码</label>
+<input id="password" name="password" type="password" autocomplete="current-password" required>
+{}
+<button type="submit">登录</button>
+</form>
+</body>
+</html>
+"#,
+        error
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header;
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password(&hash, "correct horse"));
+        assert!(!verify_password(&hash, "wrong horse"));
+        assert!(!verify_password("not a hash", "correct horse"));
+        assert!(!check_credentials(None, "correct horse"));
+    }
+
+    #[test]
+    fn test_has_login() {
+        let lan: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+        let loopback: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let mut state = AppState::default();
+        let headers = HeaderMap::new();
+        assert!(has_login(&state, &headers, Some(&lan)));
+
+        state.config.accounts.push(Account {
+            username: "alice".to_string(),
+            password_hash: "$argon2id$unused".to_string(),
+        });
+        assert!(!has_login(&state, &headers, Some(&lan)));
+        assert!(has_login(&state, &headers, Some(&loopback)));
+
+        let token = state.issue_login_token("alice");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            format!("{}={}", LOGIN_COOKIE, token).parse().unwrap(),
+        );
+        assert!(has_login(&state, &headers, Some(&lan)));
+        assert_eq!(logged_in_user(&state, &headers), Some("alice"));
+
+        // Logins expire
+        state.login_tokens.get_mut(&token).unwrap().1 = now_millis() - 1;
+        assert!(!has_login(&state, &headers, Some(&lan)));
+        state.login_tokens.get_mut(&token).unwrap().1 = now_millis() + LOGIN_TTL_MS;
+
+        // Removing the account ends its logins
+        state.config.accounts.clear();
+        state.config.accounts.push(Account {
+            username: "bob".to_string(),
+            password_hash: "$argon2id$unused".to_string(),
+        });
+        assert!(!has_login(&state, &headers, Some(&lan)));
+    }
+
+    #[test]
+    fn test_login_cookie() {
+        assert!(!login_cookie("tok", false).contains("Secure"));
+        let cookie = login_cookie("tok", true);
+        assert!(cookie.starts_with("zher_login=tok;"));
+        assert!(cookie.contains("; Max-Age=604800"));
+        assert!(cookie.ends_with("; Secure"));
+    }
+}
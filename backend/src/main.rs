@@ -11,6 +11,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
     let args: Vec<String> = env::args().collect();
+
+    // `zher hash-password` reads a password from stdin and prints the hash
+    // to put in the `accounts` list of zher.json
+    if args.get(1).map(String::as_str) == Some("hash-password") {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        println!("{}", zher::login::hash_password(password)?);
+        return Ok(());
+    }
+
     let host = args
         .get(1)
         .cloned()
@@ -0,0 +1,145 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::net::IpAddr;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
+
+use crate::auth::ADMIN_TOKEN_HEADER;
+use crate::state::SharedState;
+
+// Handshake path of the Socket.IO layer
+const SOCKET_IO_PATH: &str = "/socket.io/";
+
+/// Origins the server is reachable at: `host` for every name or address in
+/// `hosts`, plus the configured extras.
+pub fn allowed_origins(scheme: &str, port: u16, hosts: &[String], extra: &[String]) -> Vec<String> {
+    let mut origins: Vec<String> = ["localhost", "127.0.0.1", "[::1]"]
+        .into_iter()
+        .map(str::to_string)
+        .chain(hosts.iter().map(|h| match h.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => h.to_lowercase(),
+        }))
+        .map(|host| match (scheme, port) {
+            ("http", 80) | ("https", 443) => format!("{}://{}", scheme, host),
+            _ => format!("{}://{}:{}", scheme, host, port),
+        })
+        .chain(extra.iter().map(|o| normalize(o)))
+        .collect();
+    origins.sort();
+    origins.dedup();
+    origins
+}
+
+fn normalize(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_lowercase()
+}
+
+/// CORS for the allowed origins only. No credentials, so other sites can't
+/// use a visitor's access cookie even when listed.
+pub fn cors_layer(origins: &[String]) -> CorsLayer {
+    let origins: Vec<HeaderValue> = origins.iter().filter_map(|o| o.parse().ok()).collect();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            HeaderName::from_static(ADMIN_TOKEN_HEADER),
+        ])
+}
+
+// The Origin header, or the origin part of the Referer for older browsers
+fn request_origin(headers: &HeaderMap) -> Option<String> {
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        return Some(origin.to_str().unwrap_or("null").to_string());
+    }
+    let referer = headers.get(header::REFERER)?.to_str().ok()?;
+    let (scheme, rest) = referer.split_once("://")?;
+    let host = rest.split(['/', '?', '#']).next()?;
+    Some(format!("{}://{}", scheme, host))
+}
+
+/// Whether a request may change state. Browsers always say where
+/// cross-site requests come from, so one without Origin or Referer is a
+/// non-browser client and can't be forged by a web page.
+pub fn origin_allowed(allowed: &[String], headers: &HeaderMap) -> bool {
+    match request_origin(headers) {
+        Some(origin) => allowed.contains(&normalize(&origin)),
+        None => true,
+    }
+}
+
+/// CSRF check: state-changing requests and the Socket.IO handshake must
+/// come from one of the server's own pages.
+pub async fn origin_guard(State(state): State<SharedState>, req: Request, next: Next) -> Response {
+    let checked = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || req.uri().path().starts_with(SOCKET_IO_PATH);
+    if !checked {
+        return next.run(req).await;
+    }
+
+    let allowed = origin_allowed(&state.read().unwrap().allowed_origins, req.headers());
+    if !allowed {
+        warn!(
+            "Rejected {} {} from origin {}, add it to allowed_origins if it is yours",
+            req.method(),
+            req.uri().path(),
+            request_origin(req.headers()).unwrap_or_default()
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Origin not allowed" })),
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let hosts = vec!["192.168.1.20".to_string(), "fe80::1".to_string()];
+        let extra = vec!["https://Chat.Example.com/".to_string()];
+        let origins = allowed_origins("http", 4836, &hosts, &extra);
+        assert!(origins.contains(&"http://192.168.1.20:4836".to_string()));
+        assert!(origins.contains(&"http://[fe80::1]:4836".to_string()));
+        assert!(origins.contains(&"http://localhost:4836".to_string()));
+        assert!(origins.contains(&"https://chat.example.com".to_string()));
+
+        let origins = allowed_origins("https", 443, &hosts, &[]);
+        assert!(origins.contains(&"https://192.168.1.20".to_string()));
+    }
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed = allowed_origins("http", 4836, &["192.168.1.20".to_string()], &[]);
+        let own = headers(header::ORIGIN, "http://192.168.1.20:4836");
+        assert!(origin_allowed(&allowed, &own));
+        let other = headers(header::ORIGIN, "http://evil.example");
+        assert!(!origin_allowed(&allowed, &other));
+        let sandboxed = headers(header::ORIGIN, "null");
+        assert!(!origin_allowed(&allowed, &sandboxed));
+
+        let referer = headers(header::REFERER, "http://192.168.1.20:4836/?lang=en");
+        assert!(origin_allowed(&allowed, &referer));
+        let referer = headers(header::REFERER, "http://evil.example/page");
+        assert!(!origin_allowed(&allowed, &referer));
+
+        assert!(origin_allowed(&allowed, &HeaderMap::new()));
+    }
+}
@@ -0,0 +1,160 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::PathBuf};
+use tracing::warn;
+
+use crate::roles::Role;
+
+// Short words for verification phrases, easy to read out and compare
+const PHRASE_WORDS: [&str; 64] = [
+    "apple", "bird", "boat", "bread", "cake", "cat", "chair", "cloud", "coin", "corn", "cup",
+    "desk", "dog", "door", "duck", "egg", "fish", "flag", "frog", "gate", "goat", "grape", "hat",
+    "horse", "key", "kite", "lamp", "leaf", "lemon", "lion", "map", "milk", "moon", "mouse",
+    "nest", "owl", "pear", "pen", "pig", "plum", "rain", "ring", "road", "rose", "salt", "ship",
+    "shoe", "snow", "sock", "star", "stone", "sun", "table", "tea", "tiger", "tree", "truck",
+    "wave", "whale", "wind", "wolf", "wood", "yak", "zebra",
+];
+
+pub fn generate_phrase() -> String {
+    let mut rng = rand::thread_rng();
+    (0..3)
+        .map(|_| PHRASE_WORDS[rng.gen_range(0..PHRASE_WORDS.len())])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A socket from an unknown session waiting for an admin to let it in.
+#[derive(Clone, Debug, Serialize)]
+pub struct JoinRequest {
+    // Socket id of the waiting socket
+    pub id: String,
+    #[serde(skip)]
+    pub session_key: String,
+    pub device: String,
+    pub ip: String,
+    pub phrase: String,
+    #[serde(rename = "requestedAt")]
+    pub requested_at: u64,
+    // Role the device gets once approved
+    pub role: Role,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApprovedDevice {
+    // Public id, so admins never see session keys
+    pub id: String,
+    pub name: String,
+    pub device: String,
+    pub ip: String,
+    #[serde(rename = "approvedAt")]
+    pub approved_at: u64,
+}
+
+/// Sessions an admin has approved, keyed by session key. Written to `path`
+/// on every change when storage is enabled.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: HashMap<String, ApprovedDevice>,
+    path: Option<PathBuf>,
+}
+
+impl DeviceRegistry {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let devices = path
+            .as_ref()
+            .and_then(|path| {
+                let content = fs::read_to_string(path).ok()?;
+                serde_json::from_str(&content)
+                    .map_err(|e| warn!("Ignoring invalid {}: {}", path.display(), e))
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self { devices, path }
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, serde_json::to_string_pretty(&self.devices)?));
+        if let Err(e) = result {
+            warn!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn is_approved(&self, session_key: &str) -> bool {
+        self.devices.contains_key(session_key)
+    }
+
+    pub fn approve(&mut self, session_key: String, device: ApprovedDevice) {
+        self.devices.insert(session_key, device);
+        self.persist();
+    }
+
+    /// Removes the device with the given public id and returns its session key.
+    pub fn revoke(&mut self, id: &str) -> Option<String> {
+        let session_key = self
+            .devices
+            .iter()
+            .find(|(_, d)| d.id == id)
+            .map(|(k, _)| k.clone())?;
+        self.devices.remove(&session_key);
+        self.persist();
+        Some(session_key)
+    }
+
+    pub fn list(&self) -> Vec<ApprovedDevice> {
+        let mut devices: Vec<ApprovedDevice> = self.devices.values().cloned().collect();
+        devices.sort_by_key(|d| d.approved_at);
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str) -> ApprovedDevice {
+        ApprovedDevice {
+            id: id.to_string(),
+            name: "alice".to_string(),
+            device: "mobile".to_string(),
+            ip: "192.168.1.20".to_string(),
+            approved_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_generate_phrase() {
+        let phrase = generate_phrase();
+        let words: Vec<&str> = phrase.split(' ').collect();
+        assert_eq!(words.len(), 3);
+        assert!(words.iter().all(|w| PHRASE_WORDS.contains(w)));
+    }
+
+    #[test]
+    fn test_approve_and_revoke() {
+        let mut registry = DeviceRegistry::new(None);
+        registry.approve("session-1".to_string(), device("d1"));
+        assert!(registry.is_approved("session-1"));
+        assert_eq!(registry.list().len(), 1);
+
+        assert_eq!(registry.revoke("unknown"), None);
+        assert_eq!(registry.revoke("d1").as_deref(), Some("session-1"));
+        assert!(!registry.is_approved("session-1"));
+    }
+
+    #[test]
+    fn test_registry_persists() {
+        let path = std::env::temp_dir().join(format!("zher-devices-{}.json", uuid::Uuid::new_v4()));
+        let mut registry = DeviceRegistry::new(Some(path.clone()));
+        registry.approve("session-1".to_string(), device("d1"));
+
+        let reloaded = DeviceRegistry::new(Some(path.clone()));
+        assert!(reloaded.is_approved("session-1"));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,310 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+use tracing::info;
+
+use crate::roles::cookie_session_key;
+use crate::scheduler::now_millis;
+use crate::state::{AppState, SharedState};
+
+pub const RATE_LIMITED: &str = "操作过于频繁，请稍后再试";
+
+// Idle buckets are dropped once there are this many
+const MAX_BUCKETS: usize = 10_000;
+
+/// Limits applied per session and per IP address. Zero turns a limit off.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // Socket events per second, also the largest burst
+    pub events_per_second: u32,
+    // Bytes of chat messages per minute
+    pub message_bytes_per_minute: u64,
+    // Downloads running at the same time
+    pub concurrent_downloads: u32,
+    // REST calls per minute
+    pub requests_per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            events_per_second: 10,
+            message_bytes_per_minute: 1024 * 1024,
+            concurrent_downloads: 4,
+            requests_per_minute: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Limit {
+    Events,
+    MessageBytes,
+    Requests,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    // Unix time in milliseconds of the last refill
+    last: u64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, capacity: f64, per_ms: f64, now: u64) {
+        let elapsed = now.saturating_sub(self.last) as f64;
+        self.tokens = (self.tokens + elapsed * per_ms).min(capacity);
+        self.last = now;
+    }
+}
+
+// Bucket size and refill per millisecond, None when the limit is off
+fn rate(config: &RateLimitConfig, limit: Limit) -> Option<(f64, f64)> {
+    let (capacity, period) = match limit {
+        Limit::Events => (config.events_per_second as f64, 1000.0),
+        Limit::MessageBytes => (config.message_bytes_per_minute as f64, 60_000.0),
+        Limit::Requests => (config.requests_per_minute as f64, 60_000.0),
+    };
+    (capacity > 0.0).then(|| (capacity, capacity / period))
+}
+
+pub fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip.to_canonical())
+}
+
+pub fn session_key(session_key: &str) -> String {
+    format!("session:{}", session_key)
+}
+
+/// Token buckets for every client key. A request is charged to all of its
+/// keys, so a client can't get more by opening tabs or changing address.
+#[derive(Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<(Limit, String), TokenBucket>,
+    downloads: HashMap<String, u32>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Takes `amount` from the bucket of every key, or from none of them.
+    /// Returns the milliseconds to wait when a bucket is short. An amount
+    /// larger than a whole bucket takes the whole bucket.
+    pub fn take(
+        &mut self,
+        limit: Limit,
+        keys: &[String],
+        amount: u64,
+        now: u64,
+    ) -> Result<(), u64> {
+        let Some((capacity, per_ms)) = rate(&self.config, limit) else {
+            return Ok(());
+        };
+        self.prune(now);
+
+        let amount = (amount as f64).min(capacity);
+        let mut wait = 0;
+        for key in keys {
+            let bucket = self
+                .buckets
+                .entry((limit, key.clone()))
+                .or_insert(TokenBucket {
+                    tokens: capacity,
+                    last: now,
+                });
+            bucket.refill(capacity, per_ms, now);
+            if bucket.tokens < amount {
+                wait = wait.max(((amount - bucket.tokens) / per_ms).ceil() as u64);
+            }
+        }
+        if wait > 0 {
+            return Err(wait);
+        }
+        for key in keys {
+            if let Some(bucket) = self.buckets.get_mut(&(limit, key.clone())) {
+                bucket.tokens -= amount;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a download against every key, unless one is at the limit.
+    pub fn start_download(&mut self, keys: &[String]) -> bool {
+        let max = self.config.concurrent_downloads;
+        if max > 0
+            && keys
+                .iter()
+                .any(|key| self.downloads.get(key).is_some_and(|&n| n >= max))
+        {
+            return false;
+        }
+        for key in keys {
+            *self.downloads.entry(key.clone()).or_default() += 1;
+        }
+        true
+    }
+
+    pub fn end_download(&mut self, keys: &[String]) {
+        for key in keys {
+            if let Some(count) = self.downloads.get_mut(key) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    self.downloads.remove(key);
+                }
+            }
+        }
+    }
+
+    // Full buckets carry no state worth keeping
+    fn prune(&mut self, now: u64) {
+        if self.buckets.len() < MAX_BUCKETS {
+            return;
+        }
+        let config = &self.config;
+        self.buckets.retain(|(limit, _), bucket| {
+            rate(config, *limit).is_some_and(|(capacity, per_ms)| {
+                bucket.refill(capacity, per_ms, now);
+                bucket.tokens < capacity
+            })
+        });
+    }
+}
+
+/// A running download, counted against its client until dropped.
+pub struct DownloadSlot {
+    state: SharedState,
+    keys: Vec<String>,
+}
+
+impl DownloadSlot {
+    pub fn acquire(state: &SharedState, keys: Vec<String>) -> Option<Self> {
+        let started = state.write().unwrap().rate_limiter.start_download(&keys);
+        started.then(|| Self {
+            state: state.clone(),
+            keys,
+        })
+    }
+}
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        if let Ok(mut state_write) = self.state.write() {
+            state_write.rate_limiter.end_download(&self.keys);
+        }
+    }
+}
+
+/// Response for a REST call over its limit.
+pub fn too_many_requests(wait: u64) -> Response {
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(json!({ "error": "Too many requests" })),
+    )
+        .into_response();
+    if let Ok(value) = wait.div_ceil(1000).to_string().parse() {
+        response.headers_mut().insert(header::RETRY_AFTER, value);
+    }
+    response
+}
+
+/// Limits REST calls per client. Static files and the Socket.IO transport
+/// are left alone; socket events are limited where they are handled.
+pub async fn rate_guard(State(state): State<SharedState>, req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/api/") {
+        return next.run(req).await;
+    }
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip());
+
+    let result = {
+        let mut state_write = state.write().unwrap();
+        let keys = request_keys(&state_write, ip, req.headers());
+        state_write
+            .rate_limiter
+            .take(Limit::Requests, &keys, 1, now_millis())
+    };
+    if let Err(wait) = result {
+        info!(
+            "Rate limited {} {} from {}",
+            req.method(),
+            req.uri().path(),
+            ip.map(|ip| ip.to_string()).unwrap_or_default()
+        );
+        return too_many_requests(wait);
+    }
+    next.run(req).await
+}
+
+/// Keys of an HTTP request: its address and the session in its cookie.
+pub fn request_keys(state: &AppState, ip: Option<IpAddr>, headers: &HeaderMap) -> Vec<String> {
+    ip.map(ip_key)
+        .into_iter()
+        .chain(cookie_session_key(state, headers).map(|key| session_key(&key)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            events_per_second: 2,
+            message_bytes_per_minute: 600,
+            concurrent_downloads: 1,
+            requests_per_minute: 0,
+        })
+    }
+
+    #[test]
+    fn test_take() {
+        let mut limiter = limiter();
+        let keys = vec!["ip:a".to_string(), "session:1".to_string()];
+        assert!(limiter.take(Limit::Events, &keys, 1, 0).is_ok());
+        assert!(limiter.take(Limit::Events, &keys, 1, 0).is_ok());
+        assert_eq!(limiter.take(Limit::Events, &keys, 1, 0), Err(500));
+        assert!(limiter.take(Limit::Events, &keys, 1, 500).is_ok());
+
+        // Another session from the same address shares the address bucket
+        let same_ip = vec!["ip:a".to_string(), "session:2".to_string()];
+        assert!(limiter.take(Limit::Events, &same_ip, 1, 500).is_err());
+
+        // 10 bytes per second
+        assert!(limiter.take(Limit::MessageBytes, &keys, 600, 0).is_ok());
+        assert_eq!(limiter.take(Limit::MessageBytes, &keys, 10, 0), Err(1000));
+
+        // Turned off
+        for _ in 0..100 {
+            assert!(limiter.take(Limit::Requests, &keys, 1, 0).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_downloads() {
+        let mut limiter = limiter();
+        let keys = vec!["ip:a".to_string()];
+        assert!(limiter.start_download(&keys));
+        assert!(!limiter.start_download(&keys));
+        assert!(limiter.start_download(&["ip:b".to_string()]));
+        limiter.end_download(&keys);
+        assert!(limiter.start_download(&keys));
+    }
+}
@@ -0,0 +1,163 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr};
+
+use crate::access::cookie_value;
+use crate::auth::{is_admin, ADMIN_TOKEN_HEADER};
+use crate::state::{AppState, Session, SharedState};
+
+// Cookie the page sets to its session token, so plain links such as
+// downloads carry the user's role
+pub const SESSION_COOKIE: &str = "zher_session";
+
+pub const DENIED: &str = "没有权限执行此操作";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Guest,
+    #[default]
+    Member,
+    // Holders of the admin token and the server machine; not assignable
+    Admin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    SendText,
+    ShareFiles,
+    Download,
+    Rename,
+    // Download the whole history from /api/export
+    Export,
+    Admin,
+}
+
+/// What guests and members may do. Admins may do everything, and admin
+/// actions stay with admins whatever the lists say.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PermissionMatrix {
+    pub guest: Vec<Permission>,
+    pub member: Vec<Permission>,
+}
+
+impl Default for PermissionMatrix {
+    fn default() -> Self {
+        Self {
+            guest: vec![Permission::Download],
+            member: vec![
+                Permission::SendText,
+                Permission::ShareFiles,
+                Permission::Download,
+                Permission::Rename,
+                Permission::Export,
+            ],
+        }
+    }
+}
+
+impl PermissionMatrix {
+    pub fn allows(&self, role: Role, permission: Permission) -> bool {
+        match role {
+            Role::Admin => true,
+            _ if permission == Permission::Admin => false,
+            Role::Member => self.member.contains(&permission),
+            Role::Guest => self.guest.contains(&permission),
+        }
+    }
+
+    /// Everything `role` may do, for clients to hide what they can't use.
+    pub fn granted(&self, role: Role) -> Vec<Permission> {
+        [
+            Permission::SendText,
+            Permission::ShareFiles,
+            Permission::Download,
+            Permission::Rename,
+            Permission::Export,
+            Permission::Admin,
+        ]
+        .into_iter()
+        .filter(|p| self.allows(role, *p))
+        .collect()
+    }
+}
+
+/// Role of an HTTP request: admin by token or loopback, otherwise the role
+/// of the session in the session cookie. Requests without a live session
+/// are guests.
+pub fn request_role(state: &AppState, parts: &Parts) -> Role {
+    let addr = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| &c.0);
+    let token = parts
+        .headers
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok());
+    if is_admin(&state.admin_token, addr, token) {
+        return Role::Admin;
+    }
+
+    cookie_session(state, &parts.headers).map_or(Role::Guest, |session| session.user.role)
+}
+
+/// The session named by the session cookie, if the server signed it.
+pub fn cookie_session<'a>(state: &'a AppState, headers: &HeaderMap) -> Option<&'a Session> {
+    cookie_session_key(state, headers).and_then(|key| state.sessions.get(&key))
+}
+
+pub fn cookie_session_key(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    cookie_value(headers, SESSION_COOKIE).and_then(|token| state.signer.verify(token))
+}
+
+// Role of the request, for handlers that check permissions
+pub struct RequestRole(pub Role);
+
+#[async_trait]
+impl FromRequestParts<SharedState> for RequestRole {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &SharedState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(RequestRole(request_role(&state.read().unwrap(), parts)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_matrix() {
+        let matrix = PermissionMatrix::default();
+        assert!(matrix.allows(Role::Guest, Permission::Download));
+        assert!(!matrix.allows(Role::Guest, Permission::SendText));
+        assert!(matrix.allows(Role::Member, Permission::ShareFiles));
+        assert!(matrix.allows(Role::Member, Permission::Export));
+        assert!(!matrix.allows(Role::Guest, Permission::Export));
+        assert!(!matrix.allows(Role::Member, Permission::Admin));
+        assert!(matrix.allows(Role::Admin, Permission::Admin));
+        assert_eq!(matrix.granted(Role::Guest), vec![Permission::Download]);
+    }
+
+    #[test]
+    fn test_matrix_from_config() {
+        let matrix: PermissionMatrix =
+            serde_json::from_str(r#"{ "guest": ["send_text", "download"] }"#).unwrap();
+        assert!(matrix.allows(Role::Guest, Permission::SendText));
+        assert!(!matrix.allows(Role::Guest, Permission::ShareFiles));
+        // Members keep the defaults
+        assert!(matrix.allows(Role::Member, Permission::ShareFiles));
+
+        let matrix: PermissionMatrix = serde_json::from_str(r#"{ "member": ["admin"] }"#).unwrap();
+        assert!(!matrix.allows(Role::Member, Permission::Admin));
+    }
+}
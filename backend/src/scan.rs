@@ -0,0 +1,383 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use socketioxide::SocketIo;
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::auth::ADMIN_ROOM;
+use crate::state::{AppState, SharedState};
+use crate::ws::MEMBERS_ROOM;
+
+// clamd can take a while on large archives
+const CLAMD_TIMEOUT: Duration = Duration::from_secs(120);
+const CLAMD_CHUNK: usize = 64 * 1024;
+
+/// Which scanner checks files kept on the server.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScannerConfig {
+    // clamd's Unix socket path, or host:port for its TCP socket
+    Clamd {
+        address: String,
+    },
+    // Runs `program args... <file>`; a non-zero exit means infected
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Verdict {
+    Pending,
+    Clean,
+    Infected { threat: String },
+    // The scanner couldn't be run; the file stays downloadable
+    Failed { error: String },
+}
+
+/// Checks a file on disk. Called on a blocking thread.
+pub trait Scanner: Send + Sync {
+    fn scan(&self, path: &Path) -> Verdict;
+}
+
+pub fn from_config(config: &ScannerConfig) -> Arc<dyn Scanner> {
+    match config.clone() {
+        ScannerConfig::Clamd { address } => Arc::new(ClamdScanner { address }),
+        ScannerConfig::Command { program, args } => Arc::new(CommandScanner { program, args }),
+    }
+}
+
+/// Streams the file to clamd with INSTREAM, so clamd needs no access to
+/// the file store.
+pub struct ClamdScanner {
+    address: String,
+}
+
+impl Scanner for ClamdScanner {
+    fn scan(&self, path: &Path) -> Verdict {
+        let reply = match self.address.parse::<SocketAddr>() {
+            Ok(addr) => {
+                TcpStream::connect_timeout(&addr, Duration::from_secs(5)).and_then(|stream| {
+                    stream.set_read_timeout(Some(CLAMD_TIMEOUT))?;
+                    instream(stream, path)
+                })
+            }
+            Err(_) => connect_unix(&self.address).and_then(|stream| instream(stream, path)),
+        };
+        match reply {
+            Ok(reply) => parse_clamd_reply(&reply),
+            Err(e) => Verdict::Failed {
+                error: format!("clamd at {}: {}", self.address, e),
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<std::os::unix::net::UnixStream> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(CLAMD_TIMEOUT))?;
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are not available, use host:port",
+    ))
+}
+
+fn instream(mut stream: impl Read + Write, path: &Path) -> io::Result<String> {
+    stream.write_all(b"zINSTREAM\0")?;
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; CLAMD_CHUNK];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&(n as u32).to_be_bytes())?;
+        stream.write_all(&buf[..n])?;
+    }
+    stream.write_all(&0u32.to_be_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+// "stream: OK", "stream: Eicar-Signature FOUND" or "... ERROR"
+fn parse_clamd_reply(reply: &str) -> Verdict {
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        Verdict::Clean
+    } else if let Some(threat) = result.strip_suffix("FOUND") {
+        Verdict::Infected {
+            threat: threat.trim().to_string(),
+        }
+    } else {
+        Verdict::Failed {
+            error: format!("clamd: {}", reply),
+        }
+    }
+}
+
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Scanner for CommandScanner {
+    fn scan(&self, path: &Path) -> Verdict {
+        let output = match Command::new(&self.program)
+            .args(&self.args)
+            .arg(path)
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) => {
+                return Verdict::Failed {
+                    error: format!("{}: {}", self.program, e),
+                }
+            }
+        };
+        if output.status.success() {
+            return Verdict::Clean;
+        }
+        // Scanners tend to name what they found on the first line
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let threat = stdout
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{} exited with {}", self.program, output.status));
+        Verdict::Infected { threat }
+    }
+}
+
+/// Records a verdict on the file's messages. An infected file is dropped
+/// from the store and its links revoked; returns its path for deletion.
+pub fn apply_verdict(state: &mut AppState, file_id: &str, verdict: &Verdict) -> Option<PathBuf> {
+    let scan = serde_json::to_value(verdict).unwrap_or(Value::Null);
+    for msg in state.history.iter_mut().chain(state.pinned.iter_mut()) {
+        if msg.get("fileId").and_then(|v| v.as_str()) == Some(file_id) {
+            if let Some(obj) = msg.as_object_mut() {
+                obj.insert("scan".to_string(), scan.clone());
+            }
+        }
+    }
+    state.scans.insert(file_id.to_string(), verdict.clone());
+
+    if !matches!(verdict, Verdict::Infected { .. }) {
+        return None;
+    }
+    state.links.revoke_file(file_id);
+    state.file_store.remove(file_id)
+}
+
+/// Scans a file that was just stored and tells everyone the verdict. The
+/// sender and admins are warned about infected files.
+pub async fn scan_stored_file(state: SharedState, io: SocketIo, file_id: String) {
+    let job = {
+        let state_read = state.read().unwrap();
+        let path = state_read.file_store.get(&file_id).map(|f| f.path.clone());
+        state_read.scanner.clone().zip(path)
+    };
+    let Some((scanner, path)) = job else {
+        return;
+    };
+    apply_verdict(&mut state.write().unwrap(), &file_id, &Verdict::Pending);
+    let _ = io.to(MEMBERS_ROOM).emit(
+        "file-scanned",
+        json!({ "fileId": file_id, "scan": Verdict::Pending }),
+    );
+
+    let verdict = tokio::task::spawn_blocking(move || scanner.scan(&path))
+        .await
+        .unwrap_or_else(|e| Verdict::Failed {
+            error: e.to_string(),
+        });
+
+    let (infected_path, sender_sockets, file_name) = {
+        let mut state_write = state.write().unwrap();
+        let file_name = state_write.file_store.get(&file_id).map(|f| f.name.clone());
+        let infected_path = apply_verdict(&mut state_write, &file_id, &verdict);
+        let sender = state_write.file_sender(&file_id).map(str::to_string);
+        let sender_sockets: Vec<String> = state_write
+            .sessions
+            .values()
+            .filter(|s| Some(&s.user.id) == sender.as_ref())
+            .flat_map(|s| s.active_sockets.iter().cloned())
+            .collect();
+        if let Verdict::Infected { threat } = &verdict {
+            state_write.audit.record(
+                "file_infected",
+                None,
+                None,
+                json!({
+                    "fileId": file_id,
+                    "fileName": file_name,
+                    "threat": threat,
+                    "sender": sender
+                }),
+            );
+        }
+        (infected_path, sender_sockets, file_name)
+    };
+
+    let _ = io.to(MEMBERS_ROOM).emit(
+        "file-scanned",
+        json!({ "fileId": file_id, "scan": verdict }),
+    );
+    match &verdict {
+        Verdict::Infected { threat } => {
+            warn!("File {} is infected: {}", file_id, threat);
+            let warning = json!({ "fileId": file_id, "fileName": file_name, "threat": threat });
+            let _ = io
+                .to(ADMIN_ROOM)
+                .to(sender_sockets)
+                .emit("file-infected", &warning);
+        }
+        Verdict::Failed { error } => warn!("Failed to scan file {}: {}", file_id, error),
+        _ => info!("File {} scanned clean", file_id),
+    }
+    if let Some(path) = infected_path {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::serve_download;
+    use crate::links::LinkQuery;
+    use crate::roles::Role;
+    use crate::store::StoredFile;
+    use axum::http::{HeaderMap, StatusCode};
+    use socketioxide::extract::SocketRef;
+    use std::sync::{mpsc, Mutex, RwLock};
+
+    struct FakeScanner(Verdict);
+
+    impl Scanner for FakeScanner {
+        fn scan(&self, _path: &Path) -> Verdict {
+            self.0.clone()
+        }
+    }
+
+    // Holds its verdict back until the test lets it through
+    struct GatedScanner {
+        verdict: Verdict,
+        gate: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Scanner for GatedScanner {
+        fn scan(&self, _path: &Path) -> Verdict {
+            let _ = self.gate.lock().unwrap().recv();
+            self.verdict.clone()
+        }
+    }
+
+    fn state_with_file(file_id: &str) -> AppState {
+        let mut state = AppState::default();
+        state
+            .history
+            .push_back(json!({ "id": 1, "type": "file-meta", "fileId": file_id }));
+        let path = state.file_store.begin(file_id, "user-1", 1);
+        state.file_store.finish(
+            file_id,
+            StoredFile {
+                name: "a.exe".to_string(),
+                size: 1,
+                path,
+                owner: "user-1".to_string(),
+                sha256: None,
+            },
+        );
+        state
+    }
+
+    #[test]
+    fn test_apply_verdict() {
+        let mut state = state_with_file("f1");
+        let clean = FakeScanner(Verdict::Clean).scan(Path::new("a.exe"));
+        assert_eq!(apply_verdict(&mut state, "f1", &clean), None);
+        assert_eq!(state.history[0]["scan"], json!({ "status": "clean" }));
+        assert!(state.file_store.contains("f1"));
+
+        let mut state = state_with_file("f1");
+        let infected = FakeScanner(Verdict::Infected {
+            threat: "Eicar-Signature".to_string(),
+        })
+        .scan(Path::new("a.exe"));
+        assert!(apply_verdict(&mut state, "f1", &infected).is_some());
+        assert_eq!(state.history[0]["scan"]["threat"], "Eicar-Signature");
+        assert!(!state.file_store.contains("f1"));
+        assert_eq!(state.scans.get("f1"), Some(&infected));
+    }
+
+    #[tokio::test]
+    async fn test_downloads_wait_for_verdict() {
+        let (release, gate) = mpsc::channel();
+        let state: SharedState = Arc::new(RwLock::new(AppState {
+            scanner: Some(Arc::new(GatedScanner {
+                verdict: Verdict::Infected {
+                    threat: "Eicar-Signature".to_string(),
+                },
+                gate: Mutex::new(gate),
+            })),
+            ..state_with_file("f1")
+        }));
+        let (_, io) = SocketIo::builder().build_layer();
+        // Emitting needs the default namespace, even with nobody connected
+        io.ns("/", |_: SocketRef| {});
+        let scan = tokio::spawn(scan_stored_file(
+            state.clone(),
+            io.clone(),
+            "f1".to_string(),
+        ));
+        while state.read().unwrap().scans.get("f1") != Some(&Verdict::Pending) {
+            tokio::task::yield_now().await;
+        }
+
+        let (link, headers) = (LinkQuery::default(), HeaderMap::new());
+        let response =
+            serve_download("f1".to_string(), &link, &headers, Role::Admin, &state, &io).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        release.send(()).unwrap();
+        scan.await.unwrap();
+        assert!(!state.read().unwrap().file_store.contains("f1"));
+        let response =
+            serve_download("f1".to_string(), &link, &headers, Role::Admin, &state, &io).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0"), Verdict::Clean);
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Signature FOUND\0"),
+            Verdict::Infected {
+                threat: "Eicar-Signature".to_string()
+            }
+        );
+        assert!(matches!(
+            parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0"),
+            Verdict::Failed { .. }
+        ));
+    }
+}
@@ -0,0 +1,525 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socketioxide::SocketIo;
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::roles::Permission;
+use crate::state::{SharedState, User};
+use crate::ws::{post_file_meta, post_text_message, TextMessageOptions, MEMBERS_ROOM};
+
+// Number of one-second slots in the timer wheel
+const WHEEL_SLOTS: usize = 512;
+
+// Longest a self-destructing message may live, in seconds
+pub const MAX_MESSAGE_TTL: u64 = 7 * 24 * 60 * 60;
+
+// Furthest ahead a message may be scheduled, in seconds
+pub const MAX_SCHEDULE_DELAY: u64 = 30 * 24 * 60 * 60;
+
+/// Hashed timer wheel with one-second ticks. Items due more than
+/// `WHEEL_SLOTS` seconds ahead stay in their slot for extra rounds.
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<(u64, T)>>,
+    tick: u64,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: u64) -> Self {
+        Self {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            tick,
+        }
+    }
+
+    pub fn insert(&mut self, due: u64, item: T) {
+        // Anything already due fires on the next tick
+        let due = due.max(self.tick + 1);
+        self.slots[(due % WHEEL_SLOTS as u64) as usize].push((due, item));
+    }
+
+    /// Moves the wheel forward to `tick` and returns every item that is due.
+    pub fn advance(&mut self, tick: u64) -> Vec<T> {
+        let mut fired = Vec::new();
+        if tick <= self.tick {
+            return fired;
+        }
+
+        let steps = (tick - self.tick).min(WHEEL_SLOTS as u64);
+        for step in 1..=steps {
+            let slot = ((self.tick + step) % WHEEL_SLOTS as u64) as usize;
+            let (due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.slots[slot])
+                .into_iter()
+                .partition(|(due, _)| *due <= tick);
+            self.slots[slot] = pending;
+            fired.extend(due.into_iter().map(|(_, item)| item));
+        }
+
+        self.tick = tick;
+        fired
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ScheduledContent {
+    Text(TextMessageOptions),
+    File { meta: Value },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: String,
+    // Unix time in milliseconds
+    #[serde(rename = "sendAt")]
+    pub send_at: u64,
+    pub sender: User,
+    // Socket that holds the file for scheduled file announcements
+    #[serde(skip)]
+    pub socket_id: String,
+    pub content: ScheduledContent,
+}
+
+enum Timer {
+    Send(String),
+    Expire(u64),
+    Reauth(String),
+}
+
+pub enum Job {
+    Send(Box<ScheduledMessage>),
+    Expire(u64),
+    Reauth(String),
+}
+
+/// Scheduled messages, message expiry and reauth timers. Scheduled text
+/// messages are written to `path` on every change when storage is enabled.
+/// File announcements are not, the file only lives in the tab that scheduled
+/// it and that tab is gone after a restart.
+pub struct Scheduler {
+    wheel: TimerWheel<Timer>,
+    messages: HashMap<String, ScheduledMessage>,
+    path: Option<PathBuf>,
+}
+
+impl Scheduler {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut scheduler = Self {
+            wheel: TimerWheel::new(now_millis() / 1000),
+            messages: HashMap::new(),
+            path,
+        };
+        for item in scheduler.load() {
+            scheduler.insert(item);
+        }
+        scheduler
+    }
+
+    fn load(&self) -> Vec<ScheduledMessage> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+        let Ok(content) = fs::read_to_string(path) else {
+            return Vec::new();
+        };
+        let items: Vec<ScheduledMessage> = serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("Ignoring invalid {}: {}", path.display(), e);
+            Vec::new()
+        });
+        items
+            .into_iter()
+            .filter(|item| matches!(item.content, ScheduledContent::Text(_)))
+            .collect()
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let items: Vec<&ScheduledMessage> = self
+            .messages
+            .values()
+            .filter(|item| matches!(item.content, ScheduledContent::Text(_)))
+            .collect();
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, serde_json::to_string_pretty(&items)?));
+        if let Err(e) = result {
+            warn!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    fn insert(&mut self, item: ScheduledMessage) {
+        self.wheel
+            .insert(item.send_at.div_ceil(1000), Timer::Send(item.id.clone()));
+        self.messages.insert(item.id.clone(), item);
+    }
+
+    pub fn schedule(&mut self, item: ScheduledMessage) {
+        self.insert(item);
+        self.persist();
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ScheduledMessage> {
+        self.messages.get(id)
+    }
+
+    pub fn cancel(&mut self, id: &str) -> Option<ScheduledMessage> {
+        let item = self.messages.remove(id)?;
+        self.persist();
+        Some(item)
+    }
+
+    /// Drops every pending message of `user_id`, returning how many there were.
+    pub fn cancel_by_sender(&mut self, user_id: &str) -> usize {
+        let before = self.messages.len();
+        self.messages.retain(|_, m| m.sender.id != user_id);
+        let cancelled = before - self.messages.len();
+        if cancelled > 0 {
+            self.persist();
+        }
+        cancelled
+    }
+
+    pub fn by_sender(&self, user_id: &str) -> Vec<ScheduledMessage> {
+        let mut items: Vec<ScheduledMessage> = self
+            .messages
+            .values()
+            .filter(|m| m.sender.id == user_id)
+            .cloned()
+            .collect();
+        items.sort_by_key(|m| m.send_at);
+        items
+    }
+
+    pub fn expire_at(&mut self, message_id: u64, at_millis: u64) {
+        self.wheel
+            .insert(at_millis.div_ceil(1000), Timer::Expire(message_id));
+    }
+
+    pub fn reauth_deadline(&mut self, socket_id: String, at_millis: u64) {
+        self.wheel
+            .insert(at_millis.div_ceil(1000), Timer::Reauth(socket_id));
+    }
+
+    pub fn due(&mut self, now_millis: u64) -> Vec<Job> {
+        let mut sent = false;
+        let jobs: Vec<Job> = self
+            .wheel
+            .advance(now_millis / 1000)
+            .into_iter()
+            .filter_map(|timer| match timer {
+                // Cancelled messages are gone from the map, skip them
+                Timer::Send(id) => self.messages.remove(&id).map(|item| {
+                    sent = true;
+                    Job::Send(Box::new(item))
+                }),
+                Timer::Expire(message_id) => Some(Job::Expire(message_id)),
+                Timer::Reauth(socket_id) => Some(Job::Reauth(socket_id)),
+            })
+            .collect();
+
+        if sent {
+            self.persist();
+        }
+        jobs
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// When a message sent at `sent_at` (Unix millis) with `ttl` seconds to live
+/// expires. Callers clamp `ttl` to `MAX_MESSAGE_TTL` first.
+pub fn expiry_time(sent_at: u64, ttl: u64) -> u64 {
+    sent_at.saturating_add(ttl.saturating_mul(1000))
+}
+
+/// When to send a message scheduled at `now`: `send_at`, or `delay` seconds
+/// from now when `send_at` is missing. At most `MAX_SCHEDULE_DELAY` ahead.
+pub fn send_time(now: u64, send_at: Option<u64>, delay: Option<u64>) -> Result<u64, &'static str> {
+    let send_at = match (send_at, delay) {
+        (Some(send_at), _) => Some(send_at),
+        (None, Some(delay)) => delay.checked_mul(1000).and_then(|ms| now.checked_add(ms)),
+        (None, None) => return Err("缺少发送时间"),
+    };
+    send_at
+        .filter(|&at| at <= now.saturating_add(MAX_SCHEDULE_DELAY * 1000))
+        .ok_or("发送时间不能超过 30 天")
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Fires scheduled messages, expiry and reauth timers once per second.
+pub async fn run(state: SharedState, io: SocketIo) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let jobs = state.write().unwrap().scheduler.due(now_millis());
+
+        for job in jobs {
+            match job {
+                Job::Expire(message_id) => {
+                    let expired = state.write().unwrap().expire_message(message_id).is_some();
+                    if expired {
+                        let _ = io.to(MEMBERS_ROOM).emit("message-deleted", message_id);
+                    }
+                }
+                Job::Send(item) => send_scheduled(&state, &io, *item),
+                Job::Reauth(socket_id) => {
+                    // Still hasn't entered the new code
+                    let pending = state.write().unwrap().reauth_pending.remove(&socket_id);
+                    if pending {
+                        let _ = io.to(socket_id).disconnect();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn send_scheduled(state: &SharedState, io: &SocketIo, item: ScheduledMessage) {
+    let mut state_write = state.write().unwrap();
+
+    // Use the sender's current profile in case they renamed or had their role
+    // changed since scheduling
+    let session = state_write
+        .sessions
+        .values()
+        .find(|s| s.user.id == item.sender.id);
+    let sockets: Vec<String> = session
+        .map(|s| s.active_sockets.iter().cloned().collect())
+        .unwrap_or_default();
+    let sender = match session {
+        Some(session) => session.user.clone(),
+        // Items restored after a restart have no socket, and wait for the
+        // sender to come back under their scheduled profile
+        None if item.socket_id.is_empty() => item.sender.clone(),
+        // Kicked or banned since scheduling
+        None => {
+            warn!(
+                "Dropping scheduled message from {}: session is gone",
+                item.sender.name
+            );
+            return;
+        }
+    };
+
+    let needed = match item.content {
+        ScheduledContent::Text(_) => Permission::SendText,
+        ScheduledContent::File { .. } => Permission::ShareFiles,
+    };
+    if !state_write.config.permissions.allows(sender.role, needed) {
+        warn!(
+            "Dropping scheduled message from {}: no longer allowed to post",
+            sender.name
+        );
+        tell_sender(io, &sockets, "scheduled-cancelled", &item.id);
+        tell_sender(io, &sockets, "schedule-fail", "你已无权发送这条定时消息");
+        return;
+    }
+
+    let message = match item.content {
+        ScheduledContent::Text(options) => {
+            let posted = post_text_message(&mut state_write, &sender, options);
+            if let Some((sockets, data)) = posted.mentioned {
+                let _ = io.to(sockets).emit("mentioned", &data);
+            }
+            posted.message
+        }
+        ScheduledContent::File { meta } => {
+            // The file only lives in the browser tab that scheduled it
+            if !state_write.socket_to_session.contains_key(&item.socket_id) {
+                warn!(
+                    "Dropping scheduled file from {}: sender tab is gone",
+                    sender.name
+                );
+                tell_sender(io, &sockets, "scheduled-cancelled", &item.id);
+                tell_sender(
+                    io,
+                    &sockets,
+                    "schedule-fail",
+                    "定时文件未发送，发送文件的页面已关闭",
+                );
+                return;
+            }
+            post_file_meta(&mut state_write, &sender, &item.socket_id, meta)
+        }
+    };
+    let _ = io.to(MEMBERS_ROOM).emit("message", &message);
+    // Senders skip their own messages from the room, they only had the draft
+    let sent = serde_json::json!({ "id": item.id, "message": message });
+    tell_sender(io, &sockets, "scheduled-sent", &sent);
+}
+
+fn tell_sender(io: &SocketIo, sockets: &[String], event: &'static str, data: impl Serialize) {
+    // No rooms at all would broadcast to everyone
+    if !sockets.is_empty() {
+        let _ = io.to(sockets.to_vec()).emit(event, &data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wheel_fires_when_due() {
+        let mut wheel = TimerWheel::new(100);
+        wheel.insert(102, "a");
+        wheel.insert(101, "b");
+        assert!(wheel.advance(100).is_empty());
+        assert_eq!(wheel.advance(101), vec!["b"]);
+        assert_eq!(wheel.advance(105), vec!["a"]);
+        assert!(wheel.advance(106).is_empty());
+    }
+
+    #[test]
+    fn test_wheel_keeps_items_for_later_rounds() {
+        let mut wheel = TimerWheel::new(0);
+        wheel.insert(1 + WHEEL_SLOTS as u64, "late");
+        assert!(wheel.advance(1).is_empty());
+        assert!(wheel.advance(WHEEL_SLOTS as u64).is_empty());
+        assert_eq!(wheel.advance(1 + WHEEL_SLOTS as u64), vec!["late"]);
+    }
+
+    #[test]
+    fn test_wheel_past_items_fire_next_tick() {
+        let mut wheel = TimerWheel::new(50);
+        wheel.insert(10, "past");
+        assert_eq!(wheel.advance(51), vec!["past"]);
+    }
+
+    #[test]
+    fn test_wheel_large_jump() {
+        let mut wheel = TimerWheel::new(0);
+        wheel.insert(5, "a");
+        wheel.insert(700, "b");
+        let mut fired = wheel.advance(10_000);
+        fired.sort();
+        assert_eq!(fired, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_expiry_time_saturates() {
+        assert_eq!(expiry_time(1_000, 60), 61_000);
+        assert_eq!(expiry_time(u64::MAX - 1, 60), u64::MAX);
+        assert_eq!(expiry_time(1_000, u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_send_time() {
+        assert_eq!(send_time(1_000, Some(5_000), Some(60)), Ok(5_000));
+        assert_eq!(send_time(1_000, None, Some(60)), Ok(61_000));
+        assert!(send_time(1_000, None, None).is_err());
+        assert!(send_time(1_000, None, Some(MAX_SCHEDULE_DELAY + 1)).is_err());
+        assert!(send_time(1_000, None, Some(u64::MAX)).is_err());
+        assert!(send_time(1_000, Some(u64::MAX), None).is_err());
+    }
+
+    #[test]
+    fn test_file_items_are_not_persisted() {
+        let path =
+            std::env::temp_dir().join(format!("zher-scheduled-{}.json", uuid::Uuid::new_v4()));
+        let sender = User {
+            id: "u1".to_string(),
+            name: "alice".to_string(),
+            color: "#FF6B6B".to_string(),
+            device: "desktop".to_string(),
+            role: Default::default(),
+        };
+        let mut scheduler = Scheduler::new(Some(path.clone()));
+        scheduler.schedule(ScheduledMessage {
+            id: "text".to_string(),
+            send_at: now_millis() + 60_000,
+            sender: sender.clone(),
+            socket_id: "s1".to_string(),
+            content: ScheduledContent::Text(TextMessageOptions {
+                text: "hi".to_string(),
+                ..Default::default()
+            }),
+        });
+        scheduler.schedule(ScheduledMessage {
+            id: "file".to_string(),
+            send_at: now_millis() + 60_000,
+            sender,
+            socket_id: "s1".to_string(),
+            content: ScheduledContent::File {
+                meta: serde_json::json!({ "fileId": "f1" }),
+            },
+        });
+
+        let restored = Scheduler::new(Some(path.clone()));
+        let ids: Vec<String> = restored.by_sender("u1").into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec!["text".to_string()]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cancelled_message_does_not_fire() {
+        let mut scheduler = Scheduler::new(None);
+        let now = now_millis();
+        scheduler.schedule(ScheduledMessage {
+            id: "m1".to_string(),
+            send_at: now,
+            sender: User {
+                id: "u1".to_string(),
+                name: "alice".to_string(),
+                color: "#FF6B6B".to_string(),
+                device: "desktop".to_string(),
+                role: Default::default(),
+            },
+            socket_id: String::new(),
+            content: ScheduledContent::Text(TextMessageOptions {
+                text: "hi".to_string(),
+                ..Default::default()
+            }),
+        });
+        assert_eq!(scheduler.by_sender("u1").len(), 1);
+        assert!(scheduler.cancel("m1").is_some());
+        assert!(scheduler.due(now + 5000).is_empty());
+    }
+
+    #[test]
+    fn test_cancel_by_sender() {
+        let mut scheduler = Scheduler::new(None);
+        let now = now_millis();
+        for (id, sender) in [("m1", "u1"), ("m2", "u1"), ("m3", "u2")] {
+            scheduler.schedule(ScheduledMessage {
+                id: id.to_string(),
+                send_at: now,
+                sender: User {
+                    id: sender.to_string(),
+                    name: sender.to_string(),
+                    color: "#FF6B6B".to_string(),
+                    device: "desktop".to_string(),
+                    role: Default::default(),
+                },
+                socket_id: String::new(),
+                content: ScheduledContent::Text(TextMessageOptions {
+                    text: "hi".to_string(),
+                    ..Default::default()
+                }),
+            });
+        }
+        assert_eq!(scheduler.cancel_by_sender("u1"), 2);
+        assert_eq!(scheduler.cancel_by_sender("u1"), 0);
+        let due = scheduler.due(now + 5000);
+        assert_eq!(due.len(), 1);
+        assert!(matches!(&due[0], Job::Send(item) if item.id == "m3"));
+    }
+}
@@ -0,0 +1,233 @@
+use hmac::{Hmac, KeyInit, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::utils::{constant_time_eq, to_hex};
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+// The secret signs every session, so only the owner may read it
+fn write_secret(path: &Path, secret: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode above only applies to a new file
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(secret)
+}
+
+// A secret others could have read or planted is not used
+fn read_secret(path: &Path) -> Option<Vec<u8>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = fs::symlink_metadata(path).ok()?;
+        if !metadata.is_file() || metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "Ignoring {}, it is not private to this user",
+                path.display()
+            );
+            return None;
+        }
+    }
+    fs::read(path).ok()
+}
+
+/// Issues and checks session tokens of the form `<session>.<issued>.<mac>`.
+/// The secret and the revoked sessions are kept in `dir`, so tokens stay
+/// valid across restarts. Without a `dir` both only live in memory.
+pub struct SessionSigner {
+    secret: Vec<u8>,
+    revoked: HashSet<String>,
+    revoked_path: Option<PathBuf>,
+}
+
+impl SessionSigner {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        let secret_path = dir.as_ref().map(|d| d.join("secret.key"));
+        let revoked_path = dir.as_ref().map(|d| d.join("revoked.json"));
+
+        let secret = secret_path
+            .as_ref()
+            .and_then(|path| read_secret(path))
+            .filter(|secret| secret.len() >= 32)
+            .unwrap_or_else(|| {
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                if let Some(ref path) = secret_path {
+                    if let Err(e) = write_secret(path, &secret) {
+                        warn!(
+                            "Failed to save {}, sessions end on restart: {}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+                secret
+            });
+
+        let revoked = revoked_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        Self {
+            secret,
+            revoked,
+            revoked_path,
+        }
+    }
+
+    fn mac(&self, session_key: &str, issued_at: u64) -> String {
+        let message = format!("{}.{}", session_key, issued_at);
+        to_hex(&hmac_sha256(&self.secret, message.as_bytes()))
+    }
+
+    pub fn issue(&self, session_key: &str, issued_at: u64) -> String {
+        format!(
+            "{}.{}.{}",
+            session_key,
+            issued_at,
+            self.mac(session_key, issued_at)
+        )
+    }
+
+    /// Returns the session key of a valid, unrevoked token.
+    pub fn verify(&self, token: &str) -> Option<String> {
+        let mut parts = token.rsplitn(3, '.');
+        let mac = parts.next()?;
+        let issued_at: u64 = parts.next()?.parse().ok()?;
+        let session_key = parts.next()?;
+
+        let expected = self.mac(session_key, issued_at);
+        if !constant_time_eq(mac.as_bytes(), expected.as_bytes()) {
+            return None;
+        }
+        if self.revoked.contains(session_key) {
+            return None;
+        }
+        Some(session_key.to_string())
+    }
+
+    /// A value derived from the secret, stable for as long as the tokens are.
+    pub fn derive(&self, label: &str) -> String {
+        to_hex(&hmac_sha256(&self.secret, label.as_bytes()))
+    }
+
+    /// Invalidates every token issued for `session_key`.
+    pub fn revoke(&mut self, session_key: &str) {
+        self.revoked.insert(session_key.to_string());
+        let Some(path) = &self.revoked_path else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, serde_json::to_string(&self.revoked)?));
+        if let Err(e) = result {
+            warn!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Default for SessionSigner {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        // Test case 2
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Test case 6, key longer than the block size
+        assert_eq!(
+            to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let signer = SessionSigner::new(None);
+        let token = signer.issue("session-1", 1000);
+        assert_eq!(signer.verify(&token).as_deref(), Some("session-1"));
+    }
+
+    #[test]
+    fn test_rejects_forged_tokens() {
+        let signer = SessionSigner::new(None);
+        let other = SessionSigner::new(None);
+
+        assert_eq!(signer.verify("session-1"), None);
+        assert_eq!(signer.verify(&other.issue("session-1", 1000)), None);
+
+        // Swapping the session key invalidates the MAC
+        let token = signer.issue("session-1", 1000);
+        let forged = token.replacen("session-1", "session-2", 1);
+        assert_eq!(signer.verify(&forged), None);
+    }
+
+    #[test]
+    fn test_revoke() {
+        let mut signer = SessionSigner::new(None);
+        let token = signer.issue("session-1", 1000);
+        signer.revoke("session-1");
+        assert_eq!(signer.verify(&token), None);
+        assert!(signer.verify(&signer.issue("session-2", 1000)).is_some());
+    }
+
+    #[test]
+    fn test_secret_persists() {
+        let dir = std::env::temp_dir().join(format!("zher-signer-{}", uuid::Uuid::new_v4()));
+        let token = SessionSigner::new(Some(dir.clone())).issue("session-1", 1000);
+        let reloaded = SessionSigner::new(Some(dir.clone()));
+        assert_eq!(reloaded.verify(&token).as_deref(), Some("session-1"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join("secret.key"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            // A secret others can read is replaced and locked down
+            let path = dir.join("secret.key");
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            let replaced = SessionSigner::new(Some(dir.clone()));
+            assert!(replaced.verify(&token).is_none());
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
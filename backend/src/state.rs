@@ -1,13 +1,32 @@
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     sync::{Arc, Mutex, RwLock},
     time::SystemTime,
 };
 use tokio::sync::mpsc;
 
+use crate::audit::AuditLog;
+use crate::config::Config;
 use crate::discovery::DiscoveryService;
+use crate::integrity::StreamHasher;
+use crate::ipfilter::IpFilter;
+use crate::links::LinkRegistry;
+use crate::lockout::AttemptTracker;
+use crate::login::LOGIN_TTL_MS;
+use crate::pairing::{DeviceRegistry, JoinRequest};
+use crate::ratelimit::RateLimiter;
+use crate::roles::{Permission, Role};
+use crate::scan::{Scanner, Verdict};
+use crate::scheduler::{now_millis, Scheduler};
+use crate::signing::SessionSigner;
+use crate::store::{FileStore, StoredFile};
+use crate::utils::generate_token;
+
+// Number of chat messages kept in memory for export
+pub const MAX_HISTORY: usize = 1000;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct User {
@@ -15,6 +34,29 @@ pub struct User {
     pub name: String,
     pub color: String,
     pub device: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+pub struct Transfer {
+    pub file_id: String,
+    pub tx: mpsc::Sender<Result<Bytes, std::io::Error>>,
+    // Bytes the receiving side asked for
+    pub length: u64,
+    // Set for downloads of the whole file, which count as a view
+    pub whole_file: bool,
+    // Checks a whole file against the hash its sender announced
+    pub hasher: Option<StreamHasher>,
+    // Sockets of the receiving side, told about the integrity check
+    pub receivers: Vec<String>,
+}
+
+// Self-destruct settings of a message with a TTL or view-once flag
+#[derive(Clone, Debug)]
+pub struct Ephemeral {
+    pub sender_id: String,
+    pub file_id: Option<String>,
+    pub view_once: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -29,20 +71,72 @@ pub struct AppState {
     pub sessions: HashMap<String, Session>,
     // Map SocketID -> SessionID
     pub socket_to_session: HashMap<String, String>,
+    // Sockets that connected with admin rights
+    pub admin_sockets: HashSet<String>,
     // Map SocketID -> Room Code (for validation)
     pub socket_room_codes: HashMap<String, Option<String>>,
+    // Sockets that must enter the new room code before doing anything
+    pub reauth_pending: HashSet<String>,
+    // SocketID -> JoinRequest, for devices waiting for an admin
+    pub pending_joins: HashMap<String, JoinRequest>,
+    // Sessions admins have let in when pairing is on
+    pub devices: DeviceRegistry,
 
-    // file_id -> (sender_socket_id, filename, filesize)
+    // file_id -> (sender_socket_id, sanitized filename, filesize)
     pub file_owners: HashMap<String, (String, String, u64)>,
-    // transfer_id -> Sender Channel
-    pub transfers: HashMap<String, mpsc::Sender<Result<Bytes, std::io::Error>>>,
+    // transfer_id -> Transfer
+    pub transfers: HashMap<String, Transfer>,
+
+    // Recent messages as emitted to clients, oldest first
+    pub history: VecDeque<Value>,
+    // Pinned messages, in pin order
+    pub pinned: Vec<Value>,
+    // Server-side copies of pinned files
+    pub file_store: FileStore,
+    // message_id -> Ephemeral, for messages that self-destruct
+    pub ephemeral: HashMap<u64, Ephemeral>,
+    // Files of expired messages, downloads answer 410 Gone
+    pub expired_files: HashSet<String>,
+    // Checks stored files for malware when configured
+    pub scanner: Option<Arc<dyn Scanner>>,
+    // file_id -> latest scan verdict; infected files can't be downloaded
+    pub scans: HashMap<String, Verdict>,
+    // Scheduled messages and expiry timers
+    pub scheduler: Scheduler,
 
+    pub config: Config,
+    pub admin_token: String,
+    // Signs the session tokens handed out in welcome
+    pub signer: SessionSigner,
     pub server_url: String,
+    // Origins that may make state-changing requests and open sockets
+    pub allowed_origins: Vec<String>,
+    // Local CA certificate (PEM) and its SHA-256 fingerprint when serving HTTPS
+    pub ca_certificate: Option<String>,
+    pub ca_fingerprint: Option<String>,
     pub discovery: Arc<Mutex<DiscoveryService>>,
     
     // Room code settings
     pub room_code_enabled: bool,
     pub room_code: Option<String>,
+    // Access cookie token -> room code it was issued for
+    pub access_tokens: HashMap<String, String>,
+    // Failed room code attempts, for lockouts
+    pub code_attempts: AttemptTracker,
+    // Login cookie token -> username and expiry in Unix milliseconds
+    pub login_tokens: HashMap<String, (String, u64)>,
+    // Failed logins, locked out like room codes
+    pub login_attempts: AttemptTracker,
+    // Configured address lists and admin bans
+    pub ip_filter: IpFilter,
+    // Session key -> end of its ban in Unix milliseconds, None until restart
+    pub banned_sessions: HashMap<String, Option<u64>>,
+    // Connections, admin actions and downloads
+    pub audit: AuditLog,
+    // Signed download links for use outside the chat
+    pub links: LinkRegistry,
+    // Token buckets per session and address
+    pub rate_limiter: RateLimiter,
 }
 
 impl Default for AppState {
@@ -50,15 +144,240 @@ impl Default for AppState {
         Self {
             sessions: HashMap::new(),
             socket_to_session: HashMap::new(),
+            admin_sockets: HashSet::new(),
             socket_room_codes: HashMap::new(),
+            reauth_pending: HashSet::new(),
+            pending_joins: HashMap::new(),
+            devices: DeviceRegistry::default(),
             file_owners: HashMap::new(),
             transfers: HashMap::new(),
+            history: VecDeque::new(),
+            pinned: Vec::new(),
+            file_store: FileStore::default(),
+            ephemeral: HashMap::new(),
+            expired_files: HashSet::new(),
+            scanner: None,
+            scans: HashMap::new(),
+            scheduler: Scheduler::default(),
+            config: Config::default(),
+            admin_token: generate_token(),
+            signer: SessionSigner::default(),
             server_url: String::new(),
+            allowed_origins: Vec::new(),
+            ca_certificate: None,
+            ca_fingerprint: None,
             discovery: Arc::new(Mutex::new(DiscoveryService::new(true))),
             room_code_enabled: false,
             room_code: None,
+            access_tokens: HashMap::new(),
+            code_attempts: AttemptTracker::default(),
+            login_tokens: HashMap::new(),
+            login_attempts: AttemptTracker::default(),
+            ip_filter: IpFilter::default(),
+            banned_sessions: HashMap::new(),
+            audit: AuditLog::default(),
+            links: LinkRegistry::default(),
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+}
+
+impl AppState {
+    /// The end of the session's ban if it is banned, dropping expired bans.
+    pub fn session_ban(&mut self, session_key: &str, now: u64) -> Option<Option<u64>> {
+        self.banned_sessions
+            .retain(|_, until| until.is_none_or(|until| until > now));
+        self.banned_sessions.get(session_key).copied()
+    }
+
+    // Issues an access cookie token bound to `code`, or to the room code
+    pub fn issue_access_token(&mut self, code: Option<&str>) -> String {
+        let token = generate_token();
+        // The accepted code decides the holder's role, admins get the room code
+        if let Some(code) = code.or(self.room_code.as_deref()) {
+            self.access_tokens.insert(token.clone(), code.to_string());
+        }
+        token
+    }
+
+    pub fn issue_login_token(&mut self, username: &str) -> String {
+        let token = generate_token();
+        let now = now_millis();
+        self.login_tokens
+            .retain(|_, (_, expires_at)| *expires_at > now);
+        self.login_tokens.insert(
+            token.clone(),
+            (username.to_string(), now.saturating_add(LOGIN_TTL_MS)),
+        );
+        token
+    }
+
+    // Sockets waiting for reauth have no user until they enter the code
+    pub fn user_for_socket(&self, socket_id: &str) -> Option<&User> {
+        if self.reauth_pending.contains(socket_id) {
+            return None;
+        }
+        let session_key = self.socket_to_session.get(socket_id)?;
+        self.sessions.get(session_key).map(|s| &s.user)
+    }
+
+    /// Whether the user behind `socket_id` may do `permission`. Sockets
+    /// still waiting to re-enter the room code may do nothing.
+    pub fn socket_can(&self, socket_id: &str, permission: Permission) -> bool {
+        if self.reauth_pending.contains(socket_id) {
+            return false;
+        }
+        if self.admin_sockets.contains(socket_id) {
+            return true;
+        }
+        self.user_for_socket(socket_id)
+            .is_some_and(|user| self.config.permissions.allows(user.role, permission))
+    }
+
+    /// Whether `code` still lets its holder in, as the room code or the
+    /// guest code.
+    pub fn code_is_current(&self, code: &str) -> bool {
+        self.room_code.as_deref() == Some(code) || self.config.guest_code.as_deref() == Some(code)
+    }
+
+    /// Non-admin sockets that joined with a code that is no longer current,
+    /// or before the room code was enabled.
+    pub fn stale_sockets(&self) -> Vec<String> {
+        if !self.room_code_enabled || self.room_code.is_none() {
+            return Vec::new();
+        }
+        self.socket_to_session
+            .keys()
+            .filter(|id| !self.admin_sockets.contains(*id))
+            .filter(|id| !self.reauth_pending.contains(*id))
+            .filter(|id| {
+                !self
+                    .socket_room_codes
+                    .get(*id)
+                    .and_then(|c| c.as_deref())
+                    .is_some_and(|c| self.code_is_current(c))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn record_message(&mut self, msg: Value) {
+        self.history.push_back(msg);
+        while self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
         }
     }
+
+    /// Removes an ephemeral message from history, pins and file ownership.
+    /// Returns None if the message has already expired.
+    pub fn expire_message(&mut self, message_id: u64) -> Option<Ephemeral> {
+        let ephemeral = self.ephemeral.remove(&message_id)?;
+        self.history
+            .retain(|m| message_id_of(m) != Some(message_id));
+        self.pinned.retain(|m| message_id_of(m) != Some(message_id));
+
+        if let Some(ref file_id) = ephemeral.file_id {
+            self.file_owners.remove(file_id);
+            self.links.revoke_file(file_id);
+            self.expired_files.insert(file_id.clone());
+        }
+        Some(ephemeral)
+    }
+
+    /// The user who shared `file_id`, from history or pins.
+    pub fn file_sender(&self, file_id: &str) -> Option<&str> {
+        self.history
+            .iter()
+            .chain(self.pinned.iter())
+            .find(|m| m.get("fileId").and_then(|v| v.as_str()) == Some(file_id))
+            .and_then(|m| m.get("senderId"))
+            .and_then(|v| v.as_str())
+    }
+
+    /// The SHA-256 the sender announced for `file_id`, as lowercase hex.
+    pub fn file_sha256(&self, file_id: &str) -> Option<&str> {
+        self.history
+            .iter()
+            .chain(self.pinned.iter())
+            .find(|m| m.get("fileId").and_then(|v| v.as_str()) == Some(file_id))
+            .and_then(|m| m.get("sha256"))
+            .and_then(|v| v.as_str())
+    }
+
+    /// The server's copy of a file, once the scanner has let it through.
+    /// Without a scanner every stored copy may be served.
+    pub fn scanned_copy(&self, file_id: &str) -> Option<&StoredFile> {
+        let scanned = self.scanner.is_none()
+            || matches!(
+                self.scans.get(file_id),
+                Some(Verdict::Clean | Verdict::Failed { .. })
+            );
+        self.file_store.get(file_id).filter(|_| scanned)
+    }
+
+    /// Finds the view-once message that shared `file_id`.
+    pub fn view_once_message_for_file(&self, file_id: &str) -> Option<u64> {
+        self.ephemeral
+            .iter()
+            .find(|(_, e)| e.view_once && e.file_id.as_deref() == Some(file_id))
+            .map(|(id, _)| *id)
+    }
+}
+
+pub fn message_id_of(msg: &Value) -> Option<u64> {
+    msg.get("id").and_then(|v| v.as_u64())
 }
 
 pub type SharedState = Arc<RwLock<AppState>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_sockets() {
+        let mut state = AppState::default();
+        state.config.guest_code = Some("guest".to_string());
+        for id in ["old", "current", "guest", "admin"] {
+            state
+                .socket_to_session
+                .insert(id.to_string(), format!("session-{}", id));
+        }
+        state.socket_room_codes.insert("old".to_string(), None);
+        state
+            .socket_room_codes
+            .insert("current".to_string(), Some("123456".to_string()));
+        state
+            .socket_room_codes
+            .insert("guest".to_string(), Some("guest".to_string()));
+        state.socket_room_codes.insert("admin".to_string(), None);
+        state.admin_sockets.insert("admin".to_string());
+
+        // Nothing is stale while the room code is off
+        assert!(state.stale_sockets().is_empty());
+
+        state.room_code_enabled = true;
+        state.room_code = Some("123456".to_string());
+        assert_eq!(state.stale_sockets(), vec!["old".to_string()]);
+
+        // Guests keep their code when the member code changes
+        state.room_code = Some("654321".to_string());
+        let mut stale = state.stale_sockets();
+        stale.sort();
+        assert_eq!(stale, vec!["current".to_string(), "old".to_string()]);
+    }
+
+    #[test]
+    fn test_session_ban() {
+        let mut state = AppState::default();
+        state.banned_sessions.insert("temp".to_string(), Some(1000));
+        state.banned_sessions.insert("forever".to_string(), None);
+
+        assert_eq!(state.session_ban("temp", 500), Some(Some(1000)));
+        assert_eq!(state.session_ban("forever", 500), Some(None));
+        assert_eq!(state.session_ban("other", 500), None);
+
+        assert_eq!(state.session_ban("temp", 1000), None);
+        assert!(!state.banned_sessions.contains_key("temp"));
+    }
+}
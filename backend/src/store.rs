@@ -0,0 +1,207 @@
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, path::PathBuf};
+use tokio::{fs, io::AsyncWriteExt, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::state::SharedState;
+use crate::utils::to_hex;
+
+#[derive(Clone, Debug)]
+pub struct StoredFile {
+    pub name: String,
+    pub size: u64,
+    pub path: PathBuf,
+    // User who shared the file, for the per-user storage limit
+    pub owner: String,
+    // Hex SHA-256 of the stored bytes, known once the fetch is done
+    pub sha256: Option<String>,
+}
+
+/// Server-side copies of shared files, kept so they stay downloadable after
+/// the sender disconnects. Files are written under random names; the client
+/// supplied `fileId` never touches the filesystem.
+pub struct FileStore {
+    dir: PathBuf,
+    files: HashMap<String, StoredFile>,
+    // file_id -> (owner, size) of files being fetched
+    pending: HashMap<String, (String, u64)>,
+}
+
+impl FileStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            files: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, file_id: &str) -> Option<&StoredFile> {
+        self.files.get(file_id)
+    }
+
+    pub fn contains(&self, file_id: &str) -> bool {
+        self.files.contains_key(file_id) || self.pending.contains_key(file_id)
+    }
+
+    /// Bytes kept or being fetched for files shared by `owner`.
+    pub fn stored_bytes(&self, owner: &str) -> u64 {
+        let kept: u64 = self
+            .files
+            .values()
+            .filter(|f| f.owner == owner)
+            .map(|f| f.size)
+            .sum();
+        let fetching: u64 = self
+            .pending
+            .values()
+            .filter(|(o, _)| o == owner)
+            .map(|(_, size)| size)
+            .sum();
+        kept + fetching
+    }
+
+    /// Marks a file as being fetched and returns the path to write it to.
+    /// The size counts towards the owner's storage from now on.
+    pub fn begin(&mut self, file_id: &str, owner: &str, size: u64) -> PathBuf {
+        self.pending
+            .insert(file_id.to_string(), (owner.to_string(), size));
+        self.dir.join(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// Registers a fetched file. Returns false if the fetch was cancelled in
+    /// the meantime, in which case the caller should delete the file.
+    pub fn finish(&mut self, file_id: &str, file: StoredFile) -> bool {
+        if self.pending.remove(file_id).is_none() {
+            return false;
+        }
+        self.files.insert(file_id.to_string(), file);
+        true
+    }
+
+    pub fn abort(&mut self, file_id: &str) {
+        self.pending.remove(file_id);
+    }
+
+    /// Forgets a file and returns its path so the caller can delete it.
+    pub fn remove(&mut self, file_id: &str) -> Option<PathBuf> {
+        self.pending.remove(file_id);
+        self.files.remove(file_id).map(|f| f.path)
+    }
+}
+
+impl Default for FileStore {
+    fn default() -> Self {
+        Self::new(std::env::temp_dir().join("zher").join("files"))
+    }
+}
+
+/// Writes a relayed upload into the file store at `file.path`, which the
+/// caller got from `FileStore::begin`. The transfer is started by the caller,
+/// exactly like a download, with `rx` as the receiving end. Returns whether
+/// the file was stored.
+pub async fn fetch_into_store(
+    state: SharedState,
+    file_id: String,
+    file: StoredFile,
+    mut rx: mpsc::Receiver<Result<Bytes, std::io::Error>>,
+) -> bool {
+    let path = file.path.clone();
+    let (name, size) = (file.name.clone(), file.size);
+
+    let result: std::io::Result<(u64, String)> = async {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut file = fs::File::create(&path).await?;
+        let mut written = 0u64;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = rx.recv().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok((written, to_hex(&hasher.finalize())))
+    }
+    .await;
+
+    let stored = match result {
+        Ok((written, sha256)) if written == size => {
+            let file = StoredFile {
+                sha256: Some(sha256),
+                ..file
+            };
+            let mut state_write = state.write().unwrap();
+            state_write.file_store.finish(&file_id, file)
+        }
+        Ok((written, _)) => {
+            warn!(
+                "Stored file {} is incomplete ({} of {} bytes)",
+                file_id, written, size
+            );
+            state.write().unwrap().file_store.abort(&file_id);
+            false
+        }
+        Err(e) => {
+            warn!("Failed to store file {}: {}", file_id, e);
+            state.write().unwrap().file_store.abort(&file_id);
+            false
+        }
+    };
+
+    if stored {
+        info!("Stored file {} ({} bytes)", name, size);
+    } else {
+        let _ = fs::remove_file(&path).await;
+    }
+    stored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(path: PathBuf) -> StoredFile {
+        StoredFile {
+            name: "a.txt".to_string(),
+            size: 1,
+            path,
+            owner: "user-1".to_string(),
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_finish_after_begin() {
+        let mut store = FileStore::new(PathBuf::from("/tmp/zher-test"));
+        let path = store.begin("f1", "user-1", 1);
+        assert!(path.starts_with("/tmp/zher-test"));
+        assert!(store.contains("f1"));
+        assert_eq!(store.stored_bytes("user-1"), 1);
+        assert!(store.finish("f1", stored(path.clone())));
+        assert_eq!(store.get("f1").unwrap().path, path);
+        assert_eq!(store.stored_bytes("user-1"), 1);
+        assert_eq!(store.remove("f1"), Some(path));
+        assert!(!store.contains("f1"));
+        assert_eq!(store.stored_bytes("user-1"), 0);
+    }
+
+    #[test]
+    fn test_finish_after_remove_is_rejected() {
+        let mut store = FileStore::new(PathBuf::from("/tmp/zher-test"));
+        let path = store.begin("f1", "user-1", 1);
+        assert_eq!(store.remove("f1"), None);
+        assert!(!store.finish("f1", stored(path)));
+        assert!(store.get("f1").is_none());
+    }
+
+    #[test]
+    fn test_path_ignores_file_id() {
+        let mut store = FileStore::new(PathBuf::from("/tmp/zher-test"));
+        let path = store.begin("../../etc/passwd", "user-1", 1);
+        assert_eq!(path.parent(), Some(std::path::Path::new("/tmp/zher-test")));
+    }
+}
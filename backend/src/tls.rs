@@ -0,0 +1,438 @@
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use rcgen::{
+    BasicConstraints, CertificateParams, CidrSubnet, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, GeneralSubtree, IsCa, KeyPair, KeyUsagePurpose, NameConstraints,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+use time::{Duration, OffsetDateTime};
+use tokio::{net::TcpListener, sync::oneshot};
+use tracing::{info, warn};
+
+use crate::handlers::download_ca;
+use crate::state::SharedState;
+
+// iOS rejects server certificates valid for longer than 825 days
+const SERVER_CERT_DAYS: i64 = 825;
+// The server certificate is reissued once it gets this close to expiring
+const RENEW_BEFORE_DAYS: i64 = 30;
+const CA_DAYS: i64 = 10 * 365;
+
+// The CA may only vouch for these, so a leaked key cannot be used against
+// public sites on phones that trust it
+const LOCAL_DOMAINS: [&str; 5] = ["localhost", "local", "lan", "home.arpa", "internal"];
+const LOCAL_NETWORKS: [&str; 9] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "fc00::/7",
+    "fe80::/10",
+    "::1/128",
+];
+
+pub struct TlsCertificates {
+    pub ca_pem: String,
+    pub cert_pem: String,
+    pub key_pem: String,
+    // SHA-256 of the CA certificate, colon separated like browsers show it
+    pub fingerprint: String,
+}
+
+impl TlsCertificates {
+    /// Loads the local CA from `dir`, creating it on first run, and makes sure
+    /// the server certificate covers `hosts`. Without `dir` everything is
+    /// regenerated on every start.
+    pub fn load_or_generate(dir: Option<&Path>, hosts: &[String]) -> Result<Self, rcgen::Error> {
+        let (ca_pem, ca_key, ca_created) = match read_pair(dir, "ca") {
+            Some((pem, key)) => (pem, key, false),
+            None => {
+                let key = KeyPair::generate()?;
+                let pem = ca_params().self_signed(&key)?.pem();
+                write_pair(dir, "ca", &pem, &key);
+                info!("Generated local CA certificate");
+                (pem, key, true)
+            }
+        };
+        // Same subject and key as the stored certificate, so whatever it signs
+        // chains to the CA phones have installed
+        let ca = CertificateParams::from_ca_cert_pem(&ca_pem)?.self_signed(&ca_key)?;
+
+        let names = server_names(hosts);
+        let reusable =
+            read_pair(dir, "server").filter(|(pem, _)| !ca_created && covers(pem, &names));
+        let (cert_pem, key) = match reusable {
+            Some(pair) => pair,
+            None => {
+                let key = KeyPair::generate()?;
+                let pem = server_params(&names)?.signed_by(&key, &ca, &ca_key)?.pem();
+                write_pair(dir, "server", &pem, &key);
+                info!("Issued server certificate for {}", names.join(", "));
+                (pem, key)
+            }
+        };
+
+        let der = pem::parse(&ca_pem)
+            .map_err(|_| rcgen::Error::CouldNotParseCertificate)?
+            .into_contents();
+        Ok(Self {
+            fingerprint: fingerprint(&der),
+            ca_pem,
+            cert_pem,
+            key_pem: key.serialize_pem(),
+        })
+    }
+}
+
+/// LAN addresses and the hostname of this machine.
+pub fn detect_hosts() -> Vec<String> {
+    let mut hosts: Vec<String> = local_ip_address::list_afinet_netifas()
+        .map(|ifas| ifas.into_iter().map(|(_, ip)| ip.to_string()).collect())
+        .unwrap_or_default();
+    if let Some(name) = hostname::get().ok().and_then(|n| n.into_string().ok()) {
+        hosts.push(name);
+    }
+    hosts
+}
+
+fn ca_params() -> CertificateParams {
+    let now = OffsetDateTime::now_utc();
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Zher Local CA");
+    params
+        .distinguished_name
+        .push(DnType::OrganizationName, "Zher");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(CA_DAYS);
+
+    let mut permitted: Vec<GeneralSubtree> = LOCAL_DOMAINS
+        .iter()
+        .map(|d| GeneralSubtree::DnsName(d.to_string()))
+        .collect();
+    permitted.extend(
+        LOCAL_NETWORKS
+            .iter()
+            .map(|n| GeneralSubtree::IpAddress(n.parse::<CidrSubnet>().unwrap())),
+    );
+    params.name_constraints = Some(NameConstraints {
+        permitted_subtrees: permitted,
+        excluded_subtrees: Vec::new(),
+    });
+    params
+}
+
+fn server_params(names: &[String]) -> Result<CertificateParams, rcgen::Error> {
+    let now = OffsetDateTime::now_utc();
+    let mut params = CertificateParams::new(names.to_vec())?;
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, "Zher");
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(SERVER_CERT_DAYS);
+    Ok(params)
+}
+
+// Names the server certificate should cover, limited to what the CA permits.
+// A bare hostname becomes `<hostname>.local`, which mDNS resolves.
+pub fn server_names(hosts: &[String]) -> Vec<String> {
+    let mut names = BTreeSet::from(["localhost".to_string(), "127.0.0.1".to_string()]);
+    for host in hosts {
+        let host = host.trim().to_lowercase();
+        if host.is_empty() {
+            continue;
+        }
+        match host.parse::<IpAddr>() {
+            Ok(ip) if is_local_ip(ip) => {
+                names.insert(ip.to_string());
+            }
+            Ok(_) => {}
+            Err(_) if is_local_name(&host) => {
+                names.insert(host);
+            }
+            Err(_) if !host.contains('.') => {
+                names.insert(format!("{}.local", host));
+            }
+            Err(_) => {}
+        }
+    }
+    names.into_iter().collect()
+}
+
+fn is_local_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            v6.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+fn is_local_name(name: &str) -> bool {
+    LOCAL_DOMAINS
+        .iter()
+        .any(|d| name == *d || name.ends_with(&format!(".{}", d)))
+}
+
+// Whether a stored server certificate still matches `names` and is not
+// about to expire
+fn covers(pem: &str, names: &[String]) -> bool {
+    // Despite its name this parses any certificate
+    let (Ok(existing), Ok(wanted)) = (
+        CertificateParams::from_ca_cert_pem(pem),
+        CertificateParams::new(names.to_vec()),
+    ) else {
+        return false;
+    };
+    let renew_at = existing.not_after - Duration::days(RENEW_BEFORE_DAYS);
+    existing.subject_alt_names.len() == wanted.subject_alt_names.len()
+        && wanted
+            .subject_alt_names
+            .iter()
+            .all(|san| existing.subject_alt_names.contains(san))
+        && OffsetDateTime::now_utc() < renew_at
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn pair_paths(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{}.pem", name)),
+        dir.join(format!("{}.key", name)),
+    )
+}
+
+fn read_pair(dir: Option<&Path>, name: &str) -> Option<(String, KeyPair)> {
+    let (cert_path, key_path) = pair_paths(dir?, name);
+    let cert = fs::read_to_string(cert_path).ok()?;
+    let key = fs::read_to_string(&key_path).ok()?;
+    match KeyPair::from_pem(&key) {
+        Ok(key) => Some((cert, key)),
+        Err(e) => {
+            warn!("Ignoring invalid {}: {}", key_path.display(), e);
+            None
+        }
+    }
+}
+
+fn write_pair(dir: Option<&Path>, name: &str, cert: &str, key: &KeyPair) {
+    let Some(dir) = dir else {
+        return;
+    };
+    let (cert_path, key_path) = pair_paths(dir, name);
+    let result = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&cert_path, cert))
+        .and_then(|_| write_private(&key_path, &key.serialize_pem()));
+    if let Err(e) = result {
+        warn!("Failed to save {}: {}", cert_path.display(), e);
+    }
+}
+
+// Private keys are readable by the owner only
+fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+pub async fn rustls_config(certificates: &TlsCertificates) -> io::Result<RustlsConfig> {
+    // Only ring is compiled in, but rustls still wants it installed explicitly
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem(
+        certificates.cert_pem.clone().into_bytes(),
+        certificates.key_pem.clone().into_bytes(),
+    )
+    .await
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: RustlsConfig,
+    shutdown_rx: Option<oneshot::Receiver<()>>,
+) -> io::Result<()> {
+    let handle = Handle::new();
+    if let Some(shutdown_rx) = shutdown_rx {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let _ = shutdown_rx.await;
+            handle.graceful_shutdown(None);
+        });
+    }
+
+    axum_server::from_tcp_rustls(listener.into_std()?, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+}
+
+/// Plain HTTP listener that sends browsers on to HTTPS. It also serves the
+/// CA certificate, so phones can install it before their first HTTPS visit.
+pub async fn redirect_http(
+    listener: TcpListener,
+    https_port: u16,
+    fallback_host: String,
+    state: SharedState,
+) -> io::Result<()> {
+    let app = Router::new()
+        .route("/api/tls/ca", get(download_ca))
+        .fallback(move |headers: HeaderMap, uri: Uri| async move {
+            redirect_to_https(&headers, &uri, &fallback_host, https_port)
+        })
+        .with_state(state);
+    axum::serve(listener, app).await
+}
+
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, fallback_host: &str, port: u16) -> Response {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(host_without_port)
+        .unwrap_or(fallback_host);
+    if host.is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    Redirect::temporary(&https_location(host, port, uri)).into_response()
+}
+
+fn https_location(host: &str, port: u16, uri: &Uri) -> String {
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    if port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, port, path)
+    }
+}
+
+// Strips the port from a Host header, keeping IPv6 literals bracketed
+fn host_without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("zher-tls-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_server_names() {
+        let hosts = [
+            "192.168.1.20".to_string(),
+            "8.8.8.8".to_string(),
+            "fe80::1".to_string(),
+            "MyPC".to_string(),
+            "nas.lan".to_string(),
+            "example.com".to_string(),
+        ];
+        assert_eq!(
+            server_names(&hosts),
+            vec![
+                "127.0.0.1",
+                "192.168.1.20",
+                "fe80::1",
+                "localhost",
+                "mypc.local",
+                "nas.lan"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_local_ip() {
+        for ip in [
+            "10.1.2.3",
+            "172.31.0.1",
+            "100.100.1.1",
+            "127.0.0.1",
+            "fd00::1",
+            "::1",
+        ] {
+            assert!(is_local_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "100.128.0.1", "2001:db8::1"] {
+            assert!(!is_local_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_certificates_persist() {
+        let dir = temp_dir();
+        let hosts = ["192.168.1.20".to_string()];
+        let first = TlsCertificates::load_or_generate(Some(&dir), &hosts).unwrap();
+        assert_eq!(first.fingerprint.len(), 32 * 3 - 1);
+
+        // Same hosts: nothing is reissued
+        let second = TlsCertificates::load_or_generate(Some(&dir), &hosts).unwrap();
+        assert_eq!(second.fingerprint, first.fingerprint);
+        assert_eq!(second.cert_pem, first.cert_pem);
+
+        // A new address gets a new server certificate from the same CA
+        let hosts = ["192.168.1.20".to_string(), "10.0.0.5".to_string()];
+        let third = TlsCertificates::load_or_generate(Some(&dir), &hosts).unwrap();
+        assert_eq!(third.fingerprint, first.fingerprint);
+        assert_ne!(third.cert_pem, first.cert_pem);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_https_location() {
+        let uri: Uri = "/api/export?format=md".parse().unwrap();
+        assert_eq!(
+            https_location(host_without_port("192.168.1.20:8080"), 8443, &uri),
+            "https://192.168.1.20:8443/api/export?format=md"
+        );
+        assert_eq!(
+            https_location(
+                host_without_port("[fe80::1]:80"),
+                443,
+                &Uri::from_static("/")
+            ),
+            "https://[fe80::1]/"
+        );
+    }
+}
@@ -1,12 +1,59 @@
 use rand::Rng;
 
-pub fn generate_room_code() -> String {
+use crate::config::RoomCodeFormat;
+use crate::state::User;
+
+// No 0/O or 1/I/L, so codes read out loud or typed from a screen survive
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+pub fn generate_room_code(format: RoomCodeFormat) -> String {
     let mut rng = rand::thread_rng();
-    format!("{:06}", rng.gen_range(0..1000000))
+    match format {
+        RoomCodeFormat::Digits => format!("{:06}", rng.gen_range(0..1000000)),
+        RoomCodeFormat::Alphanumeric => (0..10)
+            .map(|_| CODE_CHARS[rng.gen_range(0..CODE_CHARS.len())] as char)
+            .collect(),
+        RoomCodeFormat::Passphrase => (0..4)
+            .map(|_| {
+                (0..5)
+                    .map(|_| rng.gen_range(b'a'..=b'z') as char)
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("-"),
+    }
+}
+
+pub fn validate_room_code(code: &str, format: RoomCodeFormat) -> bool {
+    match format {
+        RoomCodeFormat::Digits => code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()),
+        RoomCodeFormat::Alphanumeric => {
+            (8..=32).contains(&code.len()) && code.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        RoomCodeFormat::Passphrase => {
+            let words = code
+                .split(|c: char| c.is_whitespace() || c == '-')
+                .filter(|w| !w.is_empty())
+                .count();
+            words >= 3 && (12..=128).contains(&code.chars().count())
+        }
+    }
+}
+
+pub fn generate_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..16)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-pub fn validate_room_code(code: &str) -> bool {
-    code.len() == 6 && code.chars().all(|c| c.is_ascii_digit())
+// Compares secrets without leaking the position of the first mismatch
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 pub fn get_random_color() -> String {
@@ -29,31 +76,124 @@ pub fn get_device_type(user_agent: &str) -> String {
     }
 }
 
+/// Resolves `@name` mentions in `text` to user ids, in order of appearance.
+/// The longest matching name wins, so `@bobby` never resolves to `bob`.
+pub fn find_mentions<'a>(text: &str, users: impl IntoIterator<Item = &'a User>) -> Vec<String> {
+    let mut users: Vec<&User> = users.into_iter().filter(|u| !u.name.is_empty()).collect();
+    users.sort_by_key(|u| std::cmp::Reverse(u.name.len()));
+
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut mentions = Vec::new();
+
+    for (i, _) in text.match_indices('@') {
+        // Skip e-mail addresses and similar
+        if text[..i].chars().next_back().is_some_and(is_word_char) {
+            continue;
+        }
+
+        let rest = &text[i + 1..];
+        let user = users.iter().find(|u| {
+            rest.starts_with(u.name.as_str())
+                && !rest[u.name.len()..]
+                    .chars()
+                    .next()
+                    .is_some_and(is_word_char)
+        });
+        if let Some(user) = user {
+            if !mentions.contains(&user.id) {
+                mentions.push(user.id.clone());
+            }
+        }
+    }
+
+    mentions
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_generate_room_code() {
-        let code = generate_room_code();
+        let code = generate_room_code(RoomCodeFormat::Digits);
         assert_eq!(code.len(), 6);
         assert!(code.chars().all(|c| c.is_ascii_digit()));
     }
 
+    #[test]
+    fn test_generated_codes_validate() {
+        for format in [
+            RoomCodeFormat::Digits,
+            RoomCodeFormat::Alphanumeric,
+            RoomCodeFormat::Passphrase,
+        ] {
+            assert!(validate_room_code(&generate_room_code(format), format));
+        }
+    }
+
     #[test]
     fn test_validate_room_code_valid() {
-        assert!(validate_room_code("123456"));
-        assert!(validate_room_code("000000"));
-        assert!(validate_room_code("999999"));
+        assert!(validate_room_code("123456", RoomCodeFormat::Digits));
+        assert!(validate_room_code("000000", RoomCodeFormat::Digits));
+        assert!(validate_room_code("999999", RoomCodeFormat::Digits));
     }
 
     #[test]
     fn test_validate_room_code_invalid() {
-        assert!(!validate_room_code("12345")); // Too short
-        assert!(!validate_room_code("1234567")); // Too long
-        assert!(!validate_room_code("12345a")); // Contains letter
-        assert!(!validate_room_code("12-456")); // Contains dash
-        assert!(!validate_room_code("")); // Empty
+        assert!(!validate_room_code("12345", RoomCodeFormat::Digits)); // Too short
+        assert!(!validate_room_code("1234567", RoomCodeFormat::Digits)); // Too long
+        assert!(!validate_room_code("12345a", RoomCodeFormat::Digits)); // Contains letter
+        assert!(!validate_room_code("12-456", RoomCodeFormat::Digits)); // Contains dash
+        assert!(!validate_room_code("", RoomCodeFormat::Digits)); // Empty
+    }
+
+    #[test]
+    fn test_validate_room_code_alphanumeric() {
+        assert!(validate_room_code("K7QX2MNP", RoomCodeFormat::Alphanumeric));
+        assert!(!validate_room_code("K7QX2MN", RoomCodeFormat::Alphanumeric)); // Too short
+        assert!(!validate_room_code(
+            "K7QX-2MNP",
+            RoomCodeFormat::Alphanumeric
+        )); // Contains dash
+    }
+
+    #[test]
+    fn test_validate_room_code_passphrase() {
+        assert!(validate_room_code(
+            "correct horse battery",
+            RoomCodeFormat::Passphrase
+        ));
+        assert!(validate_room_code(
+            "apple-river-cloud",
+            RoomCodeFormat::Passphrase
+        ));
+        assert!(!validate_room_code("a bb ccc", RoomCodeFormat::Passphrase)); // Too short
+        assert!(!validate_room_code(
+            "justonelongword",
+            RoomCodeFormat::Passphrase
+        )); // One word
+    }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xab]), "000fab");
+        assert_eq!(to_hex(&[]), "");
+    }
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, generate_token());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(constant_time_eq(b"", b""));
     }
 
     #[test]
@@ -63,6 +203,34 @@ mod tests {
         assert_eq!(color.len(), 7);
     }
 
+    fn user(id: &str, name: &str) -> User {
+        User {
+            id: id.to_string(),
+            name: name.to_string(),
+            color: "#FF6B6B".to_string(),
+            device: "desktop".to_string(),
+            role: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_find_mentions() {
+        let users = vec![user("1", "bob"), user("2", "bobby"), user("3", "Mary Ann")];
+        assert_eq!(find_mentions("hi @bob", &users), vec!["1"]);
+        assert_eq!(find_mentions("@bobby, and @bob!", &users), vec!["2", "1"]);
+        assert_eq!(find_mentions("@Mary Ann see this", &users), vec!["3"]);
+        assert_eq!(find_mentions("@bob @bob", &users), vec!["1"]);
+    }
+
+    #[test]
+    fn test_find_mentions_ignores_non_mentions() {
+        let users = vec![user("1", "bob")];
+        assert!(find_mentions("mail alice@bob", &users).is_empty());
+        assert!(find_mentions("@bobcat", &users).is_empty());
+        assert!(find_mentions("@alice", &users).is_empty());
+        assert!(find_mentions("no mentions", &users).is_empty());
+    }
+
     #[test]
     fn test_get_device_type() {
         assert_eq!(get_device_type("Mozilla/5.0 (iPhone)"), "mobile");
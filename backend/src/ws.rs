@@ -1,23 +1,145 @@
 use axum::extract::ConnectInfo;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use socketioxide::extract::{Data, SocketRef, State as SocketState};
+use socketioxide::{
+    extract::{Data, SocketRef, State as SocketState},
+    SocketIo,
+};
 use std::{
     collections::HashSet,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::mpsc;
 use tracing::info;
 
-use crate::state::{Session, SharedState, User};
-use crate::utils::{get_device_type, get_random_color};
+use crate::access::{attempt_error, entry_role, has_room_access, try_room_code, CodeAttempt};
+use crate::auth::{is_admin, ADMIN_ROOM};
+use crate::config::RoomCodeChangePolicy;
+use crate::e2e::{self, E2eInfo, Envelope};
+use crate::filename;
+use crate::handlers::StartUploadData;
+use crate::integrity::{self, StreamHasher};
+use crate::limits::{check_file_meta, check_message, check_storage, INVALID_FILE_SIZE};
+use crate::links::MAX_LINK_TTL;
+use crate::login::{has_login, logged_in_user};
+use crate::pairing::{generate_phrase, ApprovedDevice, JoinRequest};
+use crate::ratelimit::{ip_key, session_key, Limit, RATE_LIMITED};
+use crate::roles::{Permission, Role, DENIED};
+use crate::scan::scan_stored_file;
+use crate::scheduler::{
+    expiry_time, now_millis, send_time, ScheduledContent, ScheduledMessage, MAX_MESSAGE_TTL,
+};
+use crate::state::{message_id_of, AppState, Ephemeral, Session, SharedState, Transfer, User};
+use crate::store::{fetch_into_store, StoredFile};
+use crate::utils::{find_mentions, get_device_type, get_random_color};
+
+// A text message is either a plain string or an object with options
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TextMessageData {
+    Plain(String),
+    Options(TextMessageOptions),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TextMessageOptions {
+    pub text: String,
+    // Seconds until the message self-destructs
+    pub ttl: Option<u64>,
+    // Delete after the first recipient has seen it
+    #[serde(rename = "viewOnce", default)]
+    pub view_once: bool,
+    // Ciphertext of the text in E2E mode; `text` is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted: Option<Envelope>,
+}
+
+// A message to post later, either a text message or a file announcement
+#[derive(Debug, Deserialize)]
+pub struct ScheduleRequest {
+    // Unix time in milliseconds
+    #[serde(rename = "sendAt")]
+    pub send_at: Option<u64>,
+    // Seconds from now, used when `sendAt` is missing
+    pub delay: Option<u64>,
+    pub text: Option<TextMessageData>,
+    pub file: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinkRequest {
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+    // Seconds the link stays valid
+    pub ttl: Option<u64>,
+    #[serde(rename = "maxDownloads")]
+    pub max_downloads: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanRequest {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    // Ban the addresses the user is connected from
+    #[serde(default)]
+    pub ip: bool,
+    // Refuse the user's session token, so the device can't rejoin with it
+    #[serde(default)]
+    pub session: bool,
+    // Seconds; None lasts until the server restarts
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleRequest {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub role: Role,
+}
+
+impl TextMessageOptions {
+    // Size of the message content, for the rate and size limits
+    pub fn byte_len(&self) -> u64 {
+        (self.text.len() + self.encrypted.as_ref().map_or(0, |e| e.data.len())) as u64
+    }
+}
+
+impl TextMessageData {
+    pub fn byte_len(&self) -> u64 {
+        match self {
+            Self::Plain(text) => text.len() as u64,
+            Self::Options(options) => options.byte_len(),
+        }
+    }
+}
+
+impl ScheduleRequest {
+    pub fn byte_len(&self) -> u64 {
+        self.text.as_ref().map_or(0, |t| t.byte_len())
+            + self.file.as_ref().map_or(0, |f| f.to_string().len() as u64)
+    }
+}
+
+impl From<TextMessageData> for TextMessageOptions {
+    fn from(data: TextMessageData) -> Self {
+        match data {
+            TextMessageData::Plain(text) => Self {
+                text,
+                ..Default::default()
+            },
+            TextMessageData::Options(options) => options,
+        }
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Auth {
-    #[serde(rename = "sessionId")]
-    pub session_id: Option<String>,
-    #[serde(rename = "roomCode")]
-    pub room_code: Option<String>,
+    // Signed token from a previous welcome
+    #[serde(rename = "sessionToken")]
+    pub session_token: Option<String>,
+    #[serde(rename = "adminToken")]
+    pub admin_token: Option<String>,
 }
 
 pub async fn on_connect(
@@ -25,50 +147,91 @@ pub async fn on_connect(
     Data(auth): Data<Auth>,
     state: SocketState<SharedState>,
 ) {
-    // Validate room code if enabled
-    {
-        let state_read = state.read().unwrap();
-        if state_read.room_code_enabled {
-            if let Some(ref expected_code) = state_read.room_code {
-                match &auth.room_code {
-                    Some(provided_code) if provided_code == expected_code => {
-                        // Valid room code
-                    }
-                    Some(_) => {
-                        // Invalid room code
-                        let socket_id = socket.id.to_string();
-                        let _ = socket.disconnect();
-                        info!("Connection rejected: Invalid room code from {}", socket_id);
-                        return;
-                    }
-                    None => {
-                        // Room code required but not provided
-                        let socket_id = socket.id.to_string();
-                        let _ = socket.disconnect();
-                        info!(
-                            "Connection rejected: Room code required but not provided from {}",
-                            socket_id
-                        );
-                        return;
-                    }
-                }
-            }
-        }
-    }
-
     // Get IP from Axum ConnectInfo
-    let ip: String = socket
+    let addr = socket
         .req_parts()
         .extensions
         .get::<ConnectInfo<SocketAddr>>()
-        .map(|c| c.0.ip().to_string())
+        .map(|c| c.0);
+
+    // Validate login and room code if enabled. The handshake already went
+    // through login_guard and room_code_guard, which admit it by cookie, but
+    // clients may also present the admin token in auth.
+    let rejected = {
+        let state_read = state.read().unwrap();
+        let headers = &socket.req_parts().headers;
+        let auth_admin = auth
+            .admin_token
+            .as_deref()
+            .is_some_and(|token| is_admin(&state_read.admin_token, None, Some(token)));
+        if !(auth_admin || has_login(&state_read, headers, addr.as_ref())) {
+            Some("login")
+        } else if !(auth_admin || has_room_access(&state_read, headers, addr.as_ref())) {
+            Some("room_code")
+        } else {
+            None
+        }
+    };
+    if let Some(reason) = rejected {
+        audit(
+            &mut state.write().unwrap(),
+            &socket,
+            "connect_rejected",
+            serde_json::json!({ "reason": reason }),
+        );
+        let socket_id = socket.id.to_string();
+        let _ = socket.disconnect();
+        info!(
+            "Connection rejected: Missing or invalid {} from {}",
+            reason, socket_id
+        );
+        return;
+    }
+
+    let ip: String = addr
+        .map(|a| a.ip().to_string())
         .unwrap_or_else(|| "unknown".to_string());
 
-    // Use session_id from auth, or fallback to generated ID
+    // Only sessions the server signed can be resumed, anything else gets a
+    // fresh session
+    let mut invalid_token = false;
     let session_key = auth
-        .session_id
+        .session_token
+        .as_deref()
+        .and_then(|token| {
+            let verified = state.read().unwrap().signer.verify(token);
+            if verified.is_none() {
+                info!("Rejected invalid session token from {}", ip);
+                invalid_token = true;
+            }
+            verified
+        })
         .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
 
+    let ban = {
+        let mut state_write = state.write().unwrap();
+        let ban = state_write.session_ban(&session_key, now_millis());
+        if let Some(until) = ban {
+            let user = state_write
+                .sessions
+                .get(&session_key)
+                .map(|s| s.user.clone());
+            state_write.audit.record(
+                "connect_rejected",
+                socket_ip(&socket),
+                user.as_ref(),
+                serde_json::json!({ "reason": "banned", "until": until }),
+            );
+        }
+        ban
+    };
+    if let Some(until) = ban {
+        info!("Rejected banned session {} from {}", session_key, ip);
+        let _ = socket.emit("banned", serde_json::json!({ "until": until }));
+        let _ = socket.disconnect();
+        return;
+    }
+
     let _ = socket.join(socket.id.to_string()); // Join own room
 
     info!(
@@ -76,120 +239,70 @@ pub async fn on_connect(
         socket.id, ip, session_key
     );
 
-    let user_profile: User;
-    let server_url: String;
-
     {
         let mut state_write = state.write().unwrap();
-        server_url = state_write.server_url.clone();
-
-        // Check if session exists for this SessionID
-        let session_exists = if let Some(session) = state_write.sessions.get_mut(&session_key) {
-            // Check if session expired (disconnected > 10 mins ago)
-            if let Some(disconnect_time) = session.disconnect_time {
-                if SystemTime::now()
-                    .duration_since(disconnect_time)
-                    .unwrap_or(Duration::ZERO)
-                    > Duration::from_secs(600)
-                {
-                    // Expired
-                    false
-                } else {
-                    // Valid, reactivate
-                    session.disconnect_time = None;
-                    session.active_sockets.insert(socket.id.to_string());
-                    true
-                }
-            } else {
-                // Already active (another tab/device with same IP)
-                session.active_sockets.insert(socket.id.to_string());
-                true
-            }
-        } else {
-            false
-        };
-
-        if session_exists {
-            // Reuse existing profile
-            user_profile = state_write.sessions.get(&session_key).unwrap().user.clone();
-        } else {
-            // Create new session/user
-            let name = format!(
-                "{}",
-                uuid::Uuid::new_v4().simple().to_string()[..6].to_string()
-            );
-            let color = get_random_color();
-            let ua = socket
-                .req_parts()
-                .headers
-                .get("user-agent")
-                .and_then(|h| h.to_str().ok());
-            let device = get_device_type(ua.unwrap_or(""));
-
-            user_profile = User {
-                id: uuid::Uuid::new_v4().to_string(), // Stable ID
-                name,
-                color,
-                device,
-            };
-
-            let mut active_sockets = HashSet::new();
-            active_sockets.insert(socket.id.to_string());
-
-            let session = Session {
-                user: user_profile.clone(),
-                disconnect_time: None,
-                active_sockets,
-            };
-
-            state_write.sessions.insert(session_key.clone(), session);
-        }
 
-        // Update socket_to_session map
-        state_write
-            .socket_to_session
-            .insert(socket.id.to_string(), session_key.clone());
-
-        // Store the room code used for this socket connection
-        state_write
-            .socket_room_codes
-            .insert(socket.id.to_string(), auth.room_code.clone());
-
-        // Collect all users to send welcome
-        let all_users: Vec<User> = state_write
-            .sessions
-            .values()
-            .filter(|s| s.disconnect_time.is_none())
-            .map(|s| s.user.clone())
-            .collect();
-
-        #[derive(Serialize)]
-        struct WelcomeData {
-            user: User,
-            #[serde(rename = "allUsers")]
-            all_users: Vec<User>,
-            #[serde(rename = "serverUrl")]
-            server_url: String,
-        }
-
-        let _ = socket.emit(
-            "welcome",
-            WelcomeData {
-                user: user_profile.clone(),
-                all_users,
-                server_url: server_url.clone(),
-            },
+        let admin = is_admin(
+            &state_write.admin_token,
+            addr.as_ref(),
+            auth.admin_token.as_deref(),
         );
+        if admin {
+            state_write.admin_sockets.insert(socket.id.to_string());
+            let _ = socket.join(ADMIN_ROOM);
+        }
+        let role = entry_role(&state_write, &socket.req_parts().headers, None);
 
-        if state_write
-            .sessions
-            .get(&session_key)
-            .unwrap()
-            .active_sockets
-            .len()
-            == 1
-        {
-            let _ = socket.broadcast().emit("user-joined", user_profile.clone());
+        // Unknown devices wait for an admin when pairing is on
+        if state_write.config.pairing && !admin && !state_write.devices.is_approved(&session_key) {
+            let request = JoinRequest {
+                id: socket.id.to_string(),
+                session_key,
+                device: socket_device(&socket),
+                ip,
+                phrase: generate_phrase(),
+                requested_at: now_millis(),
+                role,
+            };
+            info!(
+                "Socket {} from {} is waiting for approval",
+                request.id, request.ip
+            );
+            let _ = socket.emit(
+                "join-pending",
+                serde_json::json!({
+                    "phrase": request.phrase
+                }),
+            );
+            let _ = socket.within(ADMIN_ROOM).emit("join-request", &request);
+            audit(
+                &mut state_write,
+                &socket,
+                "join_pending",
+                serde_json::json!({ "device": request.device, "invalidToken": invalid_token }),
+            );
+            state_write
+                .pending_joins
+                .insert(request.id.clone(), request);
+            let _ = socket
+                .within(ADMIN_ROOM)
+                .emit("device-list", device_list(&state_write));
+        } else {
+            admit(&socket, &mut state_write, &session_key, admin, role);
+            let role = state_write
+                .user_for_socket(&socket.id.to_string())
+                .map(|u| u.role);
+            audit(
+                &mut state_write,
+                &socket,
+                "connect",
+                serde_json::json!({
+                    "socketId": socket.id.to_string(),
+                    "admin": admin,
+                    "role": role,
+                    "invalidToken": invalid_token
+                }),
+            );
         }
     }
 
@@ -203,6 +316,16 @@ pub async fn on_connect(
             }
 
             let mut state_write = state.write().unwrap();
+            if !within_limits(&mut state_write, &socket, "request-name-change", 0) {
+                return;
+            }
+            if state_write.reauth_pending.contains(&socket.id.to_string()) {
+                return;
+            }
+            if !state_write.socket_can(&socket.id.to_string(), Permission::Rename) {
+                let _ = socket.emit("name-change-fail", DENIED);
+                return;
+            }
 
             // Find session by socket
             if let Some(session_key) = state_write
@@ -233,6 +356,7 @@ pub async fn on_connect(
                         .collect();
                     let _ = socket
                         .broadcast()
+                        .to(MEMBERS_ROOM)
                         .emit("update-user-list", (all_users.clone(),));
                     let _ = socket.emit("update-user-list", (all_users,));
                 }
@@ -242,45 +366,39 @@ pub async fn on_connect(
 
     socket.on(
         "text-message",
-        |socket: SocketRef, Data::<String>(text), state: SocketState<SharedState>| async move {
-            let state_read = state.read().unwrap();
-            if let Some(session_key) = state_read.socket_to_session.get(&socket.id.to_string()) {
-                if let Some(session) = state_read.sessions.get(session_key) {
-                    let sender = &session.user;
-                    #[derive(Serialize)]
-                    struct Message {
-                        id: u128,
-                        #[serde(rename = "senderId")]
-                        sender_id: String,
-                        #[serde(rename = "senderName")]
-                        sender_name: String,
-                        #[serde(rename = "senderColor")]
-                        sender_color: String,
-                        #[serde(rename = "senderDevice")]
-                        sender_device: String,
-                        #[serde(rename = "type")]
-                        msg_type: String,
-                        text: String,
-                    }
+        |socket: SocketRef, Data::<TextMessageData>(data), state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            if !within_limits(&mut state_write, &socket, "text-message", data.byte_len()) {
+                return;
+            }
+            let Some(sender) = state_write
+                .user_for_socket(&socket.id.to_string())
+                .cloned()
+            else {
+                return;
+            };
 
-                    let id = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis();
-
-                    let msg = Message {
-                        id,
-                        sender_id: sender.id.clone(),
-                        sender_name: sender.name.clone(),
-                        sender_color: sender.color.clone(),
-                        sender_device: sender.device.clone(),
-                        msg_type: "text".to_string(),
-                        text,
-                    };
+            if !state_write.socket_can(&socket.id.to_string(), Permission::SendText) {
+                let _ = socket.emit("message-fail", DENIED);
+                return;
+            }
+            let checked = check_message(&state_write.config, "text-message", data.byte_len());
+            if let Err(error) = checked {
+                let _ = socket.emit("limit-exceeded", &error);
+                return;
+            }
 
-                    let _ = socket.broadcast().emit("message", &msg);
-                    let _ = socket.emit("message", &msg);
-                }
+            let options: TextMessageOptions = data.into();
+            if let Err(error) = e2e::check_text(&state_write, &options) {
+                let _ = socket.emit("message-fail", error);
+                return;
+            }
+
+            let posted = post_text_message(&mut state_write, &sender, options);
+            let _ = socket.broadcast().to(MEMBERS_ROOM).emit("message", &posted.message);
+            let _ = socket.emit("message", &posted.message);
+            if let Some((sockets, data)) = posted.mentioned {
+                let _ = socket.within(sockets).emit("mentioned", &data);
             }
         },
     );
@@ -289,93 +407,1421 @@ pub async fn on_connect(
         "file-meta",
         |socket: SocketRef, Data::<Value>(mut meta), state: SocketState<SharedState>| async move {
             let mut state_write = state.write().unwrap();
-            let session_key = state_write
-                .socket_to_session
-                .get(&socket.id.to_string())
-                .cloned();
-
-            if let Some(session_key) = session_key {
-                if let Some(session) = state_write.sessions.get(&session_key).cloned() {
-                    let sender = &session.user;
-                    if let Some(obj) = meta.as_object_mut() {
-                        let file_id = obj
-                            .get("fileId")
-                            .and_then(|v| v.as_str())
-                            .map(|s| s.to_string())
-                            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
-
-                        let file_name = obj
-                            .get("fileName")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("unknown_file")
-                            .to_string();
-
-                        let file_size = obj.get("fileSize").and_then(|v| v.as_u64()).unwrap_or(0);
-
-                        obj.insert("fileId".to_string(), Value::String(file_id.clone()));
-
-                        let id = SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis();
-                        obj.insert(
-                            "id".to_string(),
-                            Value::Number(serde_json::Number::from(id as u64)),
-                        );
-                        obj.insert("senderId".to_string(), Value::String(sender.id.clone()));
-                        obj.insert("senderName".to_string(), Value::String(sender.name.clone()));
-                        obj.insert(
-                            "senderColor".to_string(),
-                            Value::String(sender.color.clone()),
-                        );
-                        obj.insert(
-                            "senderDevice".to_string(),
-                            Value::String(sender.device.clone()),
-                        );
-                        obj.insert("type".to_string(), Value::String("file-meta".to_string()));
-
-                        state_write
-                            .file_owners
-                            .insert(file_id, (socket.id.to_string(), file_name, file_size));
-                    }
-                    let _ = socket.broadcast().emit("message", &meta);
-                    let _ = socket.emit("message", &meta);
+            let bytes = meta.to_string().len() as u64;
+            if !within_limits(&mut state_write, &socket, "file-meta", bytes) {
+                return;
+            }
+            let Some(sender) = state_write.user_for_socket(&socket.id.to_string()).cloned() else {
+                return;
+            };
+            if !state_write.socket_can(&socket.id.to_string(), Permission::ShareFiles) {
+                let _ = socket.emit("message-fail", DENIED);
+                return;
+            }
+            match check_file_meta(&state_write.config, "file-meta", &meta) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    let _ = socket.emit("message-fail", INVALID_FILE_SIZE);
+                    return;
                 }
+                Err(error) => {
+                    let _ = socket.emit("limit-exceeded", &error);
+                    return;
+                }
+            }
+            let checked = e2e::check_file_meta(&state_write, &mut meta)
+                .and_then(|_| integrity::check_file_meta(&mut meta));
+            if let Err(error) = checked {
+                let _ = socket.emit("message-fail", error);
+                return;
             }
+
+            let meta = post_file_meta(&mut state_write, &sender, &socket.id.to_string(), meta);
+            let _ = socket.broadcast().to(MEMBERS_ROOM).emit("message", &meta);
+            let _ = socket.emit("message", &meta);
         },
     );
 
-    socket.on_disconnect(
+    socket.on(
+        "schedule-message",
+        |socket: SocketRef, Data::<ScheduleRequest>(request), state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            if !within_limits(&mut state_write, &socket, "schedule-message", request.byte_len()) {
+                return;
+            }
+            let Some(sender) = state_write
+                .user_for_socket(&socket.id.to_string())
+                .cloned()
+            else {
+                return;
+            };
+
+            let send_at = match send_time(now_millis(), request.send_at, request.delay) {
+                Ok(send_at) => send_at,
+                Err(error) => {
+                    let _ = socket.emit("schedule-fail", error);
+                    return;
+                }
+            };
+            let mut content = match (request.text, request.file) {
+                (Some(text), _) => ScheduledContent::Text(text.into()),
+                (None, Some(meta)) => ScheduledContent::File { meta },
+                (None, None) => {
+                    let _ = socket.emit("schedule-fail", "消息内容为空");
+                    return;
+                }
+            };
+            let needed = match content {
+                ScheduledContent::Text(_) => Permission::SendText,
+                ScheduledContent::File { .. } => Permission::ShareFiles,
+            };
+            if !state_write.socket_can(&socket.id.to_string(), needed) {
+                let _ = socket.emit("schedule-fail", DENIED);
+                return;
+            }
+            let sized = match content {
+                ScheduledContent::Text(ref options) => {
+                    let bytes = options.byte_len();
+                    check_message(&state_write.config, "schedule-message", bytes)
+                        .map(|_| Some(bytes))
+                }
+                ScheduledContent::File { ref meta } => {
+                    check_file_meta(&state_write.config, "schedule-message", meta)
+                }
+            };
+            match sized {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    let _ = socket.emit("schedule-fail", INVALID_FILE_SIZE);
+                    return;
+                }
+                Err(error) => {
+                    let _ = socket.emit("limit-exceeded", &error);
+                    return;
+                }
+            }
+            let checked = match content {
+                ScheduledContent::Text(ref options) => e2e::check_text(&state_write, options),
+                ScheduledContent::File { ref mut meta } => {
+                    e2e::check_file_meta(&state_write, meta)
+                        .and_then(|_| integrity::check_file_meta(meta))
+                }
+            };
+            if let Err(error) = checked {
+                let _ = socket.emit("schedule-fail", error);
+                return;
+            }
+
+            let item = ScheduledMessage {
+                id: uuid::Uuid::new_v4().to_string(),
+                send_at,
+                sender,
+                socket_id: socket.id.to_string(),
+                content,
+            };
+            state_write.scheduler.schedule(item.clone());
+            let _ = socket.emit("message-scheduled", &item);
+        },
+    );
+
+    socket.on(
+        "list-scheduled",
         |socket: SocketRef, state: SocketState<SharedState>| async move {
+            let state_read = state.read().unwrap();
+            if let Some(user) = state_read.user_for_socket(&socket.id.to_string()) {
+                let items = state_read.scheduler.by_sender(&user.id);
+                let _ = socket.emit("scheduled-list", (items,));
+            }
+        },
+    );
+
+    socket.on(
+        "cancel-scheduled",
+        |socket: SocketRef, Data::<String>(id), state: SocketState<SharedState>| async move {
             let mut state_write = state.write().unwrap();
-            // Get SessionID
-            if let Some(session_key) = state_write.socket_to_session.remove(&socket.id.to_string())
-            {
-                let mut remove_user = false;
-                let mut user_id = String::new();
+            if !within_limits(&mut state_write, &socket, "cancel-scheduled", 0) {
+                return;
+            }
+            let Some(user_id) = state_write
+                .user_for_socket(&socket.id.to_string())
+                .map(|u| u.id.clone())
+            else {
+                return;
+            };
 
-                if let Some(session) = state_write.sessions.get_mut(&session_key) {
-                    session.active_sockets.remove(&socket.id.to_string());
-                    if session.active_sockets.is_empty() {
-                        // All tabs closed
-                        session.disconnect_time = Some(SystemTime::now());
-                        remove_user = true;
-                        user_id = session.user.id.clone();
+            match state_write.scheduler.get(&id) {
+                Some(item) if item.sender.id == user_id => {
+                    state_write.scheduler.cancel(&id);
+                    let _ = socket.emit("scheduled-cancelled", &id);
+                }
+                Some(_) => {
+                    let _ = socket.emit("schedule-fail", "只有发送者可以取消");
+                }
+                None => {
+                    let _ = socket.emit("schedule-fail", "定时消息不存在");
+                }
+            }
+        },
+    );
+
+    socket.on(
+        "message-viewed",
+        |socket: SocketRef, Data::<u64>(message_id), state: SocketState<SharedState>| async move {
+            let expired = {
+                let mut state_write = state.write().unwrap();
+                if !within_limits(&mut state_write, &socket, "message-viewed", 0) {
+                    return;
+                }
+                let Some(user_id) = state_write
+                    .user_for_socket(&socket.id.to_string())
+                    .map(|u| u.id.clone())
+                else {
+                    return;
+                };
+
+                // View-once files are consumed by their first complete download instead
+                match state_write.ephemeral.get(&message_id) {
+                    Some(e) if e.view_once && e.file_id.is_none() && e.sender_id != user_id => {
+                        state_write.expire_message(message_id).is_some()
                     }
+                    _ => false,
                 }
+            };
 
-                if remove_user {
-                    let _ = socket.broadcast().emit("user-left", user_id);
+            if expired {
+                announce_deletion(&socket, message_id);
+            }
+        },
+    );
+
+    socket.on(
+        "pin-message",
+        |socket: SocketRef,
+         io: SocketIo,
+         Data::<u64>(message_id),
+         state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            if !within_limits(&mut state_write, &socket, "pin-message", 0) {
+                return;
+            }
+            let Some(user_id) = state_write
+                .user_for_socket(&socket.id.to_string())
+                .map(|u| u.id.clone())
+            else {
+                return;
+            };
+            // Pins change what everyone sees, so they count as posting
+            if !state_write.socket_can(&socket.id.to_string(), Permission::SendText) {
+                let _ = socket.emit("pin-fail", DENIED);
+                return;
+            }
+
+            if state_write
+                .pinned
+                .iter()
+                .any(|m| message_id_of(m) == Some(message_id))
+            {
+                return;
+            }
+            if state_write.ephemeral.contains_key(&message_id) {
+                let _ = socket.emit("pin-fail", "阅后即焚消息不能置顶");
+                return;
+            }
+
+            let Some(mut msg) = state_write
+                .history
+                .iter()
+                .find(|m| message_id_of(m) == Some(message_id))
+                .cloned()
+            else {
+                let _ = socket.emit("pin-fail", "消息不存在");
+                return;
+            };
+
+            if let Some(obj) = msg.as_object_mut() {
+                obj.insert("pinnedBy".to_string(), Value::String(user_id));
+            }
+
+            // Keep a server-side copy of pinned files so they outlive the sender
+            let file_id = msg
+                .get("fileId")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string());
+            if let Some(file_id) = file_id {
+                let owner = state_write.file_owners.get(&file_id).cloned();
+                if let (false, Some((sender_id, file_name, file_size))) =
+                    (state_write.file_store.contains(&file_id), owner)
+                {
+                    // Counted against whoever shared the file, not the pinner
+                    let stored_by = msg
+                        .get("senderId")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or_default()
+                        .to_string();
+                    let stored = state_write.file_store.stored_bytes(&stored_by);
+                    let checked =
+                        check_storage(&state_write.config, "pin-message", stored, file_size);
+                    if let Err(error) = checked {
+                        let _ = socket.emit("limit-exceeded", &error);
+                        return;
+                    }
+                    let file = StoredFile {
+                        path: state_write
+                            .file_store
+                            .begin(&file_id, &stored_by, file_size),
+                        name: file_name,
+                        size: file_size,
+                        owner: stored_by,
+                        sha256: None,
+                    };
+                    let hasher = state_write
+                        .file_sha256(&file_id)
+                        .map(|sha256| StreamHasher::new(sha256.to_string()));
+
+                    let (tx, rx) = mpsc::channel::<Result<bytes::Bytes, std::io::Error>>(2);
+                    let transfer_id = uuid::Uuid::new_v4().to_string();
+                    state_write.transfers.insert(
+                        transfer_id.clone(),
+                        Transfer {
+                            file_id: file_id.clone(),
+                            tx,
+                            length: file_size,
+                            whole_file: false,
+                            hasher,
+                            receivers: Vec::new(),
+                        },
+                    );
+
+                    let _ = socket.within(sender_id).emit(
+                        "start-upload",
+                        StartUploadData {
+                            file_id: file_id.clone(),
+                            transfer_id,
+                            offset: 0,
+                            end: file_size.saturating_sub(1),
+                        },
+                    );
+                    let state = SharedState::clone(&state);
+                    tokio::spawn(async move {
+                        if fetch_into_store(state.clone(), file_id.clone(), file, rx).await {
+                            scan_stored_file(state, io, file_id).await;
+                        }
+                    });
                 }
             }
 
-            // Remove files owned by this socket (since socket is gone, transfer impossible)
-            state_write
-                .file_owners
-                .retain(|_, v| v.0 != socket.id.to_string());
+            state_write.pinned.push(msg.clone());
 
-            // Remove room code tracking for this socket
-            state_write.socket_room_codes.remove(&socket.id.to_string());
+            let _ = socket
+                .broadcast()
+                .to(MEMBERS_ROOM)
+                .emit("message-pinned", &msg);
+            let _ = socket.emit("message-pinned", &msg);
         },
     );
+
+    socket.on(
+        "unpin-message",
+        |socket: SocketRef, Data::<u64>(message_id), state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            if !within_limits(&mut state_write, &socket, "unpin-message", 0) {
+                return;
+            }
+            let Some(user_id) = state_write
+                .user_for_socket(&socket.id.to_string())
+                .map(|u| u.id.clone())
+            else {
+                return;
+            };
+            // Pins change what everyone sees, so they count as posting
+            if !state_write.socket_can(&socket.id.to_string(), Permission::SendText) {
+                let _ = socket.emit("unpin-fail", DENIED);
+                return;
+            }
+
+            let Some(index) = state_write
+                .pinned
+                .iter()
+                .position(|m| message_id_of(m) == Some(message_id))
+            else {
+                return;
+            };
+
+            let is_sender = state_write.pinned[index]
+                .get("senderId")
+                .and_then(|v| v.as_str())
+                == Some(user_id.as_str());
+            let is_admin = state_write.admin_sockets.contains(&socket.id.to_string());
+            if !is_sender && !is_admin {
+                let _ = socket.emit("unpin-fail", "只有发送者或管理员可以取消置顶");
+                return;
+            }
+
+            let msg = state_write.pinned.remove(index);
+            if let Some(file_id) = msg.get("fileId").and_then(|v| v.as_str()) {
+                if let Some(path) = state_write.file_store.remove(file_id) {
+                    tokio::spawn(async move {
+                        let _ = tokio::fs::remove_file(path).await;
+                    });
+                }
+            }
+
+            let _ = socket
+                .broadcast()
+                .to(MEMBERS_ROOM)
+                .emit("message-unpinned", message_id);
+            let _ = socket.emit("message-unpinned", message_id);
+        },
+    );
+
+    socket.on(
+        "create-link",
+        |socket: SocketRef, Data::<LinkRequest>(request), state: SocketState<SharedState>| async move {
+            let socket_id = socket.id.to_string();
+            let now = now_millis();
+            let mut guard = state.write().unwrap();
+            let state_write = &mut *guard;
+            if !within_limits(state_write, &socket, "create-link", 0) {
+                return;
+            }
+            let Some(user) = state_write.user_for_socket(&socket_id).cloned() else {
+                return;
+            };
+            if !state_write.socket_can(&socket_id, Permission::ShareFiles) {
+                let _ = socket.emit("link-fail", DENIED);
+                return;
+            }
+            let available = state_write.file_owners.contains_key(&request.file_id)
+                || state_write.file_store.get(&request.file_id).is_some();
+            if !available || state_write.expired_files.contains(&request.file_id) {
+                let _ = socket.emit("link-fail", "文件已不可下载");
+                return;
+            }
+
+            let ttl = request.ttl.unwrap_or(3600).clamp(1, MAX_LINK_TTL);
+            let max_downloads = request.max_downloads.filter(|&max| max > 0);
+            state_write.links.prune(now);
+            let (link, query) = state_write.links.create(
+                &state_write.signer,
+                &request.file_id,
+                now + ttl * 1000,
+                max_downloads,
+                &user.id,
+            );
+            let url = format!(
+                "{}/api/download/{}?{}",
+                state_write.server_url,
+                urlencoding::encode(&request.file_id),
+                query
+            );
+            info!("User {} created a download link for {}", user.id, request.file_id);
+            audit(
+                state_write,
+                &socket,
+                "link_created",
+                serde_json::json!({
+                    "fileId": request.file_id,
+                    "link": link.id,
+                    "expiresAt": link.expires_at,
+                    "maxDownloads": link.max_downloads
+                }),
+            );
+            let _ = socket.emit("link-created", serde_json::json!({ "url": url, "link": link }));
+        },
+    );
+
+    socket.on(
+        "revoke-links",
+        |socket: SocketRef, Data::<String>(file_id), state: SocketState<SharedState>| async move {
+            let socket_id = socket.id.to_string();
+            let mut state_write = state.write().unwrap();
+            if !within_limits(&mut state_write, &socket, "revoke-links", 0) {
+                return;
+            }
+            let Some(user) = state_write.user_for_socket(&socket_id).cloned() else {
+                return;
+            };
+            // The sender of the file or an admin
+            if !state_write.admin_sockets.contains(&socket_id)
+                && state_write.file_sender(&file_id) != Some(user.id.as_str())
+            {
+                let _ = socket.emit("link-fail", DENIED);
+                return;
+            }
+            let count = state_write.links.revoke_file(&file_id);
+            info!("Revoked {} download links for {}", count, file_id);
+            audit(
+                &mut state_write,
+                &socket,
+                "links_revoked",
+                serde_json::json!({ "fileId": file_id, "count": count }),
+            );
+            let _ = socket.emit(
+                "links-revoked",
+                serde_json::json!({ "fileId": file_id, "count": count }),
+            );
+        },
+    );
+
+    socket.on(
+        "reauth",
+        |socket: SocketRef, Data::<String>(code), state: SocketState<SharedState>| async move {
+            let socket_id = socket.id.to_string();
+            let ip = socket_ip(&socket);
+            let attempt = {
+                let mut state_write = state.write().unwrap();
+                let attempt = try_room_code(&mut state_write, ip, code.trim());
+                if matches!(attempt, CodeAttempt::Accepted) {
+                    state_write.reauth_pending.remove(&socket_id);
+                    let current = state_write.room_code.clone();
+                    state_write.socket_room_codes.insert(socket_id, current);
+                }
+                attempt
+            };
+
+            if let CodeAttempt::Rejected(ref lockouts) = attempt {
+                for lockout in lockouts {
+                    let _ = socket.within(ADMIN_ROOM).emit("lockout", lockout);
+                }
+            }
+            match attempt_error(&attempt) {
+                None => {
+                    let _ = socket.emit("reauth-success", ());
+                }
+                Some(error) => {
+                    let _ = socket.emit("reauth-fail", error);
+                }
+            }
+        },
+    );
+
+    socket.on(
+        "approve-join",
+        |socket: SocketRef, Data::<String>(request_id), state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                return;
+            }
+            let Some(request) = state_write.pending_joins.remove(&request_id) else {
+                return;
+            };
+
+            // Other tabs of the same session waiting with it get in too
+            let mut waiting: Vec<String> = state_write
+                .pending_joins
+                .iter()
+                .filter(|(_, r)| r.session_key == request.session_key)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &waiting {
+                state_write.pending_joins.remove(id);
+            }
+            waiting.push(request.id.clone());
+
+            for id in &waiting {
+                let target = socket
+                    .within(id.clone())
+                    .sockets()
+                    .ok()
+                    .and_then(|s| s.into_iter().next());
+                if let Some(target) = target {
+                    admit(
+                        &target,
+                        &mut state_write,
+                        &request.session_key,
+                        false,
+                        request.role,
+                    );
+                }
+            }
+
+            let name = state_write
+                .sessions
+                .get(&request.session_key)
+                .map(|s| s.user.name.clone())
+                .unwrap_or_default();
+            state_write.devices.approve(
+                request.session_key.clone(),
+                ApprovedDevice {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    name,
+                    device: request.device.clone(),
+                    ip: request.ip.clone(),
+                    approved_at: now_millis(),
+                },
+            );
+            info!("Approved device {} from {}", request.device, request.ip);
+            audit(
+                &mut state_write,
+                &socket,
+                "approve_join",
+                serde_json::json!({
+                    "device": request.device,
+                    "ip": request.ip,
+                    "role": request.role
+                }),
+            );
+
+            let _ = socket
+                .within(ADMIN_ROOM)
+                .emit("device-list", device_list(&state_write));
+        },
+    );
+
+    socket.on(
+        "deny-join",
+        |socket: SocketRef, Data::<String>(request_id), state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                return;
+            }
+            let Some(request) = state_write.pending_joins.remove(&request_id) else {
+                return;
+            };
+            info!("Denied device {} from {}", request.device, request.ip);
+            audit(
+                &mut state_write,
+                &socket,
+                "deny_join",
+                serde_json::json!({ "device": request.device, "ip": request.ip }),
+            );
+
+            let _ = socket.within(request.id.clone()).emit("join-denied", ());
+            let _ = socket.within(request.id).disconnect();
+            let _ = socket
+                .within(ADMIN_ROOM)
+                .emit("device-list", device_list(&state_write));
+        },
+    );
+
+    socket.on(
+        "revoke-device",
+        |socket: SocketRef, Data::<String>(device_id), state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                return;
+            }
+            let Some(session_key) = state_write.devices.revoke(&device_id) else {
+                return;
+            };
+
+            // Disconnect the session so reconnecting asks for approval again
+            let sockets: Vec<String> = state_write
+                .sessions
+                .get(&session_key)
+                .map(|s| s.active_sockets.iter().cloned().collect())
+                .unwrap_or_default();
+            if !sockets.is_empty() {
+                let _ = socket.within(sockets).disconnect();
+            }
+            info!("Revoked device {}", device_id);
+            audit(
+                &mut state_write,
+                &socket,
+                "revoke_device",
+                serde_json::json!({ "deviceId": device_id }),
+            );
+
+            let _ = socket
+                .within(ADMIN_ROOM)
+                .emit("device-list", device_list(&state_write));
+        },
+    );
+
+    socket.on(
+        "revoke-session",
+        |socket: SocketRef, Data::<String>(user_id), state: SocketState<SharedState>| async move {
+            let sockets = {
+                let mut state_write = state.write().unwrap();
+                if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                    return;
+                }
+                let Some((session_key, sockets)) = take_session(&mut state_write, &user_id) else {
+                    return;
+                };
+                state_write.signer.revoke(&session_key);
+                audit(
+                    &mut state_write,
+                    &socket,
+                    "revoke_session",
+                    serde_json::json!({ "targetUserId": user_id }),
+                );
+                sockets
+            };
+            info!("Revoked session of user {}", user_id);
+            eject(&socket, sockets, &user_id, "kicked", serde_json::json!({}));
+        },
+    );
+
+    socket.on(
+        "admin-kick",
+        |socket: SocketRef, Data::<String>(user_id), state: SocketState<SharedState>| async move {
+            let sockets = {
+                let mut state_write = state.write().unwrap();
+                if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                    return;
+                }
+                let Some((_, sockets)) = take_session(&mut state_write, &user_id) else {
+                    return;
+                };
+                audit(
+                    &mut state_write,
+                    &socket,
+                    "kick",
+                    serde_json::json!({ "targetUserId": user_id }),
+                );
+                sockets
+            };
+            info!("Kicked user {}", user_id);
+            eject(&socket, sockets, &user_id, "kicked", serde_json::json!({}));
+        },
+    );
+
+    socket.on(
+        "admin-ban",
+        |socket: SocketRef, Data::<BanRequest>(request), state: SocketState<SharedState>| async move {
+            let now = now_millis();
+            let until = request.duration.map(|secs| now + secs * 1000);
+            let (sockets, bans) = {
+                let mut state_write = state.write().unwrap();
+                if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                    return;
+                }
+                let Some((session_key, sockets)) = take_session(&mut state_write, &request.user_id)
+                else {
+                    return;
+                };
+
+                if request.session {
+                    state_write.banned_sessions.insert(session_key, until);
+                }
+                if request.ip {
+                    let ips: HashSet<IpAddr> = socket
+                        .within(sockets.clone())
+                        .sockets()
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(socket_ip)
+                        .collect();
+                    for ip in ips {
+                        if let Err(error) = state_write.ip_filter.ban(ip, until) {
+                            let _ = socket.emit("ban-fail", error);
+                        }
+                    }
+                }
+                audit(
+                    &mut state_write,
+                    &socket,
+                    "ban",
+                    serde_json::json!({
+                        "targetUserId": request.user_id,
+                        "ip": request.ip,
+                        "session": request.session,
+                        "until": until
+                    }),
+                );
+                (sockets, state_write.ip_filter.bans(now))
+            };
+            info!(
+                "Banned user {} (ip: {}, session: {}) until {}",
+                request.user_id,
+                request.ip,
+                request.session,
+                until.map_or("restart".to_string(), |u| u.to_string())
+            );
+
+            eject(
+                &socket,
+                sockets,
+                &request.user_id,
+                "banned",
+                serde_json::json!({ "until": until }),
+            );
+            if request.ip {
+                let _ = socket.within(ADMIN_ROOM).emit("ip-bans", &bans);
+            }
+        },
+    );
+
+    socket.on(
+        "set-role",
+        |socket: SocketRef, Data::<RoleRequest>(request), state: SocketState<SharedState>| async move {
+            // Admin rights come from the admin token, not from here
+            if request.role == Role::Admin {
+                return;
+            }
+            let (sockets, all_users, permissions) = {
+                let mut state_write = state.write().unwrap();
+                if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                    return;
+                }
+                let Some(session) = state_write
+                    .sessions
+                    .values_mut()
+                    .find(|s| s.user.id == request.user_id && s.user.role != Role::Admin)
+                else {
+                    return;
+                };
+                session.user.role = request.role;
+                let sockets: Vec<String> = session.active_sockets.iter().cloned().collect();
+
+                let all_users: Vec<User> = state_write
+                    .sessions
+                    .values()
+                    .filter(|s| s.disconnect_time.is_none())
+                    .map(|s| s.user.clone())
+                    .collect();
+                let permissions = state_write.config.permissions.granted(request.role);
+                audit(
+                    &mut state_write,
+                    &socket,
+                    "set_role",
+                    serde_json::json!({ "targetUserId": request.user_id, "role": request.role }),
+                );
+                (sockets, all_users, permissions)
+            };
+            info!("Set role of user {} to {:?}", request.user_id, request.role);
+
+            if !sockets.is_empty() {
+                let _ = socket.within(sockets).emit(
+                    "role-changed",
+                    serde_json::json!({ "role": request.role, "permissions": permissions }),
+                );
+            }
+            let _ = socket
+                .broadcast()
+                .to(MEMBERS_ROOM)
+                .emit("update-user-list", (all_users.clone(),));
+            let _ = socket.emit("update-user-list", (all_users,));
+        },
+    );
+
+    socket.on(
+        "ban-ip",
+        |socket: SocketRef, Data::<String>(ip), state: SocketState<SharedState>| async move {
+            let Ok(ip) = ip.trim().parse::<IpAddr>() else {
+                let _ = socket.emit("ban-fail", "IP 地址无效");
+                return;
+            };
+            let ip = ip.to_canonical();
+            let bans = {
+                let mut state_write = state.write().unwrap();
+                if !state_write.admin_sockets.contains(&socket.id.to_string()) {
+                    return;
+                }
+                if let Err(error) = state_write.ip_filter.ban(ip, None) {
+                    let _ = socket.emit("ban-fail", error);
+                    return;
+                }
+                audit(
+                    &mut state_write,
+                    &socket,
+                    "ban_ip",
+                    serde_json::json!({ "target": ip }),
+                );
+                state_write.ip_filter.bans(now_millis())
+            };
+            info!("Banned {}", ip);
+
+            // New requests are refused by the IP guard, open sockets go now
+            let targets: Vec<SocketRef> = socket
+                .broadcast()
+                .sockets()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|s| socket_ip(s) == Some(ip))
+                .collect();
+            for target in targets {
+                let _ = target.emit("banned", serde_json::json!({ "until": null }));
+                let _ = target.disconnect();
+            }
+            let _ = socket.within(ADMIN_ROOM).emit("ip-bans", &bans);
+        },
+    );
+
+    socket.on(
+        "unban-ip",
+        |socket: SocketRef, Data::<String>(ip), state: SocketState<SharedState>| async move {
+            let Ok(ip) = ip.trim().parse::<IpAddr>() else {
+                return;
+            };
+            let bans = {
+                let mut state_write = state.write().unwrap();
+                if !state_write.admin_sockets.contains(&socket.id.to_string())
+                    || !state_write.ip_filter.unban(ip)
+                {
+                    return;
+                }
+                audit(
+                    &mut state_write,
+                    &socket,
+                    "unban_ip",
+                    serde_json::json!({ "target": ip }),
+                );
+                state_write.ip_filter.bans(now_millis())
+            };
+            info!("Lifted ban on {}", ip);
+            let _ = socket.within(ADMIN_ROOM).emit("ip-bans", &bans);
+        },
+    );
+
+    socket.on_disconnect(
+        |socket: SocketRef, state: SocketState<SharedState>| async move {
+            let mut state_write = state.write().unwrap();
+            // Get SessionID
+            if let Some(session_key) = state_write.socket_to_session.remove(&socket.id.to_string())
+            {
+                let mut remove_user = false;
+                let mut user_id = String::new();
+
+                if let Some(session) = state_write.sessions.get_mut(&session_key) {
+                    session.active_sockets.remove(&socket.id.to_string());
+                    if session.active_sockets.is_empty() {
+                        // All tabs closed
+                        session.disconnect_time = Some(SystemTime::now());
+                        remove_user = true;
+                        user_id = session.user.id.clone();
+                    }
+                }
+
+                if remove_user {
+                    let _ = socket
+                        .broadcast()
+                        .to(MEMBERS_ROOM)
+                        .emit("user-left", user_id);
+                }
+            }
+
+            // Remove files owned by this socket (since socket is gone, transfer impossible)
+            state_write
+                .file_owners
+                .retain(|_, v| v.0 != socket.id.to_string());
+
+            state_write.admin_sockets.remove(&socket.id.to_string());
+
+            // Remove room code tracking for this socket
+            state_write.socket_room_codes.remove(&socket.id.to_string());
+            state_write.reauth_pending.remove(&socket.id.to_string());
+            if state_write
+                .pending_joins
+                .remove(&socket.id.to_string())
+                .is_some()
+            {
+                let _ = socket
+                    .within(ADMIN_ROOM)
+                    .emit("device-list", device_list(&state_write));
+            }
+        },
+    );
+}
+
+/// Applies the configured room code change policy to sockets that joined
+/// with an outdated code, or before the code was enabled.
+pub fn challenge_stale_sockets(state: &SharedState, io: &SocketIo) {
+    let (policy, timeout, stale) = {
+        let mut state_write = state.write().unwrap();
+        let policy = state_write.config.room_code_change;
+        let timeout = state_write.config.reauth_timeout;
+        if policy == RoomCodeChangePolicy::Keep {
+            return;
+        }
+        let stale = state_write.stale_sockets();
+        if policy == RoomCodeChangePolicy::Reauth {
+            let deadline = now_millis() + timeout * 1000;
+            for socket_id in &stale {
+                state_write.reauth_pending.insert(socket_id.clone());
+                state_write
+                    .scheduler
+                    .reauth_deadline(socket_id.clone(), deadline);
+            }
+        }
+        (policy, timeout, stale)
+    };
+
+    let disconnect = policy == RoomCodeChangePolicy::Disconnect;
+    for socket_id in stale {
+        let _ = io.to(socket_id.clone()).emit(
+            "reauth-required",
+            serde_json::json!({
+                "disconnect": disconnect,
+                "timeout": timeout
+            }),
+        );
+        if disconnect {
+            let _ = io.to(socket_id).disconnect();
+        }
+    }
+}
+
+// Room of sockets that were let in; chat broadcasts only go here
+pub const MEMBERS_ROOM: &str = "members";
+
+/// Removes the session of `user_id` along with their scheduled messages, and
+/// returns its key and open sockets.
+fn take_session(state: &mut AppState, user_id: &str) -> Option<(String, Vec<String>)> {
+    let session_key = state
+        .sessions
+        .iter()
+        .find(|(_, s)| s.user.id == user_id)
+        .map(|(k, _)| k.clone())?;
+    let session = state.sessions.remove(&session_key)?;
+    state.scheduler.cancel_by_sender(user_id);
+    Some((session_key, session.active_sockets.into_iter().collect()))
+}
+
+// Tells the sockets of a removed user why with `event`, drops them and
+// takes the user off everyone's list
+fn eject(
+    socket: &SocketRef,
+    sockets: Vec<String>,
+    user_id: &str,
+    event: &'static str,
+    data: Value,
+) {
+    if !sockets.is_empty() {
+        let _ = socket.within(sockets.clone()).emit(event, &data);
+        let _ = socket.within(sockets).disconnect();
+    }
+    let _ = socket
+        .broadcast()
+        .to(MEMBERS_ROOM)
+        .emit("user-left", user_id);
+    let _ = socket.emit("user-left", user_id);
+}
+
+/// Charges an event, and `bytes` of message content, to the socket's session
+/// and address. Tells the client and returns false when over a limit.
+fn within_limits(state: &mut AppState, socket: &SocketRef, event: &str, bytes: u64) -> bool {
+    let mut keys: Vec<String> = socket_ip(socket).map(ip_key).into_iter().collect();
+    if let Some(key) = state.socket_to_session.get(&socket.id.to_string()) {
+        keys.push(session_key(key));
+    }
+    let now = now_millis();
+    let limiter = &mut state.rate_limiter;
+    let result = limiter
+        .take(Limit::Events, &keys, 1, now)
+        .and_then(|_| match bytes {
+            0 => Ok(()),
+            bytes => limiter.take(Limit::MessageBytes, &keys, bytes, now),
+        });
+    match result {
+        Ok(()) => true,
+        Err(wait) => {
+            let _ = socket.emit(
+                "rate-limited",
+                serde_json::json!({ "event": event, "retryAfter": wait, "message": RATE_LIMITED }),
+            );
+            false
+        }
+    }
+}
+
+// Records an audit event by the socket's user, or by its address alone
+fn audit(state: &mut AppState, socket: &SocketRef, event: &str, details: Value) {
+    let user = state.user_for_socket(&socket.id.to_string()).cloned();
+    state
+        .audit
+        .record(event, socket_ip(socket), user.as_ref(), details);
+}
+
+// Client address of the handshake, already resolved through trusted proxies
+fn socket_ip(socket: &SocketRef) -> Option<IpAddr> {
+    socket
+        .req_parts()
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip().to_canonical())
+}
+
+fn socket_device(socket: &SocketRef) -> String {
+    let ua = socket
+        .req_parts()
+        .headers
+        .get("user-agent")
+        .and_then(|h| h.to_str().ok());
+    get_device_type(ua.unwrap_or(""))
+}
+
+/// Attaches `socket` to its session, creating the session if needed, and
+/// sends `welcome`. Runs on connect, or once an admin approves the device.
+fn admit(
+    socket: &SocketRef,
+    state_write: &mut AppState,
+    session_key: &str,
+    admin: bool,
+    role: Role,
+) {
+    let server_url = state_write.server_url.clone();
+
+    // Check if session exists for this SessionID
+    let session_exists = if let Some(session) = state_write.sessions.get_mut(session_key) {
+        // Check if session expired (disconnected > 10 mins ago)
+        if let Some(disconnect_time) = session.disconnect_time {
+            if SystemTime::now()
+                .duration_since(disconnect_time)
+                .unwrap_or(Duration::ZERO)
+                > Duration::from_secs(600)
+            {
+                // Expired
+                false
+            } else {
+                // Valid, reactivate
+                session.disconnect_time = None;
+                session.active_sockets.insert(socket.id.to_string());
+                true
+            }
+        } else {
+            // Already active (another tab/device with same IP)
+            session.active_sockets.insert(socket.id.to_string());
+            true
+        }
+    } else {
+        false
+    };
+
+    let user_profile = if session_exists {
+        // Reuse existing profile and role, unless admin rights came or went
+        let user = &mut state_write.sessions.get_mut(session_key).unwrap().user;
+        if admin {
+            user.role = Role::Admin;
+        } else if user.role == Role::Admin {
+            user.role = role;
+        }
+        user.clone()
+    } else {
+        // Create new session/user
+        let name = format!(
+            "{}",
+            uuid::Uuid::new_v4().simple().to_string()[..6].to_string()
+        );
+        let color = get_random_color();
+        let device = socket_device(socket);
+
+        let user_profile = User {
+            id: uuid::Uuid::new_v4().to_string(), // Stable ID
+            name,
+            color,
+            device,
+            role: if admin { Role::Admin } else { role },
+        };
+
+        let mut active_sockets = HashSet::new();
+        active_sockets.insert(socket.id.to_string());
+
+        let session = Session {
+            user: user_profile.clone(),
+            disconnect_time: None,
+            active_sockets,
+        };
+
+        state_write
+            .sessions
+            .insert(session_key.to_string(), session);
+        user_profile
+    };
+
+    // Update socket_to_session map
+    state_write
+        .socket_to_session
+        .insert(socket.id.to_string(), session_key.to_string());
+    let _ = socket.join(MEMBERS_ROOM);
+
+    // Store the room code used for this socket connection. Sockets that
+    // got in by cookie or as admin are on the current code too.
+    let joined_with = if state_write.room_code_enabled {
+        state_write.room_code.clone()
+    } else {
+        None
+    };
+    state_write
+        .socket_room_codes
+        .insert(socket.id.to_string(), joined_with);
+
+    // Collect all users to send welcome
+    let all_users: Vec<User> = state_write
+        .sessions
+        .values()
+        .filter(|s| s.disconnect_time.is_none())
+        .map(|s| s.user.clone())
+        .collect();
+
+    #[derive(Serialize)]
+    struct WelcomeData {
+        user: User,
+        #[serde(rename = "allUsers")]
+        all_users: Vec<User>,
+        #[serde(rename = "serverUrl")]
+        server_url: String,
+        pinned: Vec<Value>,
+        admin: bool,
+        #[serde(rename = "sessionToken")]
+        session_token: String,
+        e2e: Option<E2eInfo>,
+        // What this user may do, so the page can hide the rest
+        permissions: Vec<Permission>,
+        // Username the device logged in with, when login is on
+        account: Option<String>,
+    }
+
+    let _ = socket.emit(
+        "welcome",
+        WelcomeData {
+            user: user_profile.clone(),
+            all_users,
+            server_url,
+            pinned: state_write.pinned.clone(),
+            admin,
+            session_token: state_write.signer.issue(session_key, now_millis()),
+            e2e: e2e::info(state_write),
+            permissions: state_write.config.permissions.granted(user_profile.role),
+            account: logged_in_user(state_write, &socket.req_parts().headers).map(str::to_string),
+        },
+    );
+    if admin {
+        let _ = socket.emit("device-list", device_list(state_write));
+        let _ = socket.emit("ip-bans", state_write.ip_filter.bans(now_millis()));
+    }
+
+    if state_write
+        .sessions
+        .get(session_key)
+        .unwrap()
+        .active_sockets
+        .len()
+        == 1
+    {
+        let _ = socket
+            .broadcast()
+            .to(MEMBERS_ROOM)
+            .emit("user-joined", user_profile);
+    }
+}
+
+fn device_list(state: &AppState) -> Value {
+    let mut pending: Vec<&JoinRequest> = state.pending_joins.values().collect();
+    pending.sort_by_key(|r| r.requested_at);
+    serde_json::json!({
+        "devices": state.devices.list(),
+        "pending": pending
+    })
+}
+
+fn announce_deletion(socket: &SocketRef, message_id: u64) {
+    let _ = socket
+        .broadcast()
+        .to(MEMBERS_ROOM)
+        .emit("message-deleted", message_id);
+    let _ = socket.emit("message-deleted", message_id);
+}
+
+pub struct PostedMessage {
+    pub message: Value,
+    // Sockets of mentioned users and the notification they receive
+    pub mentioned: Option<(Vec<String>, Value)>,
+}
+
+/// Builds a text message from `sender`, records it in history and registers
+/// its self-destruct timer. Shared by live and scheduled messages.
+pub fn post_text_message(
+    state: &mut AppState,
+    sender: &User,
+    options: TextMessageOptions,
+) -> PostedMessage {
+    let TextMessageOptions {
+        text,
+        ttl,
+        view_once,
+        encrypted,
+    } = options;
+    let ttl = ttl.filter(|&t| t > 0).map(|t| t.min(MAX_MESSAGE_TTL));
+
+    #[derive(Serialize)]
+    struct Message {
+        id: u128,
+        #[serde(rename = "senderId")]
+        sender_id: String,
+        #[serde(rename = "senderName")]
+        sender_name: String,
+        #[serde(rename = "senderColor")]
+        sender_color: String,
+        #[serde(rename = "senderDevice")]
+        sender_device: String,
+        #[serde(rename = "type")]
+        msg_type: String,
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+        #[serde(rename = "expiresAt", skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(rename = "viewOnce", skip_serializing_if = "std::ops::Not::not")]
+        view_once: bool,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        mentions: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        encrypted: Option<Envelope>,
+    }
+
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    let mentions = find_mentions(
+        &text,
+        state
+            .sessions
+            .values()
+            .filter(|s| s.disconnect_time.is_none())
+            .map(|s| &s.user),
+    );
+
+    let msg = Message {
+        id,
+        sender_id: sender.id.clone(),
+        sender_name: sender.name.clone(),
+        sender_color: sender.color.clone(),
+        sender_device: sender.device.clone(),
+        msg_type: "text".to_string(),
+        text,
+        ttl,
+        expires_at: ttl.map(|t| expiry_time(id as u64, t)),
+        view_once,
+        mentions,
+        encrypted,
+    };
+
+    let message = serde_json::to_value(&msg).unwrap_or_default();
+    state.record_message(message.clone());
+
+    if ttl.is_some() || view_once {
+        state.ephemeral.insert(
+            id as u64,
+            Ephemeral {
+                sender_id: sender.id.clone(),
+                file_id: None,
+                view_once,
+            },
+        );
+    }
+    if let Some(expires_at) = msg.expires_at {
+        state.scheduler.expire_at(id as u64, expires_at);
+    }
+
+    #[derive(Serialize)]
+    struct MentionedData<'a> {
+        #[serde(rename = "messageId")]
+        message_id: u64,
+        #[serde(rename = "senderId")]
+        sender_id: &'a str,
+        #[serde(rename = "senderName")]
+        sender_name: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<&'a str>,
+    }
+
+    // Notify the mentioned users on every tab they have open
+    let mentioned_sockets: Vec<String> = state
+        .sessions
+        .values()
+        .filter(|s| s.user.id != sender.id && msg.mentions.contains(&s.user.id))
+        .flat_map(|s| s.active_sockets.iter().cloned())
+        .collect();
+    let mentioned = (!mentioned_sockets.is_empty()).then(|| {
+        let data = MentionedData {
+            message_id: id as u64,
+            sender_id: &sender.id,
+            sender_name: &sender.name,
+            text: (!msg.view_once).then_some(msg.text.as_str()),
+        };
+        (
+            mentioned_sockets,
+            serde_json::to_value(data).unwrap_or_default(),
+        )
+    });
+
+    PostedMessage { message, mentioned }
+}
+
+/// Completes a file announcement from `sender` whose file lives on
+/// `owner_socket`, records it and registers the file for download.
+pub fn post_file_meta(
+    state: &mut AppState,
+    sender: &User,
+    owner_socket: &str,
+    mut meta: Value,
+) -> Value {
+    if let Some(obj) = meta.as_object_mut() {
+        let file_id = obj
+            .get("fileId")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        // The message keeps the name as sent, downloads and storage use this
+        let file_name = obj
+            .get("fileName")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let file_name = filename::sanitize(file_name);
+
+        let file_size = obj.get("fileSize").and_then(|v| v.as_u64()).unwrap_or(0);
+
+        obj.insert("fileId".to_string(), Value::String(file_id.clone()));
+
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        obj.insert(
+            "id".to_string(),
+            Value::Number(serde_json::Number::from(id as u64)),
+        );
+        obj.insert("senderId".to_string(), Value::String(sender.id.clone()));
+        obj.insert("senderName".to_string(), Value::String(sender.name.clone()));
+        obj.insert(
+            "senderColor".to_string(),
+            Value::String(sender.color.clone()),
+        );
+        obj.insert(
+            "senderDevice".to_string(),
+            Value::String(sender.device.clone()),
+        );
+        obj.insert("type".to_string(), Value::String("file-meta".to_string()));
+
+        let ttl = obj
+            .get("ttl")
+            .and_then(|v| v.as_u64())
+            .filter(|&t| t > 0)
+            .map(|t| t.min(MAX_MESSAGE_TTL));
+        let view_once = obj
+            .get("viewOnce")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if let Some(ttl) = ttl {
+            let expires_at = expiry_time(id as u64, ttl);
+            obj.insert("ttl".to_string(), Value::Number(ttl.into()));
+            obj.insert(
+                "expiresAt".to_string(),
+                Value::Number(serde_json::Number::from(expires_at)),
+            );
+            state.scheduler.expire_at(id as u64, expires_at);
+        }
+        if ttl.is_some() || view_once {
+            state.ephemeral.insert(
+                id as u64,
+                Ephemeral {
+                    sender_id: sender.id.clone(),
+                    file_id: Some(file_id.clone()),
+                    view_once,
+                },
+            );
+        }
+
+        state
+            .file_owners
+            .insert(file_id, (owner_socket.to_string(), file_name, file_size));
+    }
+
+    state.record_message(meta.clone());
+    meta
 }
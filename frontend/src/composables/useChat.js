@@ -62,12 +62,19 @@ export function useChat() {
         saveChatHistory();
     };
 
+    const removeMessage = (id) => {
+        allMessages.value = allMessages.value.filter(m => m.id !== id);
+        messages.value = messages.value.filter(m => m.id !== id);
+        saveChatHistory();
+    };
+
     return {
         messages,
         allMessages,
         saveChatHistory,
         loadChatHistory,
         loadMoreMessages,
-        addMessage
+        addMessage,
+        removeMessage
     };
 }
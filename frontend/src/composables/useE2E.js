@@ -0,0 +1,75 @@
+import { ref, computed } from 'vue';
+import { storageService } from '../services/storageService';
+import { CHUNK_SIZE, deriveKey, encryptedSize, isSupported, newNonce, open, seal } from '../utils/e2e';
+
+export function useE2E() {
+    // Key derivation parameters from the server, null when E2E mode is off
+    const e2eInfo = ref(null);
+    const enabled = computed(() => !!e2eInfo.value);
+
+    const getKey = async () => {
+        if (!isSupported()) {
+            throw new Error('端到端加密需要通过 HTTPS 访问');
+        }
+        let code = storageService.getItem('zher_room_code');
+        if (!code) {
+            code = (window.prompt('请输入房间码以解密消息') || '').trim();
+            if (!code) throw new Error('缺少房间码，无法加密');
+            storageService.setItem('zher_room_code', code);
+        }
+        return deriveKey(code, e2eInfo.value);
+    };
+
+    const sealText = async (text, senderId) => {
+        const key = await getKey();
+        return { text: '', encrypted: await seal(key, text, senderId) };
+    };
+
+    // File announcement without the clear name; `sealed` stays with the sender
+    const sealFileMeta = async (fileId, file, senderId) => {
+        const key = await getKey();
+        const sealed = { fileId, nonce: newNonce(), chunkSize: CHUNK_SIZE, fileType: file.type };
+        const encrypted = await seal(key, {
+            fileName: file.name,
+            fileType: file.type,
+            fileSize: file.size,
+            nonce: sealed.nonce
+        }, senderId);
+        return {
+            meta: { fileId, fileSize: encryptedSize(file.size), chunkSize: CHUNK_SIZE, encrypted },
+            sealed
+        };
+    };
+
+    // Decrypts an incoming message; anything else passes through
+    const openMessage = async (msg) => {
+        if (!msg.encrypted) return msg;
+        const { encrypted, ...rest } = msg;
+        try {
+            const key = await getKey();
+            const value = await open(key, encrypted, msg.senderId);
+            if (msg.type === 'file-meta') {
+                return {
+                    ...rest,
+                    fileName: value.fileName,
+                    fileType: value.fileType,
+                    fileSize: value.fileSize,
+                    sealed: { fileId: msg.fileId, nonce: value.nonce, chunkSize: msg.chunkSize, fileType: value.fileType }
+                };
+            }
+            return { ...rest, text: value };
+        } catch (err) {
+            console.error('Decrypt failed:', err);
+            return { ...rest, type: 'text', text: '[无法解密的消息]' };
+        }
+    };
+
+    return {
+        e2eInfo,
+        enabled,
+        getKey,
+        sealText,
+        sealFileMeta,
+        openMessage
+    };
+}
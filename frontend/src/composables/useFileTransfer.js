@@ -1,8 +1,9 @@
 import { ref } from 'vue';
 import JSZip from 'jszip';
 import { getZipName, traverseFileTree } from '../utils/fileUtils';
+import { decryptResponse, encryptRange, encryptedSize } from '../utils/e2e';
 
-export function useFileTransfer(onFileReady) {
+export function useFileTransfer(onFileReady, getKey) {
     const selectedFile = ref(null);
     const isZipping = ref(false);
     const zipProgress = ref(0);
@@ -10,16 +11,22 @@ export function useFileTransfer(onFileReady) {
     const currentZipFile = ref('');
     const sharedFiles = new Map();
 
-    const addSharedFile = (fileId, file) => {
-        sharedFiles.set(fileId, file);
+    // `sealed` holds the E2E chunk parameters for encrypted files
+    const addSharedFile = (fileId, file, sealed = null) => {
+        sharedFiles.set(fileId, { file, sealed });
     };
 
     const handleStartUpload = async ({ fileId, transferId, offset = 0, end }) => {
-        const file = sharedFiles.get(fileId);
+        const { file, sealed } = sharedFiles.get(fileId) || {};
         if (file) {
             try {
                 let body = file;
-                if (offset > 0 || (typeof end === 'number' && end < file.size - 1)) {
+                if (sealed) {
+                    // Offsets refer to the ciphertext
+                    const last = encryptedSize(file.size, sealed.chunkSize) - 1;
+                    const key = await getKey();
+                    body = await encryptRange(key, file, sealed, offset, typeof end === 'number' ? end : last);
+                } else if (offset > 0 || (typeof end === 'number' && end < file.size - 1)) {
                     const sliceEnd = (typeof end === 'number') ? end + 1 : file.size;
                     body = file.slice(offset, sliceEnd);
                 }
@@ -129,13 +136,27 @@ export function useFileTransfer(onFileReady) {
         }
     };
 
-    const downloadFile = (fileId, fileName) => {
+    const downloadFile = async (fileId, fileName, sealed) => {
         const link = document.createElement('a');
         link.href = `/api/download/${fileId}`;
+        if (sealed) {
+            // Encrypted files are decrypted here before they are saved
+            try {
+                const response = await fetch(link.href);
+                if (!response.ok) throw new Error(`HTTP ${response.status}`);
+                const blob = await decryptResponse(await getKey(), response, sealed);
+                link.href = URL.createObjectURL(blob);
+            } catch (err) {
+                console.error("Decrypt download failed", err);
+                alert('文件解密失败，文件可能不完整');
+                return;
+            }
+        }
         link.download = fileName;
         document.body.appendChild(link);
         link.click();
         document.body.removeChild(link);
+        if (sealed) setTimeout(() => URL.revokeObjectURL(link.href), 60000);
     };
 
     return {
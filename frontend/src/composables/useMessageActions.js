@@ -1,8 +1,19 @@
-export function useMessageActions(emit, addMessage, addSharedFile, currentUser, scrollToBottom, chatContainer) {
-    const sendTextMessage = (inputText, resetInput) => {
+import { sha256Hex } from '../utils/fileUtils';
+
+export function useMessageActions(emit, addMessage, addSharedFile, currentUser, scrollToBottom, chatContainer, e2e) {
+    const sendTextMessage = async (inputText, resetInput) => {
         if (inputText.value.trim() && currentUser.value) {
             const text = inputText.value;
-            emit('text-message', text);
+            if (e2e && e2e.enabled.value) {
+                try {
+                    emit('text-message', await e2e.sealText(text, currentUser.value.id));
+                } catch (err) {
+                    alert(err.message);
+                    return;
+                }
+            } else {
+                emit('text-message', text);
+            }
 
             addMessage({
                 id: Date.now(),
@@ -18,11 +29,26 @@ export function useMessageActions(emit, addMessage, addSharedFile, currentUser,
         }
     };
 
-    const sendFileMessage = (selectedFile, resetInput) => {
+    // Text only; files are sent as soon as they are picked
+    const scheduleTextMessage = async (inputText, delay, resetInput) => {
+        if (!inputText.value.trim() || !currentUser.value) return;
+        let text = inputText.value;
+        if (e2e && e2e.enabled.value) {
+            try {
+                text = await e2e.sealText(text, currentUser.value.id);
+            } catch (err) {
+                alert(err.message);
+                return;
+            }
+        }
+        emit('schedule-message', { delay, text });
+        resetInput();
+    };
+
+    const sendFileMessage = async (selectedFile, resetInput) => {
         if (selectedFile.value && currentUser.value) {
             const file = selectedFile.value;
             const fileId = Math.random().toString(36).substring(2, 11);
-            addSharedFile(fileId, file);
 
             const fileMetaData = {
                 fileId,
@@ -30,8 +56,25 @@ export function useMessageActions(emit, addMessage, addSharedFile, currentUser,
                 fileSize: file.size,
                 fileType: file.type
             };
-            
-            emit('file-meta', fileMetaData);
+
+            if (e2e && e2e.enabled.value) {
+                try {
+                    const { meta, sealed } = await e2e.sealFileMeta(fileId, file, currentUser.value.id);
+                    addSharedFile(fileId, file, sealed);
+                    emit('file-meta', meta);
+                    // Downloads of our own file come back encrypted too
+                    fileMetaData.sealed = sealed;
+                } catch (err) {
+                    alert(err.message);
+                    return;
+                }
+            } else {
+                // Lets the server check relayed copies of the file
+                const sha256 = await sha256Hex(file);
+                if (sha256) fileMetaData.sha256 = sha256;
+                addSharedFile(fileId, file);
+                emit('file-meta', fileMetaData);
+            }
             
             addMessage({
                 id: Date.now(),
@@ -68,6 +111,7 @@ export function useMessageActions(emit, addMessage, addSharedFile, currentUser,
         sendMessage,
         sendTextMessage,
         sendFileMessage,
+        scheduleTextMessage,
         handleNewMessage
     };
 }
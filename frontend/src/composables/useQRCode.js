@@ -12,7 +12,7 @@ export function useQRCode(serverUrl, roomCode, roomCodeEnabled) {
                 let url = serverUrl.value;
                 if (roomCodeEnabled && roomCodeEnabled.value && roomCode && roomCode.value) {
                     const separator = url.includes('?') ? '&' : '?';
-                    url = `${url}${separator}code=${roomCode.value}`;
+                    url = `${url}${separator}code=${encodeURIComponent(roomCode.value)}`;
                 }
                 displayUrl.value = url
                 qrCodeUrl.value = await QRCode.toDataURL(url, {
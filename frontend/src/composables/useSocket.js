@@ -1,31 +1,47 @@
 import { ref } from 'vue';
 import { io } from 'socket.io-client';
 import { storageService } from '../services/storageService';
+import { copyText } from '../utils/textUtils';
 
 export function useSocket() {
     const socket = ref(null);
     const users = ref([]);
     const currentUser = ref({});
     const serverUrl = ref('');
+    const isAdmin = ref(false);
+    // Active room code lockouts, only sent to admins
+    const lockouts = ref([]);
+    // Banned addresses, only sent to admins
+    const bannedIps = ref([]);
+    // Verification phrase while this device waits for approval
+    const pendingPhrase = ref('');
+    // Pairing state, only sent to admins
+    const joinRequests = ref([]);
+    const approvedDevices = ref([]);
     const isEditingName = ref(false);
+    // What this user's role allows, from welcome and role-changed
+    const permissions = ref([]);
+    // fileId -> 'match' or 'mismatch', from the relay's hash checks
+    const fileIntegrity = ref({});
+    // fileId -> malware scan verdict of files kept on the server
+    const fileScans = ref({});
+    // Username this device logged in with, empty when login is off
+    const account = ref('');
+    // This user's messages waiting to be sent, soonest first
+    const scheduled = ref([]);
 
-    const getSessionId = () => {
-        let id = storageService.getItem('zher_uid');
-        if (!id) {
-            id = Math.random().toString(36).substring(2) + Date.now().toString(36);
-            storageService.setItem('zher_uid', id);
+    const connect = ({ onMessage, onMessageDeleted, onMessagePinned, onMessageUnpinned, onScheduledSent, onWelcome, onStartUpload, onE2eChanged }) => {
+        const auth = {};
+
+        // Signed by the server in welcome; resumes the previous session
+        const sessionToken = storageService.getItem('zher_session_token');
+        if (sessionToken) {
+            auth.sessionToken = sessionToken;
         }
-        return id;
-    };
 
-    const connect = ({ onMessage, onWelcome, onStartUpload, roomCode }) => {
-        const auth = {
-            sessionId: getSessionId()
-        };
-        
-        // Add room code if provided
-        if (roomCode) {
-            auth.roomCode = roomCode;
+        const adminToken = storageService.getItem('zher_admin_token');
+        if (adminToken) {
+            auth.adminToken = adminToken;
         }
         
         socket.value = io({
@@ -37,6 +53,18 @@ export function useSocket() {
             currentUser.value = data.user;
             users.value = data.allUsers;
             if (data.serverUrl) serverUrl.value = data.serverUrl;
+            isAdmin.value = !!data.admin;
+            pendingPhrase.value = '';
+            permissions.value = data.permissions || [];
+            account.value = data.account || '';
+            if (data.sessionToken) {
+                storageService.setItem('zher_session_token', data.sessionToken);
+                socket.value.auth.sessionToken = data.sessionToken;
+                // Lets plain links such as downloads carry this user's role
+                const secure = window.location.protocol === 'https:' ? '; Secure' : '';
+                document.cookie = `zher_session=${data.sessionToken}; Path=/; SameSite=Strict${secure}`;
+            }
+            socket.value.emit('list-scheduled');
             if (onWelcome) onWelcome(data);
         });
 
@@ -67,10 +95,176 @@ export function useSocket() {
             if (onMessage) onMessage(msg);
         });
 
+        socket.value.on('message-deleted', (id) => {
+            if (onMessageDeleted) onMessageDeleted(id);
+        });
+
+        socket.value.on('message-pinned', (msg) => {
+            if (onMessagePinned) onMessagePinned(msg);
+        });
+
+        socket.value.on('message-unpinned', (id) => {
+            if (onMessageUnpinned) onMessageUnpinned(id);
+        });
+
+        socket.value.on('scheduled-list', (items) => {
+            scheduled.value = items || [];
+        });
+
+        socket.value.on('message-scheduled', (item) => {
+            scheduled.value = [...scheduled.value, item].sort((a, b) => a.sendAt - b.sendAt);
+        });
+
+        socket.value.on('scheduled-cancelled', (id) => {
+            scheduled.value = scheduled.value.filter(item => item.id !== id);
+        });
+
+        socket.value.on('scheduled-sent', ({ id, message }) => {
+            scheduled.value = scheduled.value.filter(item => item.id !== id);
+            if (onScheduledSent) onScheduledSent(message);
+        });
+
+        socket.value.on('schedule-fail', (msg) => {
+            alert(msg);
+        });
+
+        socket.value.on('pin-fail', (msg) => {
+            alert(msg);
+        });
+
+        socket.value.on('unpin-fail', (msg) => {
+            alert(msg);
+        });
+
+        socket.value.on('mentioned', (data) => {
+            if ('Notification' in window && Notification.permission === 'granted') {
+                new Notification(data.senderName, { body: data.text || '' });
+            }
+        });
+
         socket.value.on('start-upload', (data) => {
             if (onStartUpload) onStartUpload(data);
         });
 
+        socket.value.on('e2e-changed', (info) => {
+            if (onE2eChanged) onE2eChanged(info);
+        });
+
+        socket.value.on('message-fail', (msg) => {
+            alert(msg);
+        });
+
+        const enterNewRoomCode = async (data) => {
+            const code = window.prompt('房间码已更改，请输入新的房间码');
+            if (!code) return;
+            // Also the key material in E2E mode
+            storageService.setItem('zher_room_code', code);
+            // Refresh the access cookie used by page loads and downloads
+            await fetch('/api/access', {
+                method: 'POST',
+                body: new URLSearchParams({ code })
+            }).catch(() => {});
+            if (data.disconnect) {
+                // The session resumes on reconnect, admitted by the new cookie
+                socket.value.connect();
+            } else {
+                emit('reauth', code);
+            }
+        };
+
+        socket.value.on('reauth-required', enterNewRoomCode);
+
+        socket.value.on('reauth-fail', (msg) => {
+            alert(msg);
+            enterNewRoomCode({ disconnect: false });
+        });
+
+        socket.value.on('join-pending', (data) => {
+            pendingPhrase.value = data.phrase;
+        });
+
+        socket.value.on('join-denied', () => {
+            pendingPhrase.value = '';
+            alert('管理员拒绝了加入请求');
+        });
+
+        socket.value.on('device-list', (data) => {
+            joinRequests.value = data.pending || [];
+            approvedDevices.value = data.devices || [];
+        });
+
+        socket.value.on('ip-bans', (bans) => {
+            bannedIps.value = bans || [];
+        });
+
+        socket.value.on('ban-fail', (msg) => {
+            alert(msg);
+        });
+
+        socket.value.on('link-created', ({ url }) => {
+            copyText(url, () => {
+                alert('下载链接已复制');
+            }, () => {
+                window.prompt('下载链接', url);
+            });
+        });
+
+        socket.value.on('links-revoked', ({ count }) => {
+            alert(`已撤销 ${count} 个下载链接`);
+        });
+
+        // One alert per burst, a flood of rejected events would stack them
+        let rateLimitedUntil = 0;
+        socket.value.on('rate-limited', ({ message, retryAfter }) => {
+            if (Date.now() < rateLimitedUntil) return;
+            rateLimitedUntil = Date.now() + Math.max(retryAfter, 3000);
+            alert(message);
+        });
+
+        socket.value.on('transfer-integrity', ({ fileId, status }) => {
+            fileIntegrity.value = { ...fileIntegrity.value, [fileId]: status };
+            if (status === 'mismatch') {
+                alert('文件传输校验失败，内容与发送者的文件不一致');
+            } else if (status === 'incomplete') {
+                alert('文件传输中断，收到的内容不完整');
+            }
+        });
+
+        socket.value.on('file-scanned', ({ fileId, scan }) => {
+            fileScans.value = { ...fileScans.value, [fileId]: scan };
+        });
+
+        // Only the sender and admins get this
+        socket.value.on('file-infected', ({ fileName, threat }) => {
+            alert(`文件 ${fileName || ''} 未通过病毒扫描（${threat}），已禁止下载`);
+        });
+
+        socket.value.on('limit-exceeded', ({ message }) => {
+            alert(message);
+        });
+
+        socket.value.on('link-fail', (msg) => {
+            alert(msg);
+        });
+
+        socket.value.on('banned', (data) => {
+            const until = data && data.until ? `，至 ${new Date(data.until).toLocaleString()}` : '';
+            alert(`你已被管理员封禁${until}`);
+        });
+
+        socket.value.on('role-changed', (data) => {
+            permissions.value = data.permissions || [];
+            currentUser.value.role = data.role;
+        });
+
+        socket.value.on('kicked', () => {
+            alert('你已被管理员移出房间');
+        });
+
+        socket.value.on('lockout', (lockout) => {
+            lockouts.value = [...lockouts.value.filter(l => l.until > Date.now()), lockout];
+        });
+
         socket.value.on('name-change-success', (newName) => {
             currentUser.value.name = newName;
             isEditingName.value = false;
@@ -112,6 +306,17 @@ export function useSocket() {
         users,
         currentUser,
         serverUrl,
+        isAdmin,
+        lockouts,
+        bannedIps,
+        pendingPhrase,
+        joinRequests,
+        approvedDevices,
+        permissions,
+        fileIntegrity,
+        fileScans,
+        account,
+        scheduled,
         isEditingName,
         connect,
         disconnect,
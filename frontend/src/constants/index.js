@@ -36,7 +36,7 @@ export const SOCKET_EVENTS = {
 };
 
 export const STORAGE_KEYS = {
-    USER_ID: 'zher_uid',
+    SESSION_TOKEN: 'zher_session_token',
     CHAT_HISTORY: 'zher_chat_history',
     DARK_MODE: 'zher_dark_mode'
 };
@@ -0,0 +1,138 @@
+// End-to-end encryption for E2E mode. The key is derived from the room code,
+// so the server only ever relays ciphertext. The file framing must match
+// backend/src/e2e.rs.
+
+// Plaintext bytes per sealed file chunk
+export const CHUNK_SIZE = 64 * 1024;
+const TAG_SIZE = 16;
+const NONCE_SIZE = 8;
+
+const encoder = new TextEncoder();
+const decoder = new TextDecoder();
+
+// WebCrypto only exists on HTTPS pages and localhost
+export const isSupported = () => !!(window.crypto && window.crypto.subtle);
+
+const toBase64 = (bytes) => {
+    let binary = '';
+    for (let i = 0; i < bytes.length; i++) binary += String.fromCharCode(bytes[i]);
+    return btoa(binary);
+};
+
+const fromBase64 = (text) => Uint8Array.from(atob(text), c => c.charCodeAt(0));
+
+const fromHex = (hex) => new Uint8Array(hex.match(/../g).map(h => parseInt(h, 16)));
+
+const keys = new Map();
+
+export const deriveKey = (code, { salt, iterations }) => {
+    const id = `${salt}:${iterations}:${code}`;
+    if (!keys.has(id)) {
+        keys.set(id, (async () => {
+            const material = await crypto.subtle.importKey('raw', encoder.encode(code), 'PBKDF2', false, ['deriveKey']);
+            return crypto.subtle.deriveKey(
+                { name: 'PBKDF2', salt: fromHex(salt), iterations, hash: 'SHA-256' },
+                material,
+                { name: 'AES-GCM', length: 256 },
+                false,
+                ['encrypt', 'decrypt']
+            );
+        })());
+    }
+    return keys.get(id);
+};
+
+// Seals a JSON value. `context` (the sender id) is authenticated too, so the
+// server cannot pass one user's message off as another's.
+export const seal = async (key, value, context) => {
+    const iv = crypto.getRandomValues(new Uint8Array(12));
+    const data = await crypto.subtle.encrypt(
+        { name: 'AES-GCM', iv, additionalData: encoder.encode(context) },
+        key,
+        encoder.encode(JSON.stringify(value))
+    );
+    return { iv: toBase64(iv), data: toBase64(new Uint8Array(data)) };
+};
+
+export const open = async (key, envelope, context) => {
+    const plain = await crypto.subtle.decrypt(
+        { name: 'AES-GCM', iv: fromBase64(envelope.iv), additionalData: encoder.encode(context) },
+        key,
+        fromBase64(envelope.data)
+    );
+    return JSON.parse(decoder.decode(plain));
+};
+
+export const newNonce = () => toBase64(crypto.getRandomValues(new Uint8Array(NONCE_SIZE)));
+
+// Even an empty file is one sealed chunk
+export const encryptedSize = (size, chunkSize = CHUNK_SIZE) =>
+    size + Math.max(1, Math.ceil(size / chunkSize)) * TAG_SIZE;
+
+// Index of the last sealed chunk of a `size` byte file
+const lastChunk = (size, chunkSize) => Math.max(1, Math.ceil(size / chunkSize)) - 1;
+
+// The IV is the file nonce followed by the chunk index, so chunks cannot be
+// reordered. The additional data is the file id, the index and a flag for the
+// last chunk, so a download cut off between chunks fails to decrypt.
+const chunkParams = ({ fileId, nonce }, index, final) => {
+    const iv = new Uint8Array(12);
+    iv.set(fromBase64(nonce));
+    new DataView(iv.buffer).setUint32(NONCE_SIZE, index);
+    const id = encoder.encode(fileId);
+    const additionalData = new Uint8Array(id.length + 5);
+    additionalData.set(id);
+    new DataView(additionalData.buffer).setUint32(id.length, index);
+    additionalData[id.length + 4] = final ? 1 : 0;
+    return { name: 'AES-GCM', iv, additionalData };
+};
+
+// Ciphertext bytes offset..=end of `file`, sealing only the chunks they
+// touch. Chunks come out the same every time, so ranges can be resumed.
+export const encryptRange = async (key, file, sealed, offset, end) => {
+    const sealedSize = sealed.chunkSize + TAG_SIZE;
+    const first = Math.floor(offset / sealedSize);
+    const last = Math.floor(end / sealedSize);
+    const final = lastChunk(file.size, sealed.chunkSize);
+    const parts = [];
+    for (let i = first; i <= last; i++) {
+        const plain = await file.slice(i * sealed.chunkSize, (i + 1) * sealed.chunkSize).arrayBuffer();
+        const chunk = new Uint8Array(await crypto.subtle.encrypt(chunkParams(sealed, i, i === final), key, plain));
+        const from = i === first ? offset - i * sealedSize : 0;
+        const to = i === last ? end - i * sealedSize + 1 : chunk.length;
+        parts.push(chunk.subarray(from, to));
+    }
+    return new Blob(parts);
+};
+
+// Decrypts a whole-file download chunk by chunk as it arrives. A chunk is
+// only opened once more data follows it, the one left at the end must be the
+// sealed last chunk.
+export const decryptResponse = async (key, response, sealed) => {
+    const sealedSize = sealed.chunkSize + TAG_SIZE;
+    const reader = response.body.getReader();
+    const parts = [];
+    let buffer = new Uint8Array(0);
+    let index = 0;
+
+    const openChunk = async (chunk, final) => {
+        parts.push(new Uint8Array(await crypto.subtle.decrypt(chunkParams(sealed, index++, final), key, chunk)));
+    };
+
+    for (;;) {
+        const { done, value } = await reader.read();
+        if (done) break;
+        const joined = new Uint8Array(buffer.length + value.length);
+        joined.set(buffer);
+        joined.set(value, buffer.length);
+        buffer = joined;
+        while (buffer.length > sealedSize) {
+            await openChunk(buffer.subarray(0, sealedSize), false);
+            buffer = buffer.slice(sealedSize);
+        }
+    }
+    // Even an empty file is one sealed chunk
+    if (buffer.length === 0) throw new Error('Encrypted download is empty');
+    await openChunk(buffer, true);
+    return new Blob(parts, { type: sealed.fileType || '' });
+};
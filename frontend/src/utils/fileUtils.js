@@ -6,6 +6,21 @@ export const formatFileSize = (bytes) => {
     return parseFloat((bytes / Math.pow(k, i)).toFixed(2)) + ' ' + sizes[i];
 };
 
+// Files above this are not hashed, the whole file has to be read into memory
+const MAX_HASHED_SIZE = 512 * 1024 * 1024;
+
+// Hex SHA-256 of a file, or null where it can't be computed
+export const sha256Hex = async (file) => {
+    if (!window.crypto || !window.crypto.subtle || file.size > MAX_HASHED_SIZE) return null;
+    try {
+        const digest = await window.crypto.subtle.digest('SHA-256', await file.arrayBuffer());
+        return Array.from(new Uint8Array(digest), b => b.toString(16).padStart(2, '0')).join('');
+    } catch (err) {
+        console.error('Hashing failed', err);
+        return null;
+    }
+};
+
 export const getZipName = () => {
     const date = new Date();
     const yyyy = date.getFullYear();